@@ -0,0 +1,119 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use prometheus::{HistogramOpts, HistogramVec, Registry};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+struct RequestStart(Instant);
+
+/// Measures latency of every API request, labelled by route, method and status code.
+pub(crate) struct RequestMetrics {
+    duration: HistogramVec,
+}
+
+impl RequestMetrics {
+    pub(crate) fn new(registry: Arc<Mutex<Registry>>) -> Self {
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "API request latency in seconds",
+            ),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+
+        registry
+            .lock()
+            .unwrap()
+            .register(Box::new(duration.clone()))
+            .unwrap();
+
+        RequestMetrics { duration }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let start = req.local_cache(|| RequestStart(Instant::now()));
+        // Use the route template (not the actual path) to keep label cardinality low
+        let route = match req.route() {
+            Some(x) => x.uri.as_str(),
+            None => "unmatched",
+        };
+
+        self.duration
+            .with_label_values(&[route, req.method().as_str(), &res.status().code.to_string()])
+            .observe(start.0.elapsed().as_secs_f64());
+    }
+}
+
+#[test]
+fn test_request_metrics() {
+    use rocket::local::blocking::Client;
+
+    #[get("/ping")]
+    fn ping() -> &'static str {
+        "pong"
+    }
+
+    let registry = Arc::new(Mutex::new(Registry::new()));
+    let rocket = rocket::build()
+        .mount("/", routes![ping])
+        .attach(RequestMetrics::new(Arc::clone(&registry)));
+    let client = Client::tracked(rocket).unwrap();
+    client.get("/ping").dispatch();
+    client.get("/ping").dispatch();
+    client.get("/missing").dispatch();
+
+    let mfs = registry.lock().unwrap().gather();
+    let count = |route: &str, status: &str| -> u64 {
+        mfs[0]
+            .get_metric()
+            .iter()
+            .find(|x| {
+                let labels: Vec<(&str, &str)> = x
+                    .get_label()
+                    .iter()
+                    .map(|x| (x.get_name(), x.get_value()))
+                    .collect();
+                labels.contains(&("route", route)) && labels.contains(&("status", status))
+            })
+            .map(|x| x.get_histogram().get_sample_count())
+            .unwrap_or(0)
+    };
+    assert_eq!(mfs[0].get_name(), "http_request_duration_seconds");
+    assert_eq!(count("/ping", "200"), 2);
+    assert_eq!(count("unmatched", "404"), 1);
+}
@@ -23,8 +23,11 @@ use prometheus::Registry;
 use rocket::fairing::AdHoc;
 
 use self::common::PrometheusStore;
+use self::metrics::RequestMetrics;
+use crate::config::Config;
 
 pub(crate) mod common;
+mod metrics;
 pub(crate) mod tokenauth;
 mod v1;
 
@@ -32,11 +35,13 @@ pub(crate) struct AuthKeyProvider {
     auth_key: String,
 }
 
-pub(crate) fn stage(key: &str, registry: Arc<Mutex<Registry>>) -> AdHoc {
-    let k = key.to_owned();
+pub(crate) fn stage(cfg: &Config, registry: Arc<Mutex<Registry>>) -> AdHoc {
+    let k = cfg.api.apikey.to_owned();
+    let cfg = cfg.clone();
     AdHoc::on_ignite("API", |rocket| async {
         rocket
-            .attach(v1::stage())
+            .attach(RequestMetrics::new(Arc::clone(&registry)))
+            .attach(v1::stage(Arc::clone(&registry), cfg))
             .manage(AuthKeyProvider { auth_key: k })
             .manage(PrometheusStore { registry })
     })
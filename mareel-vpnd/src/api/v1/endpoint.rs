@@ -0,0 +1,416 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use wgctrl::platform_specific::common::PlatformRoute;
+
+use super::events::{DaemonEvent, EventStore};
+use super::periodic::periodic;
+use super::stats::{self, parse_window};
+use super::types::{IfaceState, InterfaceStore, RouteManagerStore};
+
+const ENDPOINT_RESOLVE_INTERVAL: u64 = 60;
+const FAILOVER_CHECK_INTERVAL: u64 = 10;
+/// Peers with several endpoints move on to the next one after going this long without a handshake
+const DEFAULT_FAILOVER_TIMEOUT: u64 = 180;
+
+/// Splits `host:port` or `[v6]:port`. IPv6 literals need the brackets, as the port could not be
+/// told apart otherwise.
+fn split_endpoint(endpoint: &str) -> Result<(&str, u16), String> {
+    let err = || format!("Invalid endpoint: {}", endpoint);
+    let (host, port) = endpoint.rsplit_once(':').ok_or_else(err)?;
+    let port: u16 = port.parse().map_err(|_| err())?;
+    let host = match host.strip_prefix('[') {
+        Some(x) => x.strip_suffix(']').ok_or_else(err)?,
+        None if host.contains(':') => return Err(err()),
+        None => host,
+    };
+    if host.is_empty() || port == 0 {
+        return Err(err());
+    }
+    Ok((host, port))
+}
+
+#[test]
+fn test_split_endpoint() {
+    assert_eq!(split_endpoint("1.2.3.4:51820"), Ok(("1.2.3.4", 51820)));
+    assert_eq!(split_endpoint("[fd00::1]:51820"), Ok(("fd00::1", 51820)));
+    assert_eq!(
+        split_endpoint("vpn.example.com:51820"),
+        Ok(("vpn.example.com", 51820))
+    );
+    assert!(split_endpoint("fd00::1:51820").is_err());
+    assert!(split_endpoint("[fd00::1:51820").is_err());
+    assert!(split_endpoint("vpn.example.com").is_err());
+    assert!(split_endpoint("vpn.example.com:0").is_err());
+    assert!(split_endpoint(":51820").is_err());
+}
+
+/// Checks the format of `endpoint` without looking it up
+pub(crate) fn validate(endpoint: &str) -> Result<(), String> {
+    split_endpoint(endpoint).map(|_| ())
+}
+
+/// Whether `endpoint` names a host, which has to be looked up again from time to time
+fn is_hostname(endpoint: &str) -> bool {
+    endpoint.parse::<SocketAddr>().is_err()
+}
+
+/// Resolves `endpoint` through the system resolver, blocking meanwhile. `current` is kept while
+/// the host still has it, so that peers do not hop between the addresses of a host.
+pub(crate) fn resolve(endpoint: &str, current: Option<&str>) -> Result<SocketAddr, String> {
+    let (host, port) = split_endpoint(endpoint)?;
+    if let Ok(x) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(x, port));
+    }
+
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    if let Some(x) = current
+        .and_then(|x| x.parse::<SocketAddr>().ok())
+        .filter(|x| addrs.contains(x))
+    {
+        return Ok(x);
+    }
+    addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No address found for {}", host))
+}
+
+#[test]
+fn test_resolve_endpoint() {
+    assert_eq!(
+        resolve("1.2.3.4:51820", None),
+        Ok("1.2.3.4:51820".parse().unwrap())
+    );
+    assert_eq!(
+        resolve("[fd00::1]:51820", None),
+        Ok("[fd00::1]:51820".parse().unwrap())
+    );
+
+    let addr = resolve("localhost:51820", None).unwrap();
+    assert!(addr.ip().is_loopback());
+    assert_eq!(addr.port(), 51820);
+    assert_eq!(
+        resolve("localhost:51820", Some(&addr.to_string())),
+        Ok(addr)
+    );
+}
+
+/// Points the peer at `endpoint`, resolved to `address`, moving its route bypass along. Returns
+/// the previous address.
+fn update_endpoint(
+    iface_state: &mut IfaceState,
+    rms: &RouteManagerStore,
+    pubkey: &str,
+    endpoint: &str,
+    address: SocketAddr,
+) -> Result<Option<String>, String> {
+    let IfaceState {
+        interface,
+        peer_cfgs,
+        ..
+    } = iface_state;
+    let peercfg = match peer_cfgs.get_mut(pubkey) {
+        Some(x) => x,
+        None => return Err("Peer is gone".to_string()),
+    };
+
+    rms.route_manager
+        .lock()
+        .unwrap()
+        .add_route_bypass(&address.ip().to_string())
+        .map_err(|e| e.to_string())?;
+    // Suspended peers pick up the address once they are added back
+    if !peercfg.suspended {
+        interface
+            .set_peer_endpoint(pubkey, &address.to_string())
+            .map_err(|e| e.to_string())?;
+    }
+    peercfg.endpoint = Some(endpoint.to_string());
+    Ok(peercfg.resolved_endpoint.replace(address.to_string()))
+}
+
+/// Addresses of the endpoints in use by any peer
+fn endpoint_ips(iface_store: &InterfaceStore) -> HashSet<IpAddr> {
+    let mut ips = HashSet::new();
+    for x in iface_store.iface_states.iter() {
+        let iface_state = x.value().lock().unwrap();
+        ips.extend(
+            iface_state
+                .peer_cfgs
+                .values()
+                .filter_map(|x| x.resolved_endpoint.as_ref())
+                .filter_map(|x| x.parse::<SocketAddr>().ok())
+                .map(|x| x.ip()),
+        );
+    }
+    ips
+}
+
+/// Removes the bypass of an address no peer uses anymore. Locks every interface, so none may be
+/// held.
+pub(crate) fn release_bypass(
+    rms: &RouteManagerStore,
+    iface_store: &InterfaceStore,
+    address: Option<String>,
+) {
+    let ip = match address.and_then(|x| x.parse::<SocketAddr>().ok()) {
+        Some(x) => x.ip(),
+        None => return,
+    };
+    // Other peers may share the address
+    if endpoint_ips(iface_store).contains(&ip) {
+        return;
+    }
+
+    let mut rm = rms.route_manager.lock().unwrap();
+    if let Err(e) = rm.remove_route_bypass(&ip.to_string()) {
+        log::warn!("Failed to remove bypass of {}: {}", ip, e);
+    }
+}
+
+/// Looks up the endpoints given as host names again, following the ones whose address changed
+pub(crate) fn resolve_once(
+    rms: &RouteManagerStore,
+    iface_store: &InterfaceStore,
+    event_store: &EventStore,
+) {
+    let mut pending = vec![];
+    for x in iface_store.iface_states.iter() {
+        let iface_state = x.value().lock().unwrap();
+        for peercfg in iface_state.peer_cfgs.values() {
+            if let Some(endpoint) = peercfg.endpoint.as_ref().filter(|x| is_hostname(x)) {
+                pending.push((
+                    x.key().clone(),
+                    peercfg.pubkey.clone(),
+                    endpoint.clone(),
+                    peercfg.resolved_endpoint.clone(),
+                ));
+            }
+        }
+    }
+
+    // Lookups may take a while, so they are done without holding the interfaces
+    for (if_id, pubkey, endpoint, current) in pending {
+        let address = match resolve(&endpoint, current.as_deref()) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Keeping endpoint of {} as it is: {}", pubkey, e);
+                continue;
+            }
+        };
+        if current.as_deref() == Some(address.to_string().as_str()) {
+            continue;
+        }
+
+        let iface_state_lock = match iface_store.iface_states.get(&if_id) {
+            Some(x) => Arc::clone(x.value()),
+            None => continue,
+        };
+        let previous = match update_endpoint(
+            &mut iface_state_lock.lock().unwrap(),
+            rms,
+            &pubkey,
+            &endpoint,
+            address,
+        ) {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Failed to update endpoint of {}: {}", pubkey, e);
+                continue;
+            }
+        };
+        log::info!(
+            "Endpoint {} of {} now resolves to {}",
+            endpoint,
+            pubkey,
+            address
+        );
+
+        release_bypass(rms, iface_store, previous);
+
+        event_store.emit(DaemonEvent::EndpointResolved {
+            interface: if_id,
+            pubkey,
+            endpoint,
+            address: address.to_string(),
+        });
+    }
+}
+
+/// Endpoints to try after `current`, in order, wrapping around
+fn failover_order(endpoints: &[String], current: Option<&str>) -> Vec<String> {
+    match current.and_then(|x| endpoints.iter().position(|y| y == x)) {
+        Some(i) => endpoints[i + 1..]
+            .iter()
+            .chain(endpoints[..i].iter())
+            .cloned()
+            .collect(),
+        None => endpoints.to_vec(),
+    }
+}
+
+#[test]
+fn test_failover_order() {
+    let endpoints: Vec<String> = ["a:1", "b:1", "c:1"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    assert_eq!(failover_order(&endpoints, Some("a:1")), vec!["b:1", "c:1"]);
+    assert_eq!(failover_order(&endpoints, Some("c:1")), vec!["a:1", "b:1"]);
+    assert_eq!(failover_order(&endpoints, None), endpoints);
+}
+
+/// Moves peers which went `failover_timeout` without a handshake on to their next endpoint
+fn failover_once(rms: &RouteManagerStore, iface_store: &InterfaceStore, event_store: &EventStore) {
+    let now = stats::now();
+    let mut pending = vec![];
+    for x in iface_store.iface_states.iter() {
+        let iface_state = x.value().lock().unwrap();
+        let handshakes: HashMap<String, u64> = match iface_state.interface.get_trafficstats() {
+            Ok(x) => x
+                .into_iter()
+                .filter_map(|x| Some((x.pubkey, x.last_handshake?)))
+                .collect(),
+            Err(_) => continue,
+        };
+
+        for peercfg in iface_state.peer_cfgs.values() {
+            if peercfg.suspended || peercfg.endpoints.len() < 2 {
+                continue;
+            }
+            let timeout = peercfg
+                .failover_timeout
+                .as_deref()
+                .and_then(parse_window)
+                .unwrap_or(DEFAULT_FAILOVER_TIMEOUT);
+            let last_seen = match handshakes.get(&peercfg.pubkey) {
+                Some(x) => (*x).max(peercfg.endpoint_since),
+                None => peercfg.endpoint_since,
+            };
+            if now.saturating_sub(last_seen) < timeout {
+                continue;
+            }
+
+            pending.push((
+                x.key().clone(),
+                peercfg.pubkey.clone(),
+                peercfg.endpoint.clone(),
+                failover_order(&peercfg.endpoints, peercfg.endpoint.as_deref()),
+            ));
+        }
+    }
+
+    // Lookups may take a while, so they are done without holding the interfaces
+    for (if_id, pubkey, from, candidates) in pending {
+        let (to, address) = match candidates
+            .into_iter()
+            .find_map(|x| match resolve(&x, None) {
+                Ok(address) => Some((x, address)),
+                Err(e) => {
+                    log::warn!("Skipping endpoint {} of {}: {}", x, pubkey, e);
+                    None
+                }
+            }) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let iface_state_lock = match iface_store.iface_states.get(&if_id) {
+            Some(x) => Arc::clone(x.value()),
+            None => continue,
+        };
+        let previous = {
+            let mut iface_state = iface_state_lock.lock().unwrap();
+            match update_endpoint(&mut iface_state, rms, &pubkey, &to, address) {
+                Ok(x) => {
+                    if let Some(peercfg) = iface_state.peer_cfgs.get_mut(&pubkey) {
+                        peercfg.endpoint_since = now;
+                    }
+                    x
+                }
+                Err(e) => {
+                    log::error!("Failed to move {} to endpoint {}: {}", pubkey, to, e);
+                    continue;
+                }
+            }
+        };
+        log::warn!(
+            "No handshake with {} through {}, moving to {}",
+            pubkey,
+            from.as_deref().unwrap_or("-"),
+            to
+        );
+        release_bypass(rms, iface_store, previous);
+
+        event_store.emit(DaemonEvent::EndpointFailover {
+            interface: if_id,
+            pubkey,
+            from,
+            to,
+        });
+    }
+}
+
+/// Rotates peers with several endpoints away from one which stopped answering
+pub(crate) fn failover() -> AdHoc {
+    AdHoc::on_liftoff("Endpoint failover", |rocket| {
+        Box::pin(async move {
+            let rms = rocket.state::<RouteManagerStore>().unwrap().clone();
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let event_store = rocket.state::<EventStore>().unwrap().clone();
+
+            periodic(
+                rocket,
+                "Endpoint failover",
+                Duration::from_secs(FAILOVER_CHECK_INTERVAL),
+                false,
+                move || failover_once(&rms, &iface_store, &event_store),
+            );
+        })
+    })
+}
+
+/// Follows peers whose endpoint is a host name with a changing address, e.g. dynamic DNS
+pub(crate) fn resolver() -> AdHoc {
+    AdHoc::on_liftoff("Endpoint resolver", |rocket| {
+        Box::pin(async move {
+            let rms = rocket.state::<RouteManagerStore>().unwrap().clone();
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let event_store = rocket.state::<EventStore>().unwrap().clone();
+
+            periodic(
+                rocket,
+                "Endpoint resolution",
+                Duration::from_secs(ENDPOINT_RESOLVE_INTERVAL),
+                false,
+                move || resolve_once(&rms, &iface_store, &event_store),
+            );
+        })
+    })
+}
@@ -0,0 +1,179 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::{serde, Shutdown, State};
+
+use crate::api::common::{ApiResponse, ApiResponseType};
+use crate::api::tokenauth::ApiKey;
+
+use super::expiry::ExpiryReason;
+use super::stats;
+
+const RECENT_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum DaemonEvent {
+    QuotaThreshold {
+        interface: String,
+        pubkey: String,
+        percent: u8,
+        used: u64,
+        limit: u64,
+    },
+    QuotaExceeded {
+        interface: String,
+        pubkey: String,
+    },
+    QuotaReset {
+        interface: String,
+        pubkey: String,
+    },
+    PeerExpired {
+        interface: String,
+        pubkey: String,
+        reason: ExpiryReason,
+    },
+    /// Kill switch engaged after an interface carrying a default route failed
+    FirewallBlocked {
+        interface: String,
+    },
+    FirewallRestored {
+        interface: String,
+    },
+    /// Routing state differed from what we installed
+    RouteDrift {
+        restored: Vec<String>,
+        missing: Vec<String>,
+        extra: Vec<String>,
+    },
+    /// Default gateways of the physical network changed
+    NetworkChanged {
+        previous: Vec<String>,
+        gateways: Vec<String>,
+    },
+    /// DNS of the interface was set again after a network change
+    DnsReapplied {
+        interface: String,
+    },
+    /// Endpoint given as a host name resolved to a new address
+    EndpointResolved {
+        interface: String,
+        pubkey: String,
+        endpoint: String,
+        address: String,
+    },
+    /// Peer went without a handshake for too long and was moved to its next endpoint
+    EndpointFailover {
+        interface: String,
+        pubkey: String,
+        from: Option<String>,
+        to: String,
+    },
+    /// Peers were pointed at their configured endpoints again after a network change
+    EndpointsRefreshed {
+        interface: String,
+        peers: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct EventRecord {
+    pub(crate) timestamp: u64,
+    #[serde(flatten)]
+    pub(crate) event: DaemonEvent,
+}
+
+#[derive(Clone)]
+pub(crate) struct EventStore {
+    sender: broadcast::Sender<EventRecord>,
+    recent: Arc<Mutex<VecDeque<EventRecord>>>,
+}
+
+impl EventStore {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(RECENT_EVENTS);
+        EventStore {
+            sender,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(RECENT_EVENTS))),
+        }
+    }
+
+    pub(crate) fn emit(&self, event: DaemonEvent) {
+        log::info!("Event: {:?}", event);
+        let record = EventRecord {
+            timestamp: stats::now(),
+            event,
+        };
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(record.clone());
+        drop(recent);
+
+        // Nobody listening is not an error
+        self.sender.send(record).ok();
+    }
+}
+
+#[get("/events")]
+pub(crate) async fn get_events(
+    _apikey: ApiKey,
+    event_store: &State<EventStore>,
+) -> ApiResponseType<Vec<EventRecord>> {
+    let recent = event_store.recent.lock().unwrap();
+    (
+        Status::Ok,
+        ApiResponse::ok(recent.iter().cloned().collect()),
+    )
+}
+
+#[get("/events/stream")]
+pub(crate) async fn stream_events(
+    _apikey: ApiKey,
+    event_store: &State<EventStore>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut rx = event_store.sender.subscribe();
+    EventStream! {
+        loop {
+            let record = select! {
+                msg = rx.recv() => match msg {
+                    Ok(x) => x,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+
+            yield Event::json(&record);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use rocket::fairing::AdHoc;
+use rocket::serde;
+
+use crate::util::state;
+
+use super::events::{DaemonEvent, EventStore};
+use super::periodic::periodic;
+use super::quota::UsageStore;
+use super::stats::{self, parse_window};
+use super::types::{InterfaceStore, IpStore, PeerConfig, RouteManagerStore};
+use super::{endpoint, peer, route};
+
+const EXPIRY_STATE: &str = "expiry.json";
+const EXPIRY_CHECK_INTERVAL: u64 = 30;
+/// How long entries loaded from the state directory wait for their peer to be pushed again
+pub(crate) const DEFAULT_RESTORE_GRACE: u64 = 10 * 60;
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExpiryReason {
+    Expired,
+    Idle,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ExpiryEntry {
+    interface: String,
+    pubkey: String,
+    expires_at: Option<u64>,
+    idle_timeout: Option<u64>,
+    /// Last handshake, or the time the peer was scheduled if it never shook hands
+    last_seen: u64,
+    /// Loaded from the state directory, and its peer not pushed again since
+    #[serde(skip)]
+    restored: bool,
+}
+
+impl ExpiryEntry {
+    fn due(&self, now: u64) -> Option<ExpiryReason> {
+        if matches!(self.expires_at, Some(x) if x <= now) {
+            return Some(ExpiryReason::Expired);
+        }
+        if matches!(self.idle_timeout, Some(x) if now.saturating_sub(self.last_seen) >= x) {
+            return Some(ExpiryReason::Idle);
+        }
+        None
+    }
+}
+
+#[test]
+fn test_expiry_due() {
+    let mut entry = ExpiryEntry {
+        interface: "wg0".to_string(),
+        pubkey: "pubk".to_string(),
+        expires_at: Some(1000),
+        idle_timeout: None,
+        last_seen: 0,
+        restored: false,
+    };
+    assert!(entry.due(999).is_none());
+    assert!(matches!(entry.due(1000), Some(ExpiryReason::Expired)));
+
+    entry.expires_at = None;
+    entry.idle_timeout = Some(3600);
+    entry.last_seen = 500;
+    assert!(entry.due(4099).is_none());
+    assert!(matches!(entry.due(4100), Some(ExpiryReason::Idle)));
+}
+
+/// Removal schedule of time-limited peers. Persisted to the state directory, so that the
+/// deadlines and idle time of peers pushed again after a restart are kept.
+#[derive(Clone)]
+pub(crate) struct ExpiryStore {
+    entries: Arc<DashMap<(String, String), ExpiryEntry>>,
+    loaded_at: u64,
+    restore_grace: u64,
+}
+
+impl ExpiryStore {
+    pub(crate) fn load(restore_grace: u64) -> Self {
+        let now = stats::now();
+        let entries = DashMap::new();
+        for mut entry in state::load::<Vec<ExpiryEntry>>(EXPIRY_STATE).unwrap_or_default() {
+            // Peer would be removed as soon as it is pushed again
+            if matches!(entry.due(now), Some(ExpiryReason::Expired)) {
+                continue;
+            }
+            entry.restored = true;
+            entries.insert((entry.interface.clone(), entry.pubkey.clone()), entry);
+        }
+
+        ExpiryStore {
+            entries: Arc::new(entries),
+            loaded_at: now,
+            restore_grace,
+        }
+    }
+
+    /// Drops an entry whose peer is missing. Restored entries are kept for a while, as
+    /// interfaces and peers are only re-created once the controller pushes them again. Returns
+    /// whether the entry was dropped.
+    fn prune(&self, entry: &ExpiryEntry, now: u64) -> bool {
+        if entry.restored && now.saturating_sub(self.loaded_at) < self.restore_grace {
+            return false;
+        }
+        // Left alone if the peer was pushed again since `entry` was read
+        self.entries
+            .remove_if(&(entry.interface.clone(), entry.pubkey.clone()), |_, x| {
+                x == entry
+            })
+            .is_some()
+    }
+
+    fn persist(&self) {
+        let entries: Vec<ExpiryEntry> = self.entries.iter().map(|x| x.value().clone()).collect();
+        if let Err(e) = state::save(EXPIRY_STATE, &entries) {
+            log::error!("Failed to save peer expiry state: {}", e);
+        }
+    }
+
+    pub(crate) fn schedule(&self, if_id: &str, peercfg: &PeerConfig, now: u64) {
+        let idle_timeout = peercfg.idle_timeout.as_deref().and_then(parse_window);
+        if peercfg.expires_at.is_none() && idle_timeout.is_none() {
+            self.unschedule(if_id, &peercfg.pubkey);
+            return;
+        }
+
+        let key = (if_id.to_string(), peercfg.pubkey.clone());
+        let last_seen = match self.entries.get(&key) {
+            Some(x) => x.last_seen,
+            None => now,
+        };
+        self.entries.insert(
+            key,
+            ExpiryEntry {
+                interface: if_id.to_string(),
+                pubkey: peercfg.pubkey.clone(),
+                expires_at: peercfg.expires_at,
+                idle_timeout,
+                last_seen,
+                restored: false,
+            },
+        );
+        self.persist();
+    }
+
+    pub(crate) fn unschedule(&self, if_id: &str, pubk: &str) {
+        if self
+            .entries
+            .remove(&(if_id.to_string(), pubk.to_string()))
+            .is_some()
+        {
+            self.persist();
+        }
+    }
+
+    pub(crate) fn unschedule_iface(&self, if_id: &str) {
+        let before = self.entries.len();
+        self.entries.retain(|k, _| k.0 != if_id);
+        if self.entries.len() != before {
+            self.persist();
+        }
+    }
+}
+
+#[test]
+fn test_expiry_prune() {
+    let store = ExpiryStore {
+        entries: Arc::new(DashMap::new()),
+        loaded_at: 1000,
+        restore_grace: 600,
+    };
+    let mut entry = ExpiryEntry {
+        interface: "wg0".to_string(),
+        pubkey: "pubk".to_string(),
+        expires_at: None,
+        idle_timeout: Some(3600),
+        last_seen: 0,
+        restored: true,
+    };
+    store
+        .entries
+        .insert(("wg0".to_string(), "pubk".to_string()), entry.clone());
+
+    // Restored entries wait for their peer to be pushed again
+    assert!(!store.prune(&entry, 1599));
+    assert!(store.prune(&entry, 1600));
+    assert!(store.entries.is_empty());
+
+    // Pushed again, and rescheduled, since the entry was read
+    let pushed = ExpiryEntry {
+        restored: false,
+        ..entry.clone()
+    };
+    store
+        .entries
+        .insert(("wg0".to_string(), "pubk".to_string()), pushed);
+    assert!(!store.prune(&entry, 1600));
+    assert_eq!(store.entries.len(), 1);
+
+    entry.restored = false;
+    store
+        .entries
+        .insert(("wg0".to_string(), "pubk".to_string()), entry.clone());
+    assert!(store.prune(&entry, 1000));
+}
+
+fn check_once(
+    iface_store: &InterfaceStore,
+    ip_store: &IpStore,
+    rms: &RouteManagerStore,
+    expiry_store: &ExpiryStore,
+    usage_store: &UsageStore,
+    event_store: &EventStore,
+) {
+    let now = stats::now();
+    let mut dirty = false;
+    let entries: Vec<ExpiryEntry> = expiry_store
+        .entries
+        .iter()
+        .map(|x| x.value().clone())
+        .collect();
+
+    for entry in entries {
+        // Interface or peer may not have been re-created yet after a restart
+        let iface_state_lock = match iface_store.iface_states.get(&entry.interface) {
+            Some(x) => Arc::clone(x.value()),
+            None => {
+                dirty |= expiry_store.prune(&entry, now);
+                continue;
+            }
+        };
+        let mut iface_state = iface_state_lock.lock().unwrap();
+        // Peers are scheduled with their interface locked, so the entry may have changed since
+        // the snapshot, but not from here on
+        let mut entry = match expiry_store
+            .entries
+            .get(&(entry.interface.clone(), entry.pubkey.clone()))
+        {
+            Some(x) => x.value().clone(),
+            None => continue,
+        };
+        if !iface_state.peer_cfgs.contains_key(&entry.pubkey) {
+            dirty |= expiry_store.prune(&entry, now);
+            continue;
+        }
+
+        if entry.idle_timeout.is_some() {
+            let handshake = iface_state
+                .interface
+                .get_trafficstats()
+                .ok()
+                .and_then(|x| x.into_iter().find(|x| x.pubkey == entry.pubkey))
+                .and_then(|x| x.last_handshake);
+            if let Some(x) = handshake.filter(|x| *x > entry.last_seen) {
+                entry.last_seen = x;
+                expiry_store.entries.insert(
+                    (entry.interface.clone(), entry.pubkey.clone()),
+                    entry.clone(),
+                );
+                dirty = true;
+            }
+        }
+
+        let reason = match entry.due(now) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        match peer::remove_peer(&mut iface_state, ip_store, &entry.pubkey) {
+            Ok(peercfg) => {
+                expiry_store
+                    .entries
+                    .remove(&(entry.interface.clone(), entry.pubkey.clone()));
+                dirty = true;
+                usage_store.forget(&entry.interface, &entry.pubkey);
+
+                let wanted = route::auto_routes(&iface_state);
+                drop(iface_state);
+                route::sync_auto_routes(rms, &entry.interface, wanted);
+                if let Some(x) = peercfg {
+                    endpoint::release_bypass(rms, iface_store, x.resolved_endpoint);
+                }
+                event_store.emit(DaemonEvent::PeerExpired {
+                    interface: entry.interface,
+                    pubkey: entry.pubkey,
+                    reason,
+                });
+            }
+            Err(e) => log::error!("Failed to remove expired peer {}: {}", entry.pubkey, e),
+        }
+    }
+
+    if dirty {
+        expiry_store.persist();
+    }
+}
+
+pub(crate) fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("Peer expiry scheduler", |rocket| {
+        Box::pin(async move {
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let ip_store = rocket.state::<IpStore>().unwrap().clone();
+            let rms = rocket.state::<RouteManagerStore>().unwrap().clone();
+            let expiry_store = rocket.state::<ExpiryStore>().unwrap().clone();
+            let usage_store = rocket.state::<UsageStore>().unwrap().clone();
+            let event_store = rocket.state::<EventStore>().unwrap().clone();
+
+            periodic(
+                rocket,
+                "Peer expiry",
+                Duration::from_secs(EXPIRY_CHECK_INTERVAL),
+                false,
+                move || {
+                    check_once(
+                        &iface_store,
+                        &ip_store,
+                        &rms,
+                        &expiry_store,
+                        &usage_store,
+                        &event_store,
+                    )
+                },
+            );
+        })
+    })
+}
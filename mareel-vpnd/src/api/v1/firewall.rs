@@ -0,0 +1,347 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::{http::Status, serde, State};
+
+use crate::api::common::{ApiResponse, ApiResponseType};
+use crate::api::tokenauth::ApiKey;
+use crate::config::{DnsLeakProtection, FirewallConfig};
+use crate::util::state;
+use wgctrl::platform_specific::common::{
+    is_default_route, DnsLeakPolicy, FirewallPolicy, InterfaceStatus, PlatformFirewall,
+};
+use wgctrl::platform_specific::Firewall;
+
+use super::events::{DaemonEvent, EventStore};
+use super::periodic::periodic;
+use super::types::{InterfaceStore, RouteManagerStore};
+
+const FIREWALL_CHECK_INTERVAL: u64 = 5;
+const FIREWALL_STATE: &str = "firewall.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FirewallMode {
+    /// Only tunnel traffic and WireGuard traffic to the peers pass
+    Connected,
+    Blocked,
+    Off,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct FirewallConfigurationMessage {
+    pub(crate) policy: FirewallMode,
+    pub(crate) allow_lan: Option<bool>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct FirewallStatus {
+    pub(crate) policy: FirewallMode,
+    pub(crate) allow_lan: bool,
+    /// Interface whose failure engaged the kill switch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) blocked_by: Option<String>,
+}
+
+/// What was last set through the API, so that the kill switch stays on across restarts
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SavedFirewall {
+    policy: FirewallMode,
+    allow_lan: bool,
+}
+
+struct FirewallState {
+    mode: FirewallMode,
+    allow_lan: bool,
+    blocked_by: Option<String>,
+    applied: Option<FirewallPolicy>,
+}
+
+impl FirewallState {
+    fn save(&self) {
+        let saved = SavedFirewall {
+            policy: self.mode,
+            allow_lan: self.allow_lan,
+        };
+        if let Err(e) = state::save(FIREWALL_STATE, &saved) {
+            log::error!("Failed to save firewall state: {}", e);
+        }
+    }
+
+    fn status(&self) -> FirewallStatus {
+        FirewallStatus {
+            policy: self.mode,
+            allow_lan: self.allow_lan,
+            blocked_by: self.blocked_by.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct FirewallStore {
+    firewall: Arc<Mutex<Firewall>>,
+    state: Arc<Mutex<FirewallState>>,
+    block_on_failure: bool,
+    dns_leak_protection: DnsLeakProtection,
+}
+
+impl FirewallStore {
+    pub(crate) fn new(firewall: Firewall, cfg: Option<&FirewallConfig>) -> Self {
+        let saved = state::load::<SavedFirewall>(FIREWALL_STATE);
+        FirewallStore {
+            firewall: Arc::new(Mutex::new(firewall)),
+            state: Arc::new(Mutex::new(FirewallState {
+                mode: saved.as_ref().map_or(FirewallMode::Off, |x| x.policy),
+                allow_lan: match &saved {
+                    Some(x) => x.allow_lan,
+                    None => cfg.and_then(|x| x.allow_lan).unwrap_or(false),
+                },
+                blocked_by: None,
+                applied: None,
+            })),
+            block_on_failure: cfg.and_then(|x| x.block_on_failure).unwrap_or(true),
+            dns_leak_protection: cfg
+                .and_then(|x| x.dns_leak_protection)
+                .unwrap_or(DnsLeakProtection::Off),
+        }
+    }
+
+    /// Installs DNS leak rules for `servers`, or the configured default if `mode` is not given
+    pub(crate) fn set_dns(
+        &self,
+        mode: Option<DnsLeakProtection>,
+        servers: &[IpAddr],
+    ) -> Result<(), String> {
+        let servers = servers.to_vec();
+        let policy = match mode.unwrap_or(self.dns_leak_protection) {
+            DnsLeakProtection::Block => DnsLeakPolicy::Block { servers },
+            DnsLeakProtection::Redirect => DnsLeakPolicy::Redirect { servers },
+            DnsLeakProtection::Off => DnsLeakPolicy::Off,
+        };
+
+        let mut firewall = self.firewall.lock().unwrap();
+        firewall
+            .apply_dns_policy(&policy)
+            .map_err(|e| e.to_string())
+    }
+
+    pub(crate) fn clear_dns(&self) -> Result<(), String> {
+        self.set_dns(Some(DnsLeakProtection::Off), &[])
+    }
+
+    /// Applies the policy currently in effect if it differs from the installed one
+    fn apply(&self, state: &mut FirewallState, iface_store: &InterfaceStore) -> Result<(), String> {
+        let policy = if state.blocked_by.is_some() {
+            FirewallPolicy::Blocked {
+                allow_lan: state.allow_lan,
+            }
+        } else {
+            match state.mode {
+                FirewallMode::Connected => connected_policy(iface_store, state.allow_lan),
+                FirewallMode::Blocked => FirewallPolicy::Blocked {
+                    allow_lan: state.allow_lan,
+                },
+                FirewallMode::Off => FirewallPolicy::Off,
+            }
+        };
+
+        if state.applied.as_ref() == Some(&policy) {
+            return Ok(());
+        }
+
+        let mut firewall = self.firewall.lock().unwrap();
+        firewall.apply_policy(&policy).map_err(|e| e.to_string())?;
+        state.applied = Some(policy);
+        Ok(())
+    }
+
+    /// Whether local networks are to stay reachable, which routing follows as well
+    pub(crate) fn allow_lan(&self) -> bool {
+        self.state.lock().unwrap().allow_lan
+    }
+
+    pub(crate) fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.firewall.lock().unwrap().reset() {
+            log::error!("Failed to reset firewall: {}", e);
+        }
+        state.applied = None;
+    }
+}
+
+/// Everything needed to keep the tunnels up: their interfaces, peer endpoints and listen ports
+fn connected_policy(iface_store: &InterfaceStore, allow_lan: bool) -> FirewallPolicy {
+    let mut tunnels = vec![];
+    let mut endpoints = vec![];
+    let mut listen_ports = vec![];
+
+    for x in iface_store.iface_states.iter() {
+        let iface_state = x.value().lock().unwrap();
+        tunnels.push(
+            iface_state
+                .interface
+                .get_platformid()
+                .unwrap_or_else(|_| iface_state.iface_cfg.name.clone()),
+        );
+        listen_ports.extend(iface_state.iface_cfg.listen_port);
+        endpoints.extend(
+            iface_state
+                .peer_cfgs
+                .values()
+                .filter(|x| !x.suspended)
+                .filter_map(|x| x.resolved_endpoint.as_ref())
+                .filter_map(|x| x.parse::<SocketAddr>().ok()),
+        );
+    }
+
+    // Keeps the policy comparable whatever the map iteration order
+    tunnels.sort();
+    endpoints.sort();
+    endpoints.dedup();
+    listen_ports.sort_unstable();
+    listen_ports.dedup();
+
+    FirewallPolicy::Connected {
+        tunnels,
+        endpoints,
+        listen_ports,
+        allow_lan,
+    }
+}
+
+/// An interface fails when it is supposed to be up but the device is gone
+fn is_failed(iface_store: &InterfaceStore, if_id: &str) -> bool {
+    let iface_state_lock = match iface_store.iface_states.get(if_id) {
+        Some(x) => Arc::clone(x.value()),
+        None => return false,
+    };
+    let iface_state = iface_state_lock.lock().unwrap();
+
+    matches!(iface_state.interface.get_status(), InterfaceStatus::Running)
+        && iface_state.interface.get_trafficstats().is_err()
+}
+
+fn failed_default_route(iface_store: &InterfaceStore, rms: &RouteManagerStore) -> Option<String> {
+    let candidates: Vec<String> = rms
+        .route_store
+        .iter()
+        .filter(|x| x.value().keys().any(|r| is_default_route(r)))
+        .map(|x| x.key().clone())
+        .collect();
+
+    candidates.into_iter().find(|x| is_failed(iface_store, x))
+}
+
+fn check_once(
+    fw_store: &FirewallStore,
+    iface_store: &InterfaceStore,
+    rms: &RouteManagerStore,
+    event_store: &EventStore,
+) {
+    let mut state = fw_store.state.lock().unwrap();
+
+    if fw_store.block_on_failure {
+        match state.blocked_by.clone() {
+            None => {
+                if let Some(x) = failed_default_route(iface_store, rms) {
+                    state.blocked_by = Some(x.clone());
+                    event_store.emit(DaemonEvent::FirewallBlocked { interface: x });
+                }
+            }
+            // Recovered, or stopped or deleted on purpose
+            Some(x) if !is_failed(iface_store, &x) => {
+                state.blocked_by = None;
+                event_store.emit(DaemonEvent::FirewallRestored { interface: x });
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Err(e) = fw_store.apply(&mut state, iface_store) {
+        log::error!("Failed to apply firewall policy: {}", e);
+    }
+}
+
+#[get("/firewall")]
+pub(crate) async fn get_firewall(
+    _apikey: ApiKey,
+    fw_store: &State<FirewallStore>,
+) -> ApiResponseType<FirewallStatus> {
+    let state = fw_store.state.lock().unwrap();
+    (Status::Ok, ApiResponse::ok(state.status()))
+}
+
+#[put("/firewall", format = "json", data = "<fwcfg>")]
+pub(crate) async fn put_firewall(
+    _apikey: ApiKey,
+    fw_store: &State<FirewallStore>,
+    iface_store: &State<InterfaceStore>,
+    fwcfg: Json<FirewallConfigurationMessage>,
+) -> ApiResponseType<FirewallStatus> {
+    let mut state = fw_store.state.lock().unwrap();
+    let (mode, allow_lan) = (state.mode, state.allow_lan);
+
+    state.mode = fwcfg.policy;
+    if let Some(x) = fwcfg.allow_lan {
+        state.allow_lan = x;
+    }
+
+    match fw_store.apply(&mut state, iface_store) {
+        Ok(_) => {
+            state.save();
+            (Status::Ok, ApiResponse::ok(state.status()))
+        }
+        Err(e) => {
+            state.mode = mode;
+            state.allow_lan = allow_lan;
+            (Status::InternalServerError, ApiResponse::err(-1, &e))
+        }
+    }
+}
+
+/// Keeps the connected policy in sync with interfaces and peers, and blocks traffic when an
+/// interface carrying a default route fails
+pub(crate) fn monitor() -> AdHoc {
+    AdHoc::on_liftoff("Firewall monitor", |rocket| {
+        Box::pin(async move {
+            let fw_store = rocket.state::<FirewallStore>().unwrap().clone();
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let rms = rocket.state::<RouteManagerStore>().unwrap().clone();
+            let event_store = rocket.state::<EventStore>().unwrap().clone();
+
+            periodic(
+                rocket,
+                "Firewall check",
+                Duration::from_secs(FIREWALL_CHECK_INTERVAL),
+                false,
+                move || check_once(&fw_store, &iface_store, &rms, &event_store),
+            );
+        })
+    })
+}
@@ -0,0 +1,782 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use prometheus::{IntCounter, IntCounterVec, Opts, Registry};
+use rocket::fairing::AdHoc;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::net::{TcpListener, TcpStream, UdpSocket};
+use rocket::tokio::sync::Semaphore;
+use rocket::tokio::time::timeout;
+
+use crate::config::ForwarderConfig;
+
+use super::types::parse_domain;
+
+pub(crate) const DEFAULT_LISTEN: &str = "127.0.0.1:53";
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 1024;
+const DNS_PORT: u16 = 53;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// UDP queries answered at once, further datagrams wait in the socket buffer
+const MAX_UDP_IN_FLIGHT: usize = 256;
+/// Upper bound on how long answers are kept, whatever their TTL
+const MAX_CACHE_TTL: u32 = 3600;
+
+const HEADER_LEN: usize = 12;
+const MAX_UDP_LEN: usize = 4096;
+const MIN_UDP_LEN: usize = 512;
+const TYPE_OPT: u16 = 41;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([msg[pos], msg[pos + 1], msg[pos + 2], msg[pos + 3]])
+}
+
+/// Lowercased name, type and class of the only question, and where the question ends
+fn parse_question(msg: &[u8]) -> Option<(String, u16, u16, usize)> {
+    if msg.len() < HEADER_LEN || read_u16(msg, 4)? != 1 {
+        return None;
+    }
+
+    let mut labels = vec![];
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Questions come first, there is nothing to point back to
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(msg.get(pos..pos + len)?).to_ascii_lowercase());
+        pos += len;
+    }
+
+    let qtype = read_u16(msg, pos)?;
+    let qclass = read_u16(msg, pos + 2)?;
+    Some((labels.join("."), qtype, qclass, pos + 4))
+}
+
+/// Whether `response` is the answer to `query`, rather than to another one with the same ID
+fn answers(query: &[u8], response: &[u8]) -> bool {
+    let question =
+        |msg: &[u8]| parse_question(msg).map(|(name, qtype, qclass, _)| (name, qtype, qclass));
+    response.len() >= HEADER_LEN
+        && response[..2] == query[..2]
+        && response[2] & 0x80 != 0
+        && question(response).map_or(false, |x| Some(x) == question(query))
+}
+
+/// Position after the possibly compressed name at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len & 0xc0 {
+            0xc0 => return Some(pos + 2),
+            0 if len == 0 => return Some(pos + 1),
+            0 => pos += 1 + len,
+            _ => return None,
+        }
+    }
+}
+
+/// Type of every resource record and where its type field is
+fn records(msg: &[u8]) -> Option<Vec<(u16, usize)>> {
+    let (_, _, _, mut pos) = parse_question(msg)?;
+    let count = (6..HEADER_LEN)
+        .step_by(2)
+        .map(|x| read_u16(msg, x).unwrap_or(0) as usize)
+        .sum::<usize>();
+
+    let mut records = vec![];
+    for _ in 0..count {
+        pos = skip_name(msg, pos)?;
+        let rtype = read_u16(msg, pos)?;
+        let rdlen = read_u16(msg, pos + 8)? as usize;
+        records.push((rtype, pos));
+        pos += 10 + rdlen;
+        if pos > msg.len() {
+            return None;
+        }
+    }
+    Some(records)
+}
+
+/// Where the TTL of every record is, leaving out the EDNS pseudo record
+fn ttl_offsets(msg: &[u8]) -> Option<Vec<usize>> {
+    Some(
+        records(msg)?
+            .into_iter()
+            .filter(|(rtype, _)| *rtype != TYPE_OPT)
+            .map(|(_, pos)| pos + 4)
+            .collect(),
+    )
+}
+
+/// Largest answer the client takes over UDP, as advertised through EDNS
+fn udp_limit(query: &[u8]) -> usize {
+    records(query)
+        .and_then(|x| x.into_iter().find(|(rtype, _)| *rtype == TYPE_OPT))
+        .and_then(|(_, pos)| read_u16(query, pos + 2))
+        .map(|x| (x as usize).clamp(MIN_UDP_LEN, MAX_UDP_LEN))
+        .unwrap_or(MIN_UDP_LEN)
+}
+
+/// Answer to `query` carrying only `rcode`
+fn error_response(query: &[u8], rcode: u8) -> Vec<u8> {
+    let end = parse_question(query).map(|x| x.3);
+    let mut msg = query[..end.unwrap_or(HEADER_LEN)].to_vec();
+    // Keep opcode and RD, set QR and RA
+    msg[2] = 0x80 | (query[2] & 0x79);
+    msg[3] = 0x80 | rcode;
+    msg[4..6].copy_from_slice(&(end.is_some() as u16).to_be_bytes());
+    msg[6..HEADER_LEN].iter_mut().for_each(|x| *x = 0);
+    msg
+}
+
+/// Header and question of `response` with the TC bit, so that the client retries over TCP
+fn truncate(response: &[u8]) -> Vec<u8> {
+    let end = parse_question(response).map(|x| x.3).unwrap_or(HEADER_LEN);
+    let mut msg = response[..end].to_vec();
+    msg[2] |= 0x02;
+    msg[6..HEADER_LEN].iter_mut().for_each(|x| *x = 0);
+    msg
+}
+
+type CacheKey = (String, u16, u16);
+
+struct CacheEntry {
+    response: Vec<u8>,
+    stored: Instant,
+    ttl: u32,
+}
+
+impl CacheEntry {
+    fn expires(&self) -> Instant {
+        self.stored + Duration::from_secs(self.ttl as u64)
+    }
+}
+
+struct Cache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Cache {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Cached answer with its TTLs counted down to `now`
+    fn get(&mut self, key: &CacheKey, now: Instant) -> Option<Vec<u8>> {
+        let entry = self.entries.get(key)?;
+        if entry.expires() <= now {
+            self.entries.remove(key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut response = entry.response.clone();
+        for offset in ttl_offsets(&response).unwrap_or_default() {
+            let ttl = read_u32(&response, offset).saturating_sub(elapsed);
+            response[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    fn insert(&mut self, key: CacheKey, response: &[u8], now: Instant) {
+        // Only complete answers and NXDOMAIN are worth keeping
+        let rcode = response[3] & 0x0f;
+        if self.capacity == 0 || response[2] & 0x02 != 0 || !matches!(rcode, 0 | RCODE_NXDOMAIN) {
+            return;
+        }
+        let ttl = match ttl_offsets(response)
+            .and_then(|x| x.into_iter().map(|x| read_u32(response, x)).min())
+        {
+            Some(x) if x > 0 => x.min(MAX_CACHE_TTL),
+            _ => return,
+        };
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, x| x.expires() > now);
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let first = self
+                .entries
+                .iter()
+                .min_by_key(|(_, x)| x.expires())
+                .map(|(k, _)| k.clone());
+            if let Some(x) = first {
+                self.entries.remove(&x);
+            }
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response: response.to_vec(),
+                stored: now,
+                ttl,
+            },
+        );
+    }
+}
+
+async fn read_tcp_msg(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let len = stream.read_u16().await? as usize;
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+async fn write_tcp_msg(stream: &mut TcpStream, msg: &[u8]) -> io::Result<()> {
+    let mut out = (msg.len() as u16).to_be_bytes().to_vec();
+    out.extend_from_slice(msg);
+    stream.write_all(&out).await
+}
+
+async fn exchange_udp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let bind = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0u8; MAX_UDP_LEN];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Stray datagrams are dropped, as long as the right one shows up in time
+        if answers(query, &buf[..len]) {
+            return Ok(buf[..len].to_vec());
+        }
+    }
+}
+
+async fn exchange_tcp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(server).await?;
+    write_tcp_msg(&mut stream, query).await?;
+    read_tcp_msg(&mut stream).await
+}
+
+/// Asks `server` over UDP, and again over TCP if the answer did not fit
+async fn exchange(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let timed_out = |_| io::Error::new(io::ErrorKind::TimedOut, "no answer");
+    let response = timeout(UPSTREAM_TIMEOUT, exchange_udp(server, query))
+        .await
+        .map_err(timed_out)??;
+    if response[2] & 0x02 == 0 {
+        return Ok(response);
+    }
+
+    let response = timeout(UPSTREAM_TIMEOUT, exchange_tcp(server, query))
+        .await
+        .map_err(timed_out)??;
+    match answers(query, &response) {
+        true => Ok(response),
+        false => Err(io::Error::new(io::ErrorKind::InvalidData, "bad answer")),
+    }
+}
+
+fn parse_server(server: &str) -> Result<SocketAddr, String> {
+    server
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            server
+                .parse::<IpAddr>()
+                .map(|x| SocketAddr::new(x, DNS_PORT))
+        })
+        .map_err(|_| format!("Invalid DNS server: {}", server))
+}
+
+/// Names under `domain` are forwarded to `servers` instead of the servers of the interfaces
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ForwarderRule {
+    pub(crate) domain: String,
+    pub(crate) servers: Vec<SocketAddr>,
+}
+
+/// Small caching DNS forwarder the system resolver can be pointed at, for platforms where the
+/// resolver configuration keeps getting overwritten.
+pub(crate) struct Forwarder {
+    listen: SocketAddr,
+    rules: Vec<ForwarderRule>,
+    /// Servers set through the API, by interface
+    upstreams: RwLock<BTreeMap<String, Vec<SocketAddr>>>,
+    cache: Mutex<Cache>,
+    running: AtomicBool,
+    queries: IntCounterVec,
+    cache_hits: IntCounter,
+    upstream_failures: IntCounter,
+}
+
+impl Forwarder {
+    pub(crate) fn new(listen: SocketAddr, rules: Vec<ForwarderRule>, cache_size: usize) -> Self {
+        Forwarder {
+            listen,
+            rules,
+            upstreams: RwLock::new(BTreeMap::new()),
+            cache: Mutex::new(Cache::new(cache_size)),
+            running: AtomicBool::new(false),
+            queries: IntCounterVec::new(
+                Opts::new(
+                    "dns_forwarder_queries",
+                    "Queries received by the DNS forwarder",
+                ),
+                &["proto"],
+            )
+            .unwrap(),
+            cache_hits: IntCounter::new(
+                "dns_forwarder_cache_hits",
+                "Queries answered from the DNS forwarder cache",
+            )
+            .unwrap(),
+            upstream_failures: IntCounter::new(
+                "dns_forwarder_upstream_failures",
+                "Queries no upstream server answered",
+            )
+            .unwrap(),
+        }
+    }
+
+    pub(crate) fn from_config(cfg: &ForwarderConfig) -> Result<Self, String> {
+        let listen = cfg.listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+        let listen: SocketAddr = listen
+            .parse()
+            .map_err(|_| format!("Invalid listen address: {}", listen))?;
+        // System resolvers can't be given a port
+        if listen.ip().is_unspecified() || listen.port() != DNS_PORT {
+            return Err(format!("The forwarder can't listen on {}", listen));
+        }
+
+        let mut rules = vec![];
+        for rule in cfg.rules.iter().flatten() {
+            rules.push(ForwarderRule {
+                domain: parse_domain(&rule.domain)?,
+                servers: rule
+                    .servers
+                    .iter()
+                    .map(|x| parse_server(x))
+                    .collect::<Result<Vec<SocketAddr>, String>>()?,
+            });
+        }
+
+        Ok(Forwarder::new(
+            listen,
+            rules,
+            cfg.cache_size.unwrap_or(DEFAULT_CACHE_SIZE),
+        ))
+    }
+
+    pub(crate) fn register(&self, registry: &Registry) -> Result<(), prometheus::Error> {
+        registry.register(Box::new(self.queries.clone()))?;
+        registry.register(Box::new(self.cache_hits.clone()))?;
+        registry.register(Box::new(self.upstream_failures.clone()))
+    }
+
+    pub(crate) fn listen(&self) -> SocketAddr {
+        self.listen
+    }
+
+    /// Forwards to `servers` for `interface`. An empty list removes the interface.
+    pub(crate) fn set_upstreams(&self, interface: &str, servers: &[SocketAddr]) {
+        let mut upstreams = self.upstreams.write().unwrap();
+        match servers.is_empty() {
+            true => upstreams.remove(interface),
+            false => upstreams.insert(interface.to_string(), servers.to_vec()),
+        };
+        // Answers may differ between servers
+        self.cache.lock().unwrap().entries.clear();
+    }
+
+    pub(crate) fn serves(&self, interface: &str) -> bool {
+        self.upstreams.read().unwrap().contains_key(interface)
+    }
+
+    /// Servers for `name`, from the most specific matching rule or else from all interfaces
+    fn upstreams_for(&self, name: &str) -> Vec<SocketAddr> {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|x| name == x.domain || name.ends_with(&format!(".{}", x.domain)))
+            .max_by_key(|x| x.domain.len());
+        if let Some(x) = rule {
+            return x.servers.clone();
+        }
+
+        let mut servers = vec![];
+        for server in self.upstreams.read().unwrap().values().flatten() {
+            if !servers.contains(server) {
+                servers.push(*server);
+            }
+        }
+        servers
+    }
+
+    /// Answer to `query`. Nothing is sent back to what is not a DNS message.
+    async fn resolve(&self, query: &[u8], proto: &str) -> Option<Vec<u8>> {
+        if query.len() < HEADER_LEN {
+            return None;
+        }
+        self.queries.with_label_values(&[proto]).inc();
+
+        let (name, qtype, qclass, _) = match parse_question(query) {
+            Some(x) => x,
+            None => return Some(error_response(query, RCODE_FORMERR)),
+        };
+        let key = (name, qtype, qclass);
+        if let Some(mut response) = self.cache.lock().unwrap().get(&key, Instant::now()) {
+            response[..2].copy_from_slice(&query[..2]);
+            self.cache_hits.inc();
+            return Some(response);
+        }
+
+        for server in self.upstreams_for(&key.0) {
+            match exchange(server, query).await {
+                Ok(response) => {
+                    self.cache
+                        .lock()
+                        .unwrap()
+                        .insert(key, &response, Instant::now());
+                    return Some(response);
+                }
+                Err(e) => log::debug!("DNS server {} failed to answer: {}", server, e),
+            }
+        }
+
+        self.upstream_failures.inc();
+        Some(error_response(query, RCODE_SERVFAIL))
+    }
+
+    /// Binds UDP and TCP to the same address, the port of UDP is reused if it was left to the OS
+    async fn bind(&self) -> io::Result<(Arc<UdpSocket>, TcpListener)> {
+        let udp = UdpSocket::bind(self.listen).await?;
+        let tcp = TcpListener::bind(udp.local_addr()?).await?;
+        Ok((Arc::new(udp), tcp))
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: Arc<UdpSocket>) {
+        let in_flight = Arc::new(Semaphore::new(MAX_UDP_IN_FLIGHT));
+        let mut buf = vec![0u8; MAX_UDP_LEN];
+        loop {
+            // Never closed, so acquiring only waits
+            let permit = Arc::clone(&in_flight).acquire_owned().await.unwrap();
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(e) => {
+                    log::debug!("DNS forwarder failed to receive: {}", e);
+                    rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let query = buf[..len].to_vec();
+            let forwarder = Arc::clone(&self);
+            let socket = Arc::clone(&socket);
+            rocket::tokio::spawn(async move {
+                if let Some(mut response) = forwarder.resolve(&query, "udp").await {
+                    if response.len() > udp_limit(&query) {
+                        response = truncate(&response);
+                    }
+                    if let Err(e) = socket.send_to(&response, peer).await {
+                        log::debug!("Failed to answer DNS query of {}: {}", peer, e);
+                    }
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((x, _)) => x,
+                Err(e) => {
+                    log::debug!("DNS forwarder failed to accept: {}", e);
+                    rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let forwarder = Arc::clone(&self);
+            rocket::tokio::spawn(async move {
+                // Clients may send several queries over one connection
+                while let Ok(Ok(query)) = timeout(TCP_IDLE_TIMEOUT, read_tcp_msg(&mut stream)).await
+                {
+                    let response = match forwarder.resolve(&query, "tcp").await {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    if write_tcp_msg(&mut stream, &response).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ForwarderStore {
+    pub(crate) forwarder: Option<Arc<Forwarder>>,
+}
+
+impl ForwarderStore {
+    /// The forwarder, if it is configured and could be started
+    pub(crate) fn active(&self) -> Option<&Arc<Forwarder>> {
+        self.forwarder
+            .as_ref()
+            .filter(|x| x.running.load(Ordering::Relaxed))
+    }
+}
+
+pub(crate) fn server() -> AdHoc {
+    AdHoc::on_liftoff("DNS forwarder", |rocket| {
+        Box::pin(async move {
+            let forwarder = match &rocket.state::<ForwarderStore>().unwrap().forwarder {
+                Some(x) => Arc::clone(x),
+                None => return,
+            };
+            let shutdown = rocket.shutdown();
+
+            let (udp, tcp) = match forwarder.bind().await {
+                Ok(x) => x,
+                Err(e) => {
+                    log::error!(
+                        "Failed to start DNS forwarder on {}, DNS is set directly: {}",
+                        forwarder.listen,
+                        e
+                    );
+                    return;
+                }
+            };
+            log::info!("DNS forwarder listening on {}", forwarder.listen);
+            forwarder.running.store(true, Ordering::Relaxed);
+
+            rocket::tokio::spawn(async move {
+                rocket::tokio::select! {
+                    _ = Arc::clone(&forwarder).serve_udp(udp) => {},
+                    _ = Arc::clone(&forwarder).serve_tcp(tcp) => {},
+                    _ = shutdown => {},
+                };
+                forwarder.running.store(false, Ordering::Relaxed);
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+fn test_query(id: u16, name: &str) -> Vec<u8> {
+    let mut msg = id.to_be_bytes().to_vec();
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+    msg
+}
+
+#[cfg(test)]
+fn test_answer(query: &[u8], addr: [u8; 4], ttl: u32) -> Vec<u8> {
+    let mut msg = query.to_vec();
+    msg[2] |= 0x80;
+    msg[3] = 0x80;
+    msg[7] = 1;
+    msg.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+    msg.extend_from_slice(&ttl.to_be_bytes());
+    msg.extend_from_slice(&[0, 4]);
+    msg.extend_from_slice(&addr);
+    msg
+}
+
+/// Answers every query with `addr`, and counts them
+#[cfg(test)]
+async fn test_upstream(addr: [u8; 4]) -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local = socket.local_addr().unwrap();
+    let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = Arc::clone(&count);
+    rocket::tokio::spawn(async move {
+        let mut buf = [0u8; MIN_UDP_LEN];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let answer = test_answer(&buf[..len], addr, 60);
+            socket.send_to(&answer, peer).await.unwrap();
+        }
+    });
+    (local, count)
+}
+
+#[test]
+fn test_dns_message() {
+    let query = test_query(0x1234, "Host.Example.org");
+    let (name, qtype, qclass, end) = parse_question(&query).unwrap();
+    assert_eq!(name, "host.example.org");
+    assert_eq!((qtype, qclass, end), (1, 1, query.len()));
+    assert!(parse_question(&query[..query.len() - 1]).is_none());
+    assert_eq!(udp_limit(&query), MIN_UDP_LEN);
+
+    let answer = test_answer(&query, [10, 0, 0, 1], 300);
+    assert_eq!(ttl_offsets(&answer).unwrap(), vec![query.len() + 6]);
+    assert_eq!(read_u32(&answer, query.len() + 6), 300);
+
+    let truncated = truncate(&answer);
+    assert_eq!(truncated.len(), query.len());
+    assert_eq!(truncated[2] & 0x02, 0x02);
+    assert_eq!(&truncated[6..HEADER_LEN], &[0; 6]);
+
+    assert!(answers(&query, &answer));
+    assert!(!answers(&query, &query));
+    let other = test_answer(&test_query(0x1234, "other.example.org"), [10, 0, 0, 1], 300);
+    assert!(!answers(&query, &other));
+
+    let servfail = error_response(&query, RCODE_SERVFAIL);
+    assert_eq!(&servfail[..2], &[0x12, 0x34]);
+    assert_eq!(servfail[3] & 0x0f, RCODE_SERVFAIL);
+    assert_eq!(servfail.len(), query.len());
+}
+
+#[test]
+fn test_dns_cache() {
+    let now = Instant::now();
+    let mut cache = Cache::new(1);
+    let query = test_query(1, "example.org");
+    let key = ("example.org".to_string(), 1, 1);
+
+    cache.insert(key.clone(), &test_answer(&query, [10, 0, 0, 1], 60), now);
+    let hit = cache.get(&key, now + Duration::from_secs(10)).unwrap();
+    assert_eq!(read_u32(&hit, query.len() + 6), 50);
+    assert!(cache.get(&key, now + Duration::from_secs(60)).is_none());
+
+    // Nothing to keep without a TTL
+    cache.insert(key.clone(), &test_answer(&query, [10, 0, 0, 1], 0), now);
+    assert!(cache.get(&key, now).is_none());
+
+    let other = ("example.com".to_string(), 1, 1);
+    cache.insert(key.clone(), &test_answer(&query, [10, 0, 0, 1], 60), now);
+    cache.insert(other.clone(), &test_answer(&query, [10, 0, 0, 2], 60), now);
+    assert!(cache.get(&key, now).is_none());
+    assert!(cache.get(&other, now).is_some());
+}
+
+#[test]
+fn test_forwarder_config() {
+    let mut cfg = ForwarderConfig {
+        listen: None,
+        cache_size: None,
+        rules: Some(vec![crate::config::ForwarderRuleConfig {
+            domain: "Corp.Example.".to_string(),
+            servers: vec!["10.1.0.53".to_string(), "[fd00::53]:5353".to_string()],
+        }]),
+    };
+    let forwarder = Forwarder::from_config(&cfg).unwrap();
+    assert_eq!(forwarder.listen(), DEFAULT_LISTEN.parse().unwrap());
+    assert_eq!(
+        forwarder.rules,
+        vec![ForwarderRule {
+            domain: "corp.example".to_string(),
+            servers: vec![
+                "10.1.0.53:53".parse().unwrap(),
+                "[fd00::53]:5353".parse().unwrap()
+            ],
+        }]
+    );
+
+    cfg.listen = Some("127.0.0.1:5353".to_string());
+    assert!(Forwarder::from_config(&cfg).is_err());
+    cfg.listen = Some("0.0.0.0:53".to_string());
+    assert!(Forwarder::from_config(&cfg).is_err());
+}
+
+#[rocket::async_test]
+async fn test_forwarder() {
+    let (upstream, upstream_count) = test_upstream([10, 0, 0, 1]).await;
+    let (corp, corp_count) = test_upstream([10, 0, 0, 2]).await;
+    let rules = vec![ForwarderRule {
+        domain: "corp.example".to_string(),
+        servers: vec![corp],
+    }];
+    let forwarder = Arc::new(Forwarder::new("127.0.0.1:0".parse().unwrap(), rules, 16));
+    forwarder.set_upstreams("wg0", &[upstream]);
+
+    let (udp, tcp) = forwarder.bind().await.unwrap();
+    let listen = udp.local_addr().unwrap();
+    rocket::tokio::spawn(Arc::clone(&forwarder).serve_udp(udp));
+    rocket::tokio::spawn(Arc::clone(&forwarder).serve_tcp(tcp));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(listen).await.unwrap();
+    let mut buf = [0u8; MIN_UDP_LEN];
+    for (id, name, addr) in [
+        (1u16, "example.org", [10, 0, 0, 1]),
+        (2, "example.org", [10, 0, 0, 1]),
+        (3, "host.corp.example", [10, 0, 0, 2]),
+    ] {
+        client.send(&test_query(id, name)).await.unwrap();
+        let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..2], &id.to_be_bytes());
+        assert_eq!(&buf[len - 4..len], &addr);
+    }
+    assert_eq!(upstream_count.load(Ordering::SeqCst), 1);
+    assert_eq!(corp_count.load(Ordering::SeqCst), 1);
+    assert_eq!(forwarder.cache_hits.get(), 1);
+
+    let mut stream = TcpStream::connect(listen).await.unwrap();
+    write_tcp_msg(&mut stream, &test_query(4, "example.net"))
+        .await
+        .unwrap();
+    let response = read_tcp_msg(&mut stream).await.unwrap();
+    assert_eq!(&response[response.len() - 4..], &[10, 0, 0, 1]);
+    assert_eq!(upstream_count.load(Ordering::SeqCst), 2);
+    assert_eq!(forwarder.queries.with_label_values(&["udp"]).get(), 3);
+    assert_eq!(forwarder.queries.with_label_values(&["tcp"]).get(), 1);
+
+    forwarder.set_upstreams("wg0", &[]);
+    let response = forwarder
+        .resolve(&test_query(5, "example.org"), "udp")
+        .await
+        .unwrap();
+    assert_eq!(response[3] & 0x0f, RCODE_SERVFAIL);
+    assert_eq!(forwarder.upstream_failures.get(), 1);
+    assert!(forwarder.resolve(&[0u8; 4], "udp").await.is_none());
+}
@@ -19,16 +19,19 @@
 
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 
-use crate::api::common::{ApiResponse, ApiResponseType, PrometheusStore};
+use crate::api::common::{ApiResponse, ApiResponseType};
 use crate::api::v1::types::DnsMonStore;
+use crate::config::DnsLeakProtection;
+use ipnet::IpNet;
 use rocket::serde::json::Json;
 use rocket::State;
 use rocket::{http::Status, serde};
 use wgctrl::platform_specific::common::{
-    InterfaceStatus, PeerTrafficStat, PlatformInterface, PlatformRoute, WgIfCfg,
+    default_route_family, parse_addresses, DnsConfig, InterfaceStatus, PeerTrafficStat,
+    PlatformInterface, PlatformRoute, RoutePolicy, WgIfCfg,
 };
 use wgctrl::platform_specific::PlatformSpecificFactory;
 
@@ -36,9 +39,14 @@ use wgctrl::platform_specific::PlatformSpecificFactory;
 use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
 use curve25519_dalek::scalar::Scalar;
 
+use super::expiry::ExpiryStore;
+use super::firewall::FirewallStore;
+use super::forwarder::ForwarderStore;
+use super::quota::UsageStore;
+use super::split_tunnel;
 use super::types::{
-    IfaceState, InterfaceConfig, InterfaceStore, IpConfigurationMessage, RouteConfigurationMessage,
-    RouteManagerStore,
+    DnsConfigureReq, IfaceState, InterfaceConfig, InterfaceStore, IpConfigurationMessage,
+    RouteConfigurationMessage, RouteManagerStore, SplitTunnelStore,
 };
 use crate::api::tokenauth::ApiKey;
 
@@ -89,10 +97,48 @@ pub(crate) struct InterfaceStatusResp {
     pub(crate) status: String,
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize)]
 #[serde(crate = "rocket::serde")]
-pub(crate) struct DnsConfigureReq {
-    pub dns: Vec<String>,
+pub(crate) struct DnsStatusResp {
+    /// DNS last set through the API
+    pub(crate) config: Option<DnsConfigureReq>,
+    /// Manager enforcing it, `None` if nothing is set
+    pub(crate) manager: Option<String>,
+    /// Resolvers in use right now, if they can be observed on this platform
+    pub(crate) resolvers: Option<Vec<String>>,
+    /// Whether all configured servers are currently in use
+    pub(crate) in_effect: Option<bool>,
+    /// Address of the built-in forwarder the system is pointed at instead of the servers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) forwarder: Option<String>,
+    /// Times our settings were found overwritten and put back, if the manager is watched for that
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) corrections: Option<u64>,
+}
+
+/// Whether the system uses every server in `config`
+fn dns_in_effect(config: &DnsConfigureReq, resolvers: &[IpAddr]) -> bool {
+    match config.to_config() {
+        Ok(x) => x.servers.iter().all(|server| resolvers.contains(server)),
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn test_dns_in_effect() {
+    let config = DnsConfigureReq {
+        dns: vec!["10.0.0.1".to_string(), "fd00::1".to_string()],
+        leak_protection: None,
+        search_domains: vec![],
+        routing_domains: vec![],
+    };
+    let resolvers: Vec<IpAddr> = vec![
+        "fd00::1".parse().unwrap(),
+        "127.0.0.53".parse().unwrap(),
+        "10.0.0.1".parse().unwrap(),
+    ];
+    assert!(dns_in_effect(&config, &resolvers));
+    assert!(!dns_in_effect(&config, &resolvers[..2]));
 }
 
 #[post("/interface", format = "json", data = "<ifcfg>")]
@@ -100,6 +146,7 @@ pub(crate) async fn create_iface(
     _apikey: ApiKey,
     rms: &State<RouteManagerStore>,
     iface_store: &State<InterfaceStore>,
+    sts: &State<SplitTunnelStore>,
     ifcfg: Json<InterfaceConfig>,
 ) -> ApiResponseType<String> {
     let private_key = match ifcfg.private_key.clone() {
@@ -127,6 +174,23 @@ pub(crate) async fn create_iface(
         );
     }
 
+    let policy = RoutePolicy {
+        table: match &ifcfg.table {
+            Some(x) => match x.parse() {
+                Ok(x) => x,
+                Err(e) => {
+                    return (
+                        Status::UnprocessableEntity,
+                        ApiResponse::err(-1, &e.to_string()),
+                    )
+                }
+            },
+            None => rms.defaults.table,
+        },
+        fwmark: ifcfg.fwmark.unwrap_or(rms.defaults.fwmark),
+        rule_priority: ifcfg.rule_priority.unwrap_or(rms.defaults.rule_priority),
+    };
+
     let iface_states = &iface_store.iface_states;
 
     if iface_states.is_empty() {
@@ -149,7 +213,7 @@ pub(crate) async fn create_iface(
             match x.set_config(WgIfCfg {
                 listen_port: ifcfg.listen_port,
                 privkey: private_key,
-                fwmark: 0x7370616b,
+                fwmark: policy.fwmark,
             }) {
                 Ok(_) => Box::new(x),
                 Err(e) => {
@@ -165,6 +229,26 @@ pub(crate) async fn create_iface(
         }
     };
 
+    let mut rm = rms.route_manager.lock().unwrap();
+    if let Err(e) = rm.add_interface(&ifcfg.name, policy) {
+        // Roll back, dropping the interface deletes its link
+        let mut iface = iface;
+        iface.down();
+        drop(iface);
+        if iface_states.is_empty() {
+            if let Err(e) = rm.restore_default_route() {
+                log::warn!("Failed to restore default route: {}", e);
+            }
+        }
+        return (
+            Status::InternalServerError,
+            ApiResponse::err(-1, &e.to_string()),
+        );
+    }
+    let bypass_priority = rm.bypass_priority();
+    drop(rm);
+    split_tunnel::reprioritize(sts, bypass_priority);
+
     let mut iface_cfg: InterfaceConfig = ifcfg.into_inner();
 
     // For security reason, do not hold private_key in return object
@@ -177,6 +261,8 @@ pub(crate) async fn create_iface(
             interface: iface,
             iface_cfg,
             peer_cfgs: HashMap::new(),
+            ips: vec![],
+            dns: None,
         })),
     );
 
@@ -229,13 +315,34 @@ pub(crate) async fn delete_iface(
     _apikey: ApiKey,
     rms: &State<RouteManagerStore>,
     iface_store: &State<InterfaceStore>,
-    prom_store: &State<PrometheusStore>,
+    sts: &State<SplitTunnelStore>,
+    expiry_store: &State<ExpiryStore>,
+    usage_store: &State<UsageStore>,
+    dns_store: &State<DnsMonStore>,
+    fw_store: &State<FirewallStore>,
+    fwd_store: &State<ForwarderStore>,
     id: String,
 ) -> ApiResponseType<String> {
+    // While the interface is still there, as its platform id is needed
+    let dns = match iface_store.iface_states.get(&id) {
+        Some(x) => {
+            let mut iface = x.lock().unwrap();
+            match iface.dns.take() {
+                Some(_) => iface.interface.get_platformid().ok(),
+                None => None,
+            }
+        }
+        None => None,
+    };
+    if let Some(platformid) = dns {
+        if let Err(e) = reset_dns(iface_store, dns_store, fw_store, fwd_store, platformid).await {
+            log::warn!("Failed to reset DNS of {}: {}", id, e);
+        }
+    }
+
     let ifaces = &iface_store.iface_states;
     let mut rm = rms.route_manager.lock().unwrap();
     let rs = &rms.route_store;
-    let reg = prom_store.registry.lock().unwrap();
     match rm.restore_default_route() {
         Ok(_) => {}
         Err(_x) => {
@@ -248,20 +355,22 @@ pub(crate) async fn delete_iface(
 
     // Remove all route owned by the interface
     rs.remove(&id);
+    if let Err(e) = rm.remove_interface(&id) {
+        log::warn!("Failed to clean up routing of {}: {}", id, e);
+    }
+    split_tunnel::reprioritize(sts, rm.bypass_priority());
 
     match ifaces.get(&id) {
         Some(x) => {
             let mut iface = x.lock().unwrap();
             iface.interface.down();
-            for (_, tx_cnt, rx_cnt) in iface.peer_cfgs.values() {
-                reg.unregister(Box::new(tx_cnt.clone())).unwrap();
-                reg.unregister(Box::new(rx_cnt.clone())).unwrap();
-            }
 
             // Wait for iface drop explictly
             drop(iface);
             drop(x);
             ifaces.remove(&id);
+            expiry_store.unschedule_iface(&id);
+            usage_store.forget_iface(&id);
             (Status::Ok, ApiResponse::ok("Ok".to_string()))
         }
         None => (Status::NotFound, ApiResponse::err(-1, "Not found")),
@@ -331,11 +440,24 @@ pub(crate) async fn put_ips(
     id: String,
     ips: Json<IpConfigurationMessage>,
 ) -> ApiResponseType<String> {
+    let ipaddr: Vec<String> = match parse_addresses(&ips.ipaddr) {
+        Ok(x) => x.iter().map(|x| x.to_string()).collect(),
+        Err(e) => {
+            return (
+                Status::UnprocessableEntity,
+                ApiResponse::err(-1, &e.join(", ")),
+            )
+        }
+    };
+
     match iface_store.iface_states.get(&id) {
         Some(x) => {
-            let intf = &mut x.lock().unwrap().interface;
-            match intf.set_ip(&ips.ipaddr) {
-                Ok(_) => (Status::Ok, ApiResponse::ok("Ok".to_string())),
+            let iface_state = &mut *x.lock().unwrap();
+            match iface_state.interface.set_ip(&ipaddr) {
+                Ok(_) => {
+                    iface_state.ips = ipaddr;
+                    (Status::Ok, ApiResponse::ok("Ok".to_string()))
+                }
                 Err(e) => (
                     Status::InternalServerError,
                     ApiResponse::err(-1, &e.to_string()),
@@ -346,6 +468,82 @@ pub(crate) async fn put_ips(
     }
 }
 
+#[get("/interface/<id>/ips")]
+pub(crate) async fn get_ips(
+    _apikey: ApiKey,
+    iface_store: &State<InterfaceStore>,
+    id: String,
+) -> ApiResponseType<IpConfigurationMessage> {
+    match iface_store.iface_states.get(&id) {
+        Some(x) => match x.lock().unwrap().interface.get_ip() {
+            Ok(ipaddr) => (
+                Status::Ok,
+                ApiResponse::ok(IpConfigurationMessage { ipaddr }),
+            ),
+            Err(e) => (
+                Status::InternalServerError,
+                ApiResponse::err(-1, &e.to_string()),
+            ),
+        },
+        None => (Status::NotFound, ApiResponse::err(-1, "Not found")),
+    }
+}
+
+#[delete("/interface/<id>/ips/<cidr>")]
+pub(crate) async fn delete_ip(
+    _apikey: ApiKey,
+    iface_store: &State<InterfaceStore>,
+    id: String,
+    cidr: String,
+) -> ApiResponseType<String> {
+    let cidr = match cidr.parse::<IpNet>() {
+        Ok(x) => x,
+        Err(_) => {
+            return (
+                Status::UnprocessableEntity,
+                ApiResponse::err(-1, &format!("Invalid address: {}", cidr)),
+            )
+        }
+    };
+
+    let iface_state_lock = match iface_store.iface_states.get(&id) {
+        Some(x) => x,
+        None => return (Status::NotFound, ApiResponse::err(-1, "IFace not found")),
+    };
+    let iface_state = &mut *iface_state_lock.lock().unwrap();
+
+    let current = match iface_state.interface.get_ip() {
+        Ok(x) => x,
+        Err(e) => {
+            return (
+                Status::InternalServerError,
+                ApiResponse::err(-1, &e.to_string()),
+            )
+        }
+    };
+    let remaining: Vec<String> = current
+        .iter()
+        .filter(|x| x.parse::<IpNet>().ok() != Some(cidr))
+        .cloned()
+        .collect();
+    if remaining.len() == current.len() {
+        return (Status::NotFound, ApiResponse::err(-1, "Address not found"));
+    }
+
+    match iface_state.interface.set_ip(&remaining) {
+        Ok(_) => {
+            iface_state
+                .ips
+                .retain(|x| x.parse::<IpNet>().ok() != Some(cidr));
+            (Status::Ok, ApiResponse::ok("Ok".to_string()))
+        }
+        Err(e) => (
+            Status::InternalServerError,
+            ApiResponse::err(-1, &e.to_string()),
+        ),
+    }
+}
+
 #[post("/interface/<id>/routes", format = "json", data = "<route>")]
 pub(crate) async fn post_routes(
     _apikey: ApiKey,
@@ -356,6 +554,16 @@ pub(crate) async fn post_routes(
 ) -> ApiResponseType<String> {
     match iface_store.iface_states.get(&id) {
         Some(_) => {
+            let opts = match route.options() {
+                Ok(x) => x,
+                Err(e) => {
+                    return (
+                        Status::UnprocessableEntity,
+                        ApiResponse::err(-1, &e.join(", ")),
+                    )
+                }
+            };
+
             let mut rm = rms.route_manager.lock().unwrap();
             let rs = &rms.route_store;
             let mut routemap = match rs.get_mut(&id) {
@@ -367,21 +575,16 @@ pub(crate) async fn post_routes(
             };
 
             // Search map before adding CIDR
-            match routemap.get(&route.cidr) {
-                Some(_) => {
-                    return (
-                        Status::Conflict,
-                        ApiResponse::err(-1, "Route conflict. cannot add it"),
-                    )
-                }
-                None => {
-                    routemap.insert(route.cidr.clone(), true);
-                }
+            if routemap.contains_key(&route.cidr) {
+                return (
+                    Status::Conflict,
+                    ApiResponse::err(-1, "Route conflict. cannot add it"),
+                );
             }
 
             // Try to put route CIDR to route_store
-            if route.cidr == "0.0.0.0/0" {
-                match rm.remove_default_route() {
+            if let Some(family) = default_route_family(&route.cidr) {
+                match rm.remove_default_route(family) {
                     Ok(_) => (),
                     Err(e) => {
                         return (
@@ -392,8 +595,12 @@ pub(crate) async fn post_routes(
                 }
             }
 
-            match rm.add_route(&id, &route.cidr) {
-                Ok(_) => (Status::Ok, ApiResponse::ok("Ok".to_string())),
+            // Only routes which made it to the system go to route_store
+            match rm.add_route(&id, &route.cidr, &opts) {
+                Ok(_) => {
+                    routemap.insert(route.cidr.clone(), route.0.clone());
+                    (Status::Ok, ApiResponse::ok("Ok".to_string()))
+                }
                 Err(e) => (
                     Status::InternalServerError,
                     ApiResponse::err(-1, &e.to_string()),
@@ -483,48 +690,235 @@ pub(crate) async fn get_trafficstat(
     }
 }
 
+/// Least restrictive of the leak protection modes asked for, as a stricter one would break DNS
+/// of the interfaces which asked for less, e.g. split DNS
+fn least_restrictive(modes: impl Iterator<Item = DnsLeakProtection>) -> Option<DnsLeakProtection> {
+    modes.min_by_key(|x| match x {
+        DnsLeakProtection::Off => 0,
+        DnsLeakProtection::Redirect => 1,
+        DnsLeakProtection::Block => 2,
+    })
+}
+
+#[test]
+fn test_least_restrictive() {
+    use DnsLeakProtection::*;
+    assert_eq!(least_restrictive(vec![].into_iter()), None);
+    assert_eq!(least_restrictive(vec![Block].into_iter()), Some(Block));
+    assert_eq!(
+        least_restrictive(vec![Block, Redirect].into_iter()),
+        Some(Redirect)
+    );
+    assert_eq!(
+        least_restrictive(vec![Block, Off, Redirect].into_iter()),
+        Some(Off)
+    );
+}
+
+/// Servers set on any interface, which the DNS leak rules have to let through, and the leak
+/// protection mode for all of them. This is the only place the mode is decided.
+fn dns_servers(iface_store: &InterfaceStore) -> (Option<DnsLeakProtection>, Vec<IpAddr>) {
+    let mut modes = vec![];
+    let mut servers = vec![];
+    for x in iface_store.iface_states.iter() {
+        let iface_state = x.value().lock().unwrap();
+        let dns = match &iface_state.dns {
+            Some(x) => x,
+            None => continue,
+        };
+        modes.extend(dns.leak_protection());
+        for server in dns.to_config().map(|x| x.servers).unwrap_or_default() {
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+    }
+    (least_restrictive(modes.into_iter()), servers)
+}
+
+/// Hands the servers of `config` to the forwarder, if it runs, and points `config` at it instead
+pub(crate) fn forward_dns(fwd_store: &ForwarderStore, platformid: &str, config: &mut DnsConfig) {
+    // Split DNS is left to the system, which knows which names go where
+    match fwd_store.active() {
+        Some(x) if config.routing_domains.is_empty() => {
+            let upstreams: Vec<SocketAddr> = config
+                .servers
+                .iter()
+                .map(|x| SocketAddr::new(*x, 53))
+                .collect();
+            x.set_upstreams(platformid, &upstreams);
+            config.servers = vec![x.listen().ip()];
+        }
+        Some(x) => x.set_upstreams(platformid, &[]),
+        None => {}
+    }
+}
+
+/// Takes the DNS of the interface out of the system and of the leak rules
+async fn reset_dns(
+    iface_store: &InterfaceStore,
+    dns_store: &DnsMonStore,
+    fw_store: &FirewallStore,
+    fwd_store: &ForwarderStore,
+    platformid: String,
+) -> Result<(), String> {
+    if let Some(x) = &fwd_store.forwarder {
+        x.set_upstreams(&platformid, &[]);
+    }
+
+    // Restored resolvers would be caught by the leak rules otherwise
+    match dns_servers(iface_store) {
+        (_, x) if x.is_empty() => fw_store.clear_dns()?,
+        (leak_protection, x) => fw_store.set_dns(leak_protection, &x)?,
+    }
+
+    let dnsmon_lock = dns_store.dnsmon.clone();
+    rocket::tokio::task::spawn_blocking(move || {
+        let mut dnsmon = dnsmon_lock.lock().unwrap();
+        dnsmon.reset_interface(&platformid)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+#[get("/interface/<id>/dns")]
+pub(crate) async fn get_dns(
+    _apikey: ApiKey,
+    iface_store: &State<InterfaceStore>,
+    dns_store: &State<DnsMonStore>,
+    fwd_store: &State<ForwarderStore>,
+    id: String,
+) -> ApiResponseType<DnsStatusResp> {
+    let (platformid, config) = match iface_store.iface_states.get(&id) {
+        Some(x) => {
+            let iface_state = x.lock().unwrap();
+            match iface_state.interface.get_platformid() {
+                Ok(id) => (id, iface_state.dns.clone()),
+                Err(e) => {
+                    return (
+                        Status::InternalServerError,
+                        ApiResponse::err(-1, &e.to_string()),
+                    );
+                }
+            }
+        }
+        None => {
+            return (Status::NotFound, ApiResponse::err(-1, "Not found"));
+        }
+    };
+
+    let forwarder = fwd_store
+        .forwarder
+        .as_ref()
+        .filter(|x| x.serves(&platformid))
+        .map(|x| x.listen());
+
+    let dnsmon_lock = dns_store.dnsmon.clone();
+    let status = match rocket::tokio::task::spawn_blocking(move || {
+        let dnsmon = dnsmon_lock.lock().unwrap();
+        dnsmon.status(&platformid)
+    })
+    .await
+    {
+        Ok(Ok(x)) => x,
+        Ok(Err(e)) => {
+            return (
+                Status::InternalServerError,
+                ApiResponse::err(-1, &e.to_string()),
+            )
+        }
+        Err(e) => {
+            return (
+                Status::InternalServerError,
+                ApiResponse::err(-1, &e.to_string()),
+            )
+        }
+    };
+
+    let in_effect = match (&config, &status.resolvers, forwarder) {
+        (Some(_), Some(resolvers), Some(listen)) => Some(resolvers.contains(&listen.ip())),
+        (Some(config), Some(resolvers), None) => Some(dns_in_effect(config, resolvers)),
+        _ => None,
+    };
+    (
+        Status::Ok,
+        ApiResponse::ok(DnsStatusResp {
+            config,
+            manager: status.manager,
+            resolvers: status
+                .resolvers
+                .map(|x| x.iter().map(|x| x.to_string()).collect()),
+            in_effect,
+            corrections: status.corrections,
+            forwarder: forwarder.map(|x| x.to_string()),
+        }),
+    )
+}
+
 #[put("/interface/<id>/dns", format = "json", data = "<dns>")]
 pub(crate) async fn put_dns(
     _apikey: ApiKey,
     iface_store: &State<InterfaceStore>,
     dns_store: &State<DnsMonStore>,
+    fw_store: &State<FirewallStore>,
+    fwd_store: &State<ForwarderStore>,
     id: String,
     dns: Json<DnsConfigureReq>,
 ) -> ApiResponseType<String> {
-    let platformid = match iface_store.iface_states.get(&id) {
-        Some(x) => match x.lock().unwrap().interface.get_platformid() {
-            Ok(id) => id,
-            Err(e) => {
-                return (Status::InternalServerError, ApiResponse::err(-1, ":("));
-            }
-        },
+    let iface_state_lock = match iface_store.iface_states.get(&id) {
+        Some(x) => Arc::clone(x.value()),
         None => {
             return (Status::NotFound, ApiResponse::err(-1, "Not found"));
         }
     };
-
-    let dns: Vec<IpAddr> = match dns.dns.iter().map(|e| e.parse()).collect() {
-        Ok(x) => x,
+    let platformid = match iface_state_lock.lock().unwrap().interface.get_platformid() {
+        Ok(id) => id,
         Err(e) => {
             return (
-                Status::UnprocessableEntity,
+                Status::InternalServerError,
                 ApiResponse::err(-1, &e.to_string()),
             );
         }
     };
 
+    let mut config = match dns.to_config() {
+        Ok(x) => x,
+        Err(e) => {
+            return (Status::UnprocessableEntity, ApiResponse::err(-1, &e));
+        }
+    };
+
+    forward_dns(fwd_store, &platformid, &mut config);
+
     let dnsmon_lock = dns_store.dnsmon.clone();
     match rocket::tokio::task::spawn_blocking(move || {
         let mut dnsmon = dnsmon_lock.lock().unwrap();
-        dnsmon.set(&platformid, &dns)
+        dnsmon.set(&platformid, &config)
     })
     .await
     {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            return (
+                Status::InternalServerError,
+                ApiResponse::err(-1, &e.to_string()),
+            )
+        }
+        Err(e) => {
+            return (
+                Status::InternalServerError,
+                ApiResponse::err(-1, &e.to_string()),
+            )
+        }
+    }
+
+    iface_state_lock.lock().unwrap().dns = Some(dns.into_inner());
+
+    let (leak_protection, servers) = dns_servers(iface_store);
+    match fw_store.set_dns(leak_protection, &servers) {
         Ok(_) => (Status::Ok, ApiResponse::ok("ok".to_string())),
-        Err(e) => (
-            Status::InternalServerError,
-            ApiResponse::err(-1, &e.to_string()),
-        ),
+        Err(e) => (Status::InternalServerError, ApiResponse::err(-1, &e)),
     }
 }
 
@@ -533,31 +927,31 @@ pub(crate) async fn delete_dns(
     _apikey: ApiKey,
     iface_store: &State<InterfaceStore>,
     dns_store: &State<DnsMonStore>,
+    fw_store: &State<FirewallStore>,
+    fwd_store: &State<ForwarderStore>,
     id: String,
 ) -> ApiResponseType<String> {
     let platformid = match iface_store.iface_states.get(&id) {
-        Some(x) => match x.lock().unwrap().interface.get_platformid() {
-            Ok(id) => id,
-            Err(e) => {
-                return (Status::InternalServerError, ApiResponse::err(-1, ":("));
+        Some(x) => {
+            let mut iface_state = x.lock().unwrap();
+            iface_state.dns = None;
+            match iface_state.interface.get_platformid() {
+                Ok(id) => id,
+                Err(e) => {
+                    return (
+                        Status::InternalServerError,
+                        ApiResponse::err(-1, &e.to_string()),
+                    );
+                }
             }
-        },
+        }
         None => {
             return (Status::NotFound, ApiResponse::err(-1, "Not found"));
         }
     };
 
-    let dnsmon_lock = dns_store.dnsmon.clone();
-    match rocket::tokio::task::spawn_blocking(move || {
-        let mut dnsmon = dnsmon_lock.lock().unwrap();
-        dnsmon.reset()
-    })
-    .await
-    {
+    match reset_dns(iface_store, dns_store, fw_store, fwd_store, platformid).await {
         Ok(_) => (Status::Ok, ApiResponse::ok("ok".to_string())),
-        Err(e) => (
-            Status::InternalServerError,
-            ApiResponse::err(-1, &e.to_string()),
-        ),
+        Err(e) => (Status::InternalServerError, ApiResponse::err(-1, &e)),
     }
 }
@@ -0,0 +1,392 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::{DashMap, DashSet};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounterVec, IntGaugeVec, Opts};
+
+use wgctrl::platform_specific::common::{DnsMonitor, InterfaceStatus};
+#[cfg(test)]
+use wgctrl::platform_specific::common::{PeerTrafficStat, PlatformInterface, WgIfCfg, WgPeerCfg};
+#[cfg(test)]
+use wgctrl::VpnctrlError;
+
+use super::types::IfaceState;
+
+/// Amount to advance a counter by. Device counters start over when the peer is added again.
+fn counter_delta(last: u64, current: u64) -> u64 {
+    match current.checked_sub(last) {
+        Some(x) => x,
+        None => current,
+    }
+}
+
+/// Collects interface and peer statistics straight from the platform backend on every scrape.
+pub(crate) struct InterfaceCollector {
+    iface_states: Arc<DashMap<String, Arc<Mutex<IfaceState>>>>,
+    /// TX and RX bytes of each peer seen on the last scrape, which the counters are advanced from
+    last: Mutex<HashMap<(String, String), (u64, u64)>>,
+    peer_tx: IntCounterVec,
+    peer_rx: IntCounterVec,
+    peer_handshake_age: IntGaugeVec,
+    interface_peers: IntGaugeVec,
+    interface_up: IntGaugeVec,
+}
+
+impl InterfaceCollector {
+    pub(crate) fn new(iface_states: Arc<DashMap<String, Arc<Mutex<IfaceState>>>>) -> Self {
+        InterfaceCollector {
+            iface_states,
+            last: Mutex::new(HashMap::new()),
+            peer_tx: IntCounterVec::new(
+                Opts::new("peer_tx", "Peer TX bytes"),
+                &["interface", "pubk"],
+            )
+            .unwrap(),
+            peer_rx: IntCounterVec::new(
+                Opts::new("peer_rx", "Peer RX bytes"),
+                &["interface", "pubk"],
+            )
+            .unwrap(),
+            peer_handshake_age: IntGaugeVec::new(
+                Opts::new(
+                    "peer_last_handshake_age_seconds",
+                    "Seconds since the last handshake with the peer",
+                ),
+                &["interface", "pubk"],
+            )
+            .unwrap(),
+            interface_peers: IntGaugeVec::new(
+                Opts::new(
+                    "interface_peers",
+                    "Number of peers configured on the interface",
+                ),
+                &["interface"],
+            )
+            .unwrap(),
+            interface_up: IntGaugeVec::new(
+                Opts::new(
+                    "interface_up",
+                    "Whether the interface is up (1) or down (0)",
+                ),
+                &["interface"],
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl Collector for InterfaceCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = Vec::new();
+        descs.extend(self.peer_tx.desc());
+        descs.extend(self.peer_rx.desc());
+        descs.extend(self.peer_handshake_age.desc());
+        descs.extend(self.interface_peers.desc());
+        descs.extend(self.interface_up.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Serialize scrapes, as every scrape rebuilds the gauges
+        let mut last = self.last.lock().unwrap();
+        let mut seen = HashSet::new();
+        let mut failed = HashSet::new();
+
+        self.peer_handshake_age.reset();
+        self.interface_peers.reset();
+        self.interface_up.reset();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+
+        for iface in self.iface_states.iter() {
+            let if_id = iface.key().as_str();
+            let iface_state = iface.lock().unwrap();
+
+            self.interface_peers
+                .with_label_values(&[if_id])
+                .set(iface_state.peer_cfgs.len() as i64);
+            self.interface_up.with_label_values(&[if_id]).set(
+                match iface_state.interface.get_status() {
+                    InterfaceStatus::Running => 1,
+                    InterfaceStatus::Stopped => 0,
+                },
+            );
+
+            let trafficstat = match iface_state.interface.get_trafficstats() {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("Failed to get traffic stats of {}: {}", if_id, e);
+                    failed.insert(if_id.to_string());
+                    continue;
+                }
+            };
+
+            for stat in trafficstat.iter() {
+                if !iface_state.peer_cfgs.contains_key(&stat.pubkey) {
+                    continue;
+                }
+
+                let labels = [if_id, stat.pubkey.as_str()];
+                let key = (if_id.to_string(), stat.pubkey.clone());
+                let (tx, rx) = last.get(&key).copied().unwrap_or((0, 0));
+                self.peer_tx
+                    .with_label_values(&labels)
+                    .inc_by(counter_delta(tx, stat.tx_bytes));
+                self.peer_rx
+                    .with_label_values(&labels)
+                    .inc_by(counter_delta(rx, stat.rx_bytes));
+                last.insert(key.clone(), (stat.tx_bytes, stat.rx_bytes));
+                seen.insert(key);
+                if let Some(x) = stat.last_handshake {
+                    self.peer_handshake_age
+                        .with_label_values(&labels)
+                        .set(now.saturating_sub(x) as i64);
+                }
+            }
+        }
+
+        // Peers which are gone stop being reported. Those of interfaces which failed to report are
+        // kept, so that their counters are not counted twice once they report again.
+        last.retain(|key, _| {
+            if seen.contains(key) || failed.contains(&key.0) {
+                return true;
+            }
+            let labels = [key.0.as_str(), key.1.as_str()];
+            self.peer_tx.remove_label_values(&labels).ok();
+            self.peer_rx.remove_label_values(&labels).ok();
+            false
+        });
+
+        let mut mfs = Vec::new();
+        mfs.extend(self.peer_tx.collect());
+        mfs.extend(self.peer_rx.collect());
+        mfs.extend(self.peer_handshake_age.collect());
+        mfs.extend(self.interface_peers.collect());
+        mfs.extend(self.interface_up.collect());
+        mfs
+    }
+}
+
+#[cfg(test)]
+struct FakeInterface {
+    stats: Arc<Mutex<Vec<PeerTrafficStat>>>,
+}
+
+#[cfg(test)]
+impl PlatformInterface for FakeInterface {
+    fn new(_name: &str) -> Result<Self, VpnctrlError> {
+        Ok(FakeInterface {
+            stats: Arc::new(Mutex::new(vec![])),
+        })
+    }
+    fn set_config(&mut self, _cfg: WgIfCfg) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+    fn add_peer(&mut self, _peer: WgPeerCfg) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+    fn get_peers(&self) -> Result<Vec<WgPeerCfg>, VpnctrlError> {
+        Ok(vec![])
+    }
+    fn get_peer(&self, pubkey: &str) -> Result<WgPeerCfg, VpnctrlError> {
+        Err(VpnctrlError::EntryNotFound {
+            msg: pubkey.to_string(),
+        })
+    }
+    fn remove_peer(&mut self, _pubkey: &str) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+    fn set_peer_endpoint(&mut self, _pubkey: &str, _endpoint: &str) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+    fn get_status(&self) -> InterfaceStatus {
+        InterfaceStatus::Running
+    }
+    fn get_trafficstats(&self) -> Result<Vec<PeerTrafficStat>, VpnctrlError> {
+        Ok(self.stats.lock().unwrap().clone())
+    }
+    fn get_platformid(&self) -> Result<String, VpnctrlError> {
+        Ok("wg0".to_string())
+    }
+    fn up(&mut self) -> bool {
+        true
+    }
+    fn down(&mut self) -> bool {
+        true
+    }
+    fn set_ip(&mut self, _ips: &[String]) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+    fn get_ip(&self) -> Result<Vec<String>, VpnctrlError> {
+        Ok(vec![])
+    }
+}
+
+#[test]
+fn test_interface_collector() {
+    use rocket::serde::json;
+
+    let stat = |rx, tx| PeerTrafficStat::new("pubk".to_string(), rx, tx, None, None, None, None);
+    let stats = Arc::new(Mutex::new(vec![stat(100, 200)]));
+    let iface_states = Arc::new(DashMap::new());
+    iface_states.insert(
+        "wg0".to_string(),
+        Arc::new(Mutex::new(IfaceState {
+            interface: Box::new(FakeInterface {
+                stats: Arc::clone(&stats),
+            }),
+            iface_cfg: json::from_str(r#"{"name": "wg0"}"#).unwrap(),
+            peer_cfgs: vec![(
+                "pubk".to_string(),
+                json::from_str(r#"{"pubkey": "pubk", "allowed_ips": []}"#).unwrap(),
+            )]
+            .into_iter()
+            .collect(),
+            ips: vec![],
+            dns: None,
+        })),
+    );
+    let collector = InterfaceCollector::new(iface_states);
+    let counters = || -> Vec<(String, f64)> {
+        collector
+            .collect()
+            .iter()
+            .filter(|x| x.get_name() == "peer_rx" || x.get_name() == "peer_tx")
+            .flat_map(|x| {
+                x.get_metric()
+                    .iter()
+                    .map(move |m| (x.get_name().to_string(), m.get_counter().get_value()))
+            })
+            .collect()
+    };
+
+    assert_eq!(
+        counters(),
+        vec![
+            ("peer_tx".to_string(), 200.0),
+            ("peer_rx".to_string(), 100.0)
+        ]
+    );
+    // Scrapes advance the counters by what was transferred in between
+    *stats.lock().unwrap() = vec![stat(150, 250)];
+    assert_eq!(
+        counters(),
+        vec![
+            ("peer_tx".to_string(), 250.0),
+            ("peer_rx".to_string(), 150.0)
+        ]
+    );
+    *stats.lock().unwrap() = vec![stat(10, 20)];
+    assert_eq!(
+        counters(),
+        vec![
+            ("peer_tx".to_string(), 270.0),
+            ("peer_rx".to_string(), 160.0)
+        ]
+    );
+
+    stats.lock().unwrap().clear();
+    assert!(counters().is_empty());
+}
+
+/// Reports allocation status of the autoalloc address pools.
+pub(crate) struct AddressPoolCollector {
+    v4: Arc<DashSet<u32>>,
+    v6: Arc<DashSet<u64>>,
+    allocated: IntGaugeVec,
+}
+
+impl AddressPoolCollector {
+    pub(crate) fn new(v4: Arc<DashSet<u32>>, v6: Arc<DashSet<u64>>) -> Self {
+        AddressPoolCollector {
+            v4,
+            v6,
+            allocated: IntGaugeVec::new(
+                Opts::new(
+                    "address_pool_allocated",
+                    "Number of addresses allocated from the autoalloc pool",
+                ),
+                &["pool"],
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl Collector for AddressPoolCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.allocated.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.allocated
+            .with_label_values(&["v4"])
+            .set(self.v4.len() as i64);
+        self.allocated
+            .with_label_values(&["v6"])
+            .set(self.v6.len() as i64);
+        self.allocated.collect()
+    }
+}
+
+/// Reports which DNS manager is currently enforcing our DNS settings.
+pub(crate) struct DnsManagerCollector {
+    dnsmon: Arc<Mutex<DnsMonitor>>,
+    manager: IntGaugeVec,
+}
+
+impl DnsManagerCollector {
+    pub(crate) fn new(dnsmon: Arc<Mutex<DnsMonitor>>) -> Self {
+        DnsManagerCollector {
+            dnsmon,
+            manager: IntGaugeVec::new(
+                Opts::new("dns_manager", "DNS manager currently in use"),
+                &["manager"],
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl Collector for DnsManagerCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.manager.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Do not block the scrape while DNS is being reconfigured. Report last known value instead.
+        if let Ok(dnsmon) = self.dnsmon.try_lock() {
+            self.manager.reset();
+            if let Some(name) = dnsmon.manager_name() {
+                self.manager.with_label_values(&[name.as_str()]).set(1);
+            }
+        }
+
+        self.manager.collect()
+    }
+}
@@ -17,10 +17,9 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RwLock};
 
-use ::prometheus::{Encoder, TextEncoder};
+use ::prometheus::{Encoder, Registry, TextEncoder};
 use dashmap::{DashMap, DashSet};
 use rocket::fairing::AdHoc;
 use rocket::http::Status;
@@ -29,18 +28,44 @@ use rocket::{serde, Shutdown, State};
 use rocket_client_addr::ClientAddr;
 
 use crate::api::tokenauth::ApiKey;
-use wgctrl::platform_specific::common::PlatformRoute;
-use wgctrl::platform_specific::PlatformSpecificFactory;
+use crate::config::Config;
+use crate::util::state;
+use wgctrl::platform_specific::common::{
+    set_instance, DnsBackend, DnsMonitor, PlatformRoute, PlatformSplitTunnel, RoutePolicy,
+};
+use wgctrl::platform_specific::{journal, PlatformSpecificFactory};
 
-use self::types::{DnsMonStore, IpStore, RouteManagerStore};
+use self::events::EventStore;
+use self::expiry::{ExpiryStore, DEFAULT_RESTORE_GRACE};
+use self::firewall::FirewallStore;
+use self::forwarder::{Forwarder, ForwarderStore};
+use self::metrics::{AddressPoolCollector, DnsManagerCollector, InterfaceCollector};
+use self::periodic::NetworkMonitorStore;
+use self::quota::UsageStore;
+use self::route::{DriftStore, DEFAULT_RECONCILE_INTERVAL};
+use self::stats::{DEFAULT_RETENTION, DEFAULT_SAMPLE_INTERVAL};
+use self::types::{
+    DnsMonStore, IfaceState, IpStore, RouteManagerStore, SplitTunnelStore, StatsStore,
+};
 
 use super::common::{ApiResponse, ApiResponseType, PrometheusStore};
 
 use types::{DaemonControlMessage, InterfaceStore};
 
+mod endpoint;
+mod events;
+mod expiry;
+mod firewall;
+mod forwarder;
 mod interface;
+mod metrics;
 mod peer;
+mod periodic;
+mod quota;
+mod roaming;
 mod route;
+mod split_tunnel;
+mod stats;
 mod types;
 
 #[post("/shutdown", format = "json", data = "<magic>")]
@@ -48,20 +73,23 @@ async fn shutdown_daemon(
     _apikey: ApiKey,
     shutdown: Shutdown,
     iface_store: &State<InterfaceStore>,
+    rms: &State<RouteManagerStore>,
+    sts: &State<SplitTunnelStore>,
+    fw_store: &State<FirewallStore>,
     magic: Json<DaemonControlMessage>,
 ) -> ApiResponseType<String> {
     match magic.magic {
         0xfee1dead => {
             // Shutdown
-            let ifaces = &iface_store.iface_states;
-            let keys: Vec<String> = { ifaces.iter().map(|x| x.key().clone()).collect() };
+            remove_ifaces(&iface_store.iface_states);
 
-            for k in keys {
-                if let Some(x) = ifaces.get(&k) {
-                    x.lock().unwrap().interface.down();
-                    ifaces.remove(&k);
-                }
+            if let Err(e) = sts.split_tunnel.lock().unwrap().reset() {
+                log::error!("Failed to reset split tunnel: {}", e);
+            }
+            if let Err(e) = rms.route_manager.lock().unwrap().cleanup() {
+                log::error!("Failed to clean up routes: {}", e);
             }
+            fw_store.reset();
 
             shutdown.notify();
             (Status::Ok, ApiResponse::ok("All is well".to_string()))
@@ -107,47 +135,7 @@ async fn myip(client_addr: &ClientAddr) -> (Status, Json<MyipMessage>) {
 }
 
 #[get("/prometheus")]
-async fn prometheus(
-    _apikey: ApiKey,
-    iface_store: &State<InterfaceStore>,
-    prom_store: &State<PrometheusStore>,
-) -> (Status, String) {
-    let ifaces = &iface_store.iface_states;
-
-    // Update
-
-    for iface in ifaces.iter() {
-        let ifacestat = iface.lock().unwrap();
-        let trafficstat = match ifacestat.interface.get_trafficstats() {
-            Ok(x) => x,
-            Err(e) => return (Status::InternalServerError, e.to_string()),
-        };
-
-        // Move to HashMap
-        let mut hm: HashMap<String, (u64, u64)> = HashMap::new();
-        for stat in trafficstat.iter() {
-            hm.insert(stat.pubkey.clone(), (stat.tx_bytes, stat.rx_bytes));
-        }
-
-        for (peer, tx_cnt, rx_cnt) in ifacestat.peer_cfgs.values() {
-            if let Some((tx_bytes, rx_bytes)) = hm.get(&peer.pubkey) {
-                if (*tx_bytes as f64 - tx_cnt.get()) > 0.0 {
-                    tx_cnt.inc_by(*tx_bytes as f64 - tx_cnt.get());
-                } else {
-                    tx_cnt.reset();
-                    tx_cnt.inc_by(*tx_bytes as f64);
-                }
-
-                if (*rx_bytes as f64 - rx_cnt.get()) > 0.0 {
-                    rx_cnt.inc_by(*rx_bytes as f64 - rx_cnt.get());
-                } else {
-                    rx_cnt.reset();
-                    rx_cnt.inc_by(*rx_bytes as f64);
-                }
-            }
-        }
-    }
-
+async fn prometheus(_apikey: ApiKey, prom_store: &State<PrometheusStore>) -> (Status, String) {
     let reg = prom_store.registry.lock().unwrap();
     let mut buffer = Vec::<u8>::new();
     let encoder = TextEncoder::new();
@@ -159,22 +147,241 @@ async fn prometheus(
     (Status::Ok, String::from_utf8(buffer).unwrap())
 }
 
+const JOURNAL_STATE: &str = "journal.json";
+
 // TODO: FIXME: Hacky way to integrate talpid anyway
 lazy_static! {
     static ref TALPID_TOKIO_RT: rocket::tokio::runtime::Runtime =
         rocket::tokio::runtime::Runtime::new().unwrap();
 }
 
-pub(crate) fn stage() -> AdHoc {
-    AdHoc::on_ignite("API v1", |rocket| async {
-        let mut route_manager = Box::new(PlatformSpecificFactory::get_route(0x7370616b).unwrap());
-        match route_manager.init() {
-            Ok(_) => {}
-            Err(_) => {
-                panic!("Failed to initialize RouteManager!")
+/// Takes every interface down and drops it, which deletes its link
+fn remove_ifaces(ifaces: &DashMap<String, Arc<Mutex<IfaceState>>>) {
+    let keys: Vec<String> = { ifaces.iter().map(|x| x.key().clone()).collect() };
+
+    for k in keys {
+        if let Some(x) = ifaces.get(&k) {
+            x.lock().unwrap().interface.down();
+            drop(x);
+            ifaces.remove(&k);
+        }
+    }
+}
+
+/// Cleans up interfaces, routes, rules, split tunnel, firewall and DNS when the daemon goes down
+/// without `/shutdown`, and closes the journal as nothing is left to undo
+fn system_cleanup() -> AdHoc {
+    AdHoc::on_liftoff("System cleanup", |rocket| {
+        Box::pin(async move {
+            let route_manager =
+                Arc::clone(&rocket.state::<RouteManagerStore>().unwrap().route_manager);
+            let split_tunnel =
+                Arc::clone(&rocket.state::<SplitTunnelStore>().unwrap().split_tunnel);
+            let fw_store = rocket.state::<FirewallStore>().unwrap().clone();
+            let dnsmon = Arc::clone(&rocket.state::<DnsMonStore>().unwrap().dnsmon);
+            let iface_states = Arc::clone(&rocket.state::<InterfaceStore>().unwrap().iface_states);
+            let shutdown = rocket.shutdown();
+
+            rocket::tokio::spawn(async move {
+                shutdown.await;
+                // The DNS monitor blocks on its own runtime
+                rocket::tokio::task::spawn_blocking(move || {
+                    if let Err(e) = dnsmon.lock().unwrap().reset() {
+                        log::error!("Failed to reset DNS: {}", e);
+                    }
+                })
+                .await
+                .ok();
+                // Interfaces left to themselves would outlive us, along with their journal entries
+                remove_ifaces(&iface_states);
+                if let Err(e) = split_tunnel.lock().unwrap().reset() {
+                    log::error!("Failed to reset split tunnel: {}", e);
+                }
+                if let Err(e) = route_manager.lock().unwrap().cleanup() {
+                    log::error!("Failed to clean up routes: {}", e);
+                }
+                fw_store.reset();
+                journal::close();
+            });
+        })
+    })
+}
+
+/// Tells at startup why DNS backends are not usable, rather than at the first `put_dns`
+fn dns_self_test(backends: &[DnsBackend]) {
+    let probes = DnsMonitor::probe(backends);
+    for (backend, res) in &probes {
+        match res {
+            Ok(_) => log::info!("DNS backend {} is usable", backend),
+            Err(e) => log::warn!("DNS backend {} rejected: {}", backend, e),
+        }
+    }
+
+    match probes.iter().find(|(_, res)| res.is_ok()) {
+        Some((backend, _)) => log::info!("Managing DNS via {}", backend),
+        None if !probes.is_empty() => log::error!("No usable DNS backend, setting DNS will fail"),
+        None => {}
+    }
+}
+
+/// Undoes what a previous run left on the system when it did not exit cleanly
+fn recover() {
+    let leftovers = match journal::open(&state::state_dir().join(JOURNAL_STATE)) {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!(
+                "Failed to open journal, a crash will leave changes behind: {}",
+                e
+            );
+            return;
+        }
+    };
+    if !leftovers.is_empty() {
+        log::warn!(
+            "Undoing {} system changes left by an unclean exit",
+            leftovers.len()
+        );
+        journal::recover(&leftovers);
+    }
+}
+
+pub(crate) fn stage(registry: Arc<Mutex<Registry>>, cfg: Config) -> AdHoc {
+    AdHoc::try_on_ignite("API v1", |rocket| async move {
+        // Journal and backups are found through the instance
+        let instance_cfg = cfg.instance.as_ref();
+        state::init(
+            instance_cfg.and_then(|x| x.state_dir.as_deref()),
+            instance_cfg.and_then(|x| x.name.as_deref()),
+        );
+        if let Some(x) = instance_cfg {
+            match x.instance() {
+                Ok(x) => set_instance(x),
+                Err(e) => {
+                    log::error!("Invalid instance in config: {}", e);
+                    return Err(rocket);
+                }
+            }
+        }
+
+        // Before anything is set up again, so that leftovers don't get in the way
+        recover();
+
+        let routing_cfg = cfg.routing.as_ref();
+        let mut defaults = RoutePolicy::default();
+        if let Some(x) = routing_cfg.and_then(|x| x.table.as_ref()) {
+            defaults.table = match x.parse() {
+                Ok(x) => x,
+                Err(e) => {
+                    log::error!("Invalid routing table in config: {}", e);
+                    return Err(rocket);
+                }
+            };
+        }
+        if let Some(x) = routing_cfg.and_then(|x| x.fwmark) {
+            defaults.fwmark = x;
+        }
+        if let Some(x) = routing_cfg.and_then(|x| x.rule_priority) {
+            defaults.rule_priority = x;
+        }
+
+        let mut route_manager = match PlatformSpecificFactory::get_route(defaults) {
+            Ok(x) => Box::new(x),
+            Err(e) => {
+                log::error!("Failed to create route manager: {}", e);
+                return Err(rocket);
+            }
+        };
+        if let Err(e) = route_manager.init() {
+            log::error!("Failed to initialize route manager: {}", e);
+            return Err(rocket);
+        }
+
+        // Evaluated ahead of both tunnel and bypass rules, moved along with them
+        let split_tunnel = match PlatformSpecificFactory::get_split_tunnel(
+            route_manager.bypass_priority().saturating_sub(1),
+        ) {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Failed to set up split tunnel: {}", e);
+                return Err(rocket);
             }
+        };
+        let firewall = match PlatformSpecificFactory::get_firewall() {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Failed to set up firewall: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let iface_states = Arc::new(DashMap::new());
+        let v4 = Arc::new(DashSet::new());
+        let v6 = Arc::new(DashSet::new());
+        let dns_backends = match &cfg.dns {
+            Some(x) => match x.backends() {
+                Ok(x) => x,
+                Err(e) => {
+                    log::error!("Invalid DNS backend in config: {}", e);
+                    return Err(rocket);
+                }
+            },
+            None => DnsBackend::AUTO.to_vec(),
+        };
+        dns_self_test(&dns_backends);
+        let dnsmon = match PlatformSpecificFactory::get_dnsmon(
+            TALPID_TOKIO_RT.handle().clone(),
+            &dns_backends,
+        ) {
+            Ok(x) => Arc::new(Mutex::new(x)),
+            Err(e) => {
+                log::error!("Failed to set up DNS monitor: {}", e);
+                return Err(rocket);
+            }
+        };
+        let forwarder = match cfg.dns.as_ref().and_then(|x| x.forwarder.as_ref()) {
+            Some(x) => match Forwarder::from_config(x) {
+                Ok(x) => Some(Arc::new(x)),
+                Err(e) => {
+                    log::error!("Invalid DNS forwarder config: {}", e);
+                    return Err(rocket);
+                }
+            },
+            None => None,
+        };
+
+        let reg = registry.lock().unwrap();
+        let registered = reg
+            .register(Box::new(InterfaceCollector::new(Arc::clone(&iface_states))))
+            .and_then(|_| {
+                reg.register(Box::new(AddressPoolCollector::new(
+                    Arc::clone(&v4),
+                    Arc::clone(&v6),
+                )))
+            })
+            .and_then(|_| reg.register(Box::new(DnsManagerCollector::new(Arc::clone(&dnsmon)))))
+            .and_then(|_| match &forwarder {
+                Some(x) => x.register(&reg),
+                None => Ok(()),
+            });
+        drop(reg);
+        if let Err(e) = registered {
+            log::error!("Failed to register metrics: {}", e);
+            return Err(rocket);
         }
-        rocket
+
+        let stats_cfg = cfg.stats.as_ref();
+        let stats_store = StatsStore {
+            interval: stats_cfg
+                .and_then(|x| x.sample_interval)
+                .unwrap_or(DEFAULT_SAMPLE_INTERVAL)
+                .max(1),
+            retention: stats_cfg
+                .and_then(|x| x.retention)
+                .unwrap_or(DEFAULT_RETENTION),
+            history: Arc::new(DashMap::new()),
+        };
+
+        Ok(rocket
             .mount(
                 "/api/v1",
                 routes![
@@ -189,10 +396,13 @@ pub(crate) fn stage() -> AdHoc {
                     interface::get_status,
                     interface::put_status,
                     interface::put_ips,
+                    interface::get_ips,
+                    interface::delete_ip,
                     interface::post_routes,
                     interface::get_routes,
                     interface::delete_routes,
                     interface::get_trafficstat,
+                    interface::get_dns,
                     interface::put_dns,
                     interface::delete_dns,
                     peer::create_peer,
@@ -200,29 +410,65 @@ pub(crate) fn stage() -> AdHoc {
                     peer::get_peer,
                     //peer::update_peer,
                     peer::delete_peer,
+                    peer::get_peer_history,
+                    peer::reset_usage,
+                    events::get_events,
+                    events::stream_events,
                     route::create_bypass,
                     route::get_bypass,
                     route::delete_bypass,
+                    route::get_drift,
+                    split_tunnel::add_pid,
+                    split_tunnel::get_pids,
+                    split_tunnel::delete_pid,
+                    split_tunnel::delete_pids,
+                    firewall::get_firewall,
+                    firewall::put_firewall,
                     prometheus,
                 ],
             )
-            .manage(InterfaceStore {
-                iface_states: DashMap::new(),
-            })
+            .manage(InterfaceStore { iface_states })
             .manage(RouteManagerStore {
-                route_manager: Mutex::new(route_manager),
-                route_store: DashMap::new(),
+                route_manager: Arc::new(Mutex::new(route_manager)),
+                route_store: Arc::new(DashMap::new()),
+                defaults,
+                reconcile_interval: routing_cfg
+                    .and_then(|x| x.reconcile_interval)
+                    .unwrap_or(DEFAULT_RECONCILE_INTERVAL),
             })
-            .manage(DnsMonStore {
-                dnsmon: Arc::new(Mutex::new(
-                    PlatformSpecificFactory::get_dnsmon(TALPID_TOKIO_RT.handle().clone()).unwrap(),
-                )),
+            .manage(SplitTunnelStore {
+                split_tunnel: Arc::new(Mutex::new(split_tunnel)),
             })
+            .manage(FirewallStore::new(firewall, cfg.firewall.as_ref()))
+            .attach(system_cleanup())
+            .manage(DnsMonStore { dnsmon })
+            .manage(ForwarderStore { forwarder })
+            .attach(forwarder::server())
+            .manage(stats_store)
+            .manage(EventStore::new())
+            .manage(ExpiryStore::load(
+                cfg.expiry
+                    .as_ref()
+                    .and_then(|x| x.restore_grace)
+                    .unwrap_or(DEFAULT_RESTORE_GRACE),
+            ))
+            .manage(UsageStore::load())
+            .manage(NetworkMonitorStore::new())
+            .attach(periodic::network_watcher())
+            .attach(expiry::scheduler())
+            .attach(stats::sampler())
+            .attach(firewall::monitor())
+            .attach(route::lan_monitor())
+            .manage(DriftStore::default())
+            .attach(route::reconciler())
+            .attach(roaming::monitor())
+            .attach(endpoint::resolver())
+            .attach(endpoint::failover())
             .manage(IpStore {
-                v4: DashSet::new(),
-                v4_last_count: RwLock::new(0),
-                v6: DashSet::new(),
-                v6_last_count: RwLock::new(0),
-            })
+                v4,
+                v4_last_count: Arc::new(RwLock::new(0)),
+                v6,
+                v6_last_count: Arc::new(RwLock::new(0)),
+            }))
     })
 }
@@ -17,20 +17,27 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use prometheus::{Counter, Opts};
+use std::net::SocketAddr;
+
 use regex::Regex;
-use rocket::{http::Status, serde::json::Json, State};
+use rocket::{http::Status, serde, serde::json::Json, State};
 
 use crate::api::{
-    common::{ApiResponse, ApiResponseType, PrometheusStore},
+    common::{ApiResponse, ApiResponseType},
     v1::{
-        types::{IpStore, RouteManagerStore},
+        endpoint,
+        events::{DaemonEvent, EventStore},
+        expiry::ExpiryStore,
+        quota::UsageStore,
+        route,
+        stats::{self, parse_window, RateSample},
+        types::{IpStore, RouteManagerStore, StatsStore},
         InterfaceStore,
     },
 };
-use wgctrl::platform_specific::common::{PlatformRoute, WgPeerCfg};
+use wgctrl::platform_specific::common::PlatformRoute;
 
-use super::types::PeerConfig;
+use super::types::{IfaceState, PeerConfig};
 use crate::api::tokenauth::ApiKey;
 
 fn format_ipv4(v4_suffix: u32) -> String {
@@ -66,7 +73,8 @@ pub(crate) async fn create_peer(
     rms: &State<RouteManagerStore>,
     iface_store: &State<InterfaceStore>,
     ip_store: &State<IpStore>,
-    prom_store: &State<PrometheusStore>,
+    expiry_store: &State<ExpiryStore>,
+    usage_store: &State<UsageStore>,
     if_id: String,
     mut peercfg: Json<PeerConfig>,
 ) -> ApiResponseType<PeerConfig> {
@@ -81,6 +89,73 @@ pub(crate) async fn create_peer(
         }
     }
 
+    if let Some(quota) = &peercfg.quota {
+        if quota.period.is_some() && quota.period_secs().is_none() {
+            return (
+                Status::UnprocessableEntity,
+                ApiResponse::err(-1, "Bad quota period format"),
+            );
+        }
+        peercfg.usage = Some(usage_store.restore(&if_id, &peercfg.pubkey, stats::now()));
+    }
+
+    if let Some(idle_timeout) = &peercfg.idle_timeout {
+        if parse_window(idle_timeout).is_none() {
+            return (
+                Status::UnprocessableEntity,
+                ApiResponse::err(-1, "Bad idle_timeout format"),
+            );
+        }
+    }
+
+    if !peercfg.endpoints.is_empty() {
+        for x in peercfg.endpoints.iter() {
+            if let Err(e) = endpoint::validate(x) {
+                return (Status::UnprocessableEntity, ApiResponse::err(-1, &e));
+            }
+        }
+        match peercfg.endpoint.clone() {
+            Some(x) if !peercfg.endpoints.contains(&x) => {
+                return (
+                    Status::UnprocessableEntity,
+                    ApiResponse::err(-1, "endpoint is not one of endpoints"),
+                );
+            }
+            Some(_) => {}
+            None => peercfg.endpoint = peercfg.endpoints.first().cloned(),
+        }
+        // Handshakes only happen while traffic flows, so idle peers would look unreachable
+        if peercfg.endpoints.len() > 1 && peercfg.keepalive.is_none() {
+            return (
+                Status::UnprocessableEntity,
+                ApiResponse::err(-1, "Endpoint failover needs keepalive"),
+            );
+        }
+    }
+
+    if let Some(failover_timeout) = &peercfg.failover_timeout {
+        if parse_window(failover_timeout).is_none() {
+            return (
+                Status::UnprocessableEntity,
+                ApiResponse::err(-1, "Bad failover_timeout format"),
+            );
+        }
+    }
+    peercfg.endpoint_since = stats::now();
+
+    if let Some(endpt) = peercfg.endpoint.clone() {
+        match rocket::tokio::task::spawn_blocking(move || endpoint::resolve(&endpt, None)).await {
+            Ok(Ok(x)) => peercfg.resolved_endpoint = Some(x.to_string()),
+            Ok(Err(e)) => return (Status::UnprocessableEntity, ApiResponse::err(-1, &e)),
+            Err(e) => {
+                return (
+                    Status::InternalServerError,
+                    ApiResponse::err(-1, &e.to_string()),
+                )
+            }
+        }
+    }
+
     let iface_states = &iface_store.iface_states;
     let iface_state_lock = match iface_states.get(&if_id) {
         Some(x) => x,
@@ -153,11 +228,10 @@ pub(crate) async fn create_peer(
         peercfg.autoalloc_v6 = Some(v6_suffix);
     }
 
-    if let Some(endpt) = &peercfg.endpoint {
+    if let Some(endpt) = &peercfg.resolved_endpoint {
         let mut rm = rms.route_manager.lock().unwrap();
-        let re = Regex::new(r":.*").unwrap();
-        let ip = re.replace_all(endpt, "");
-        match rm.add_route_bypass(&(*ip).to_string()) {
+        let ip = endpt.parse::<SocketAddr>().unwrap().ip();
+        match rm.add_route_bypass(&ip.to_string()) {
             Ok(_) => {}
             Err(_x) => {
                 return (
@@ -168,21 +242,8 @@ pub(crate) async fn create_peer(
         }
     }
 
-    let peer_tx_opts = Opts::new("peer_tx", "Peer TX bytes")
-        .const_label("interface", if_id.clone())
-        .const_label("pubk", peercfg.pubkey.clone());
-    let peer_rx_opts = Opts::new("peer_rx", "Peer RX bytes")
-        .const_label("interface", if_id.clone())
-        .const_label("pubk", peercfg.pubkey.clone());
-
     // Do some magic
-    match iface_state.interface.add_peer(WgPeerCfg {
-        pubkey: peercfg.pubkey.clone(),
-        psk: None,
-        endpoint: peercfg.endpoint.clone(),
-        allowed_ips: peercfg.allowed_ips.clone(),
-        keep_alive: peercfg.keepalive,
-    }) {
+    match iface_state.interface.add_peer(peercfg.wg_peer_cfg()) {
         Ok(_) => {}
         Err(e) => {
             return (
@@ -191,18 +252,14 @@ pub(crate) async fn create_peer(
             )
         }
     }
-    let tx_counter = Counter::with_opts(peer_tx_opts).unwrap();
-    let rx_counter = Counter::with_opts(peer_rx_opts).unwrap();
-
-    let reg = prom_store.registry.lock().unwrap();
-    reg.register(Box::new(tx_counter.clone())).unwrap();
-    reg.register(Box::new(rx_counter.clone())).unwrap();
-    drop(reg);
+    iface_state
+        .peer_cfgs
+        .insert(peercfg.pubkey.clone(), peercfg.clone());
+    expiry_store.schedule(&if_id, &peercfg, stats::now());
 
-    iface_state.peer_cfgs.insert(
-        peercfg.pubkey.clone(),
-        (peercfg.clone(), tx_counter, rx_counter),
-    );
+    let wanted = route::auto_routes(&iface_state);
+    drop(iface_state);
+    route::sync_auto_routes(rms, &if_id, wanted);
 
     (Status::Ok, ApiResponse::ok(peercfg.into_inner()))
 }
@@ -221,11 +278,7 @@ pub(crate) async fn get_peers(
 
     let iface_state = iface_state_lock.lock().unwrap();
 
-    let peers: Vec<PeerConfig> = iface_state
-        .peer_cfgs
-        .values()
-        .map(|x| x.0.clone())
-        .collect();
+    let peers: Vec<PeerConfig> = iface_state.peer_cfgs.values().cloned().collect();
 
     (Status::Ok, ApiResponse::ok(peers))
 }
@@ -245,10 +298,141 @@ pub(crate) async fn get_peer(
 
     let iface_state = iface_state_lock.lock().unwrap();
 
-    match iface_state.peer_cfgs.get(&pubk) {
-        Some(x) => (Status::Ok, ApiResponse::ok(x.0.clone())),
-        None => (Status::NotFound, ApiResponse::err(-1, "Not found")),
+    let mut peercfg = match iface_state.peer_cfgs.get(&pubk) {
+        Some(x) => x.clone(),
+        None => return (Status::NotFound, ApiResponse::err(-1, "Not found")),
+    };
+
+    peercfg.stats = match iface_state.interface.get_trafficstats() {
+        Ok(x) => x.into_iter().find(|x| x.pubkey == pubk),
+        Err(e) => {
+            return (
+                Status::InternalServerError,
+                ApiResponse::err(-1, &e.to_string()),
+            )
+        }
+    };
+
+    (Status::Ok, ApiResponse::ok(peercfg))
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct PeerHistoryResp {
+    pub(crate) interval: u64,
+    pub(crate) samples: Vec<RateSample>,
+}
+
+#[get("/interface/<if_id>/peer/<pubk>/history?<window>")]
+pub(crate) async fn get_peer_history(
+    _apikey: ApiKey,
+    iface_store: &State<InterfaceStore>,
+    stats_store: &State<StatsStore>,
+    if_id: String,
+    pubk: String,
+    window: Option<String>,
+) -> ApiResponseType<PeerHistoryResp> {
+    let window = match window {
+        Some(x) => match parse_window(&x) {
+            Some(x) => x,
+            None => {
+                return (
+                    Status::UnprocessableEntity,
+                    ApiResponse::err(-1, "Bad window format"),
+                )
+            }
+        },
+        None => stats_store.retention,
+    };
+
+    match iface_store.iface_states.get(&if_id) {
+        Some(x) => {
+            if !x.lock().unwrap().peer_cfgs.contains_key(&pubk) {
+                return (Status::NotFound, ApiResponse::err(-1, "Not found"));
+            }
+        }
+        None => return (Status::NotFound, ApiResponse::err(-1, "Not found")),
+    };
+
+    let samples = match stats_store.history.get(&(if_id, pubk)) {
+        Some(x) => x.window(stats::now(), window),
+        None => vec![],
+    };
+
+    (
+        Status::Ok,
+        ApiResponse::ok(PeerHistoryResp {
+            interval: stats_store.interval,
+            samples,
+        }),
+    )
+}
+
+#[post("/interface/<if_id>/peer/<pubk>/reset-usage")]
+pub(crate) async fn reset_usage(
+    _apikey: ApiKey,
+    iface_store: &State<InterfaceStore>,
+    usage_store: &State<UsageStore>,
+    event_store: &State<EventStore>,
+    if_id: String,
+    pubk: String,
+) -> ApiResponseType<PeerConfig> {
+    let iface_states = &iface_store.iface_states;
+    let iface_state_lock = match iface_states.get(&if_id) {
+        Some(x) => x,
+        None => return (Status::NotFound, ApiResponse::err(-1, "Not found")),
+    };
+    let mut iface_state = iface_state_lock.lock().unwrap();
+    let IfaceState {
+        interface,
+        peer_cfgs,
+        ..
+    } = &mut *iface_state;
+
+    let peercfg = match peer_cfgs.get_mut(&pubk) {
+        Some(x) => x,
+        None => return (Status::NotFound, ApiResponse::err(-1, "Not found")),
+    };
+
+    // Suspended peers are not on the interface, their counters start over once added back
+    let counters = if peercfg.suspended {
+        None
+    } else {
+        interface
+            .get_trafficstats()
+            .ok()
+            .and_then(|x| x.into_iter().find(|x| x.pubkey == pubk))
+            .map(|x| (x.rx_bytes, x.tx_bytes))
+    };
+
+    if peercfg.suspended {
+        match interface.add_peer(peercfg.wg_peer_cfg()) {
+            Ok(_) => {
+                peercfg.suspended = false;
+                // No handshake is due before the peer is back
+                peercfg.endpoint_since = stats::now();
+            }
+            Err(e) => {
+                return (
+                    Status::InternalServerError,
+                    ApiResponse::err(-1, &e.to_string()),
+                )
+            }
+        }
+    }
+
+    if let Some(usage) = &mut peercfg.usage {
+        usage.reset(counters);
     }
+    let peercfg = peercfg.clone();
+    usage_store.update(&if_id, &iface_state);
+
+    event_store.emit(DaemonEvent::QuotaReset {
+        interface: if_id.clone(),
+        pubkey: pubk.clone(),
+    });
+
+    (Status::Ok, ApiResponse::ok(peercfg))
 }
 
 //#[put("/interface/<if_id>/peer/<pubk>", format = "json", data = "<peercfg>")]
@@ -262,12 +446,66 @@ pub(crate) async fn get_peer(
 //    None
 //}
 
+/// Removes a peer from the interface and releases its autoalloc addresses. Shared by the API and
+/// the expiry scheduler, which release the endpoint bypass once the interface is unlocked. Returns
+/// `None` if there is no such peer.
+pub(crate) fn remove_peer(
+    iface_state: &mut IfaceState,
+    ip_store: &IpStore,
+    pubk: &str,
+) -> Result<Option<PeerConfig>, String> {
+    let peercfg = match iface_state.peer_cfgs.remove(pubk) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    // Suspended peers are already gone from the interface
+    if !peercfg.suspended {
+        iface_state
+            .interface
+            .remove_peer(pubk)
+            .map_err(|e| e.to_string())?;
+    }
+
+    if let Some(x) = peercfg.autoalloc_v4 {
+        ip_store.v4.remove(&x);
+    }
+    if let Some(x) = peercfg.autoalloc_v6 {
+        ip_store.v6.remove(&x);
+    }
+
+    Ok(Some(peercfg))
+}
+
+/// Points the peers at their configured endpoints again, dropping the ones learned on the
+/// previous network. Returns the public keys of the refreshed peers.
+pub(crate) fn refresh_endpoints(iface_state: &mut IfaceState) -> Vec<String> {
+    let mut refreshed = vec![];
+    for peercfg in iface_state.peer_cfgs.values() {
+        // Suspended peers are not on the interface
+        let endpt = match &peercfg.resolved_endpoint {
+            Some(x) if !peercfg.suspended => x,
+            _ => continue,
+        };
+        match iface_state
+            .interface
+            .set_peer_endpoint(&peercfg.pubkey, endpt)
+        {
+            Ok(_) => refreshed.push(peercfg.pubkey.clone()),
+            Err(e) => log::warn!("Failed to refresh endpoint of {}: {}", peercfg.pubkey, e),
+        }
+    }
+    refreshed
+}
+
 #[delete("/interface/<if_id>/peer/<pubk>")]
 pub(crate) async fn delete_peer(
     _apikey: ApiKey,
+    rms: &State<RouteManagerStore>,
     iface_store: &State<InterfaceStore>,
     ip_store: &State<IpStore>,
-    prom_store: &State<PrometheusStore>,
+    expiry_store: &State<ExpiryStore>,
+    usage_store: &State<UsageStore>,
     if_id: String,
     pubk: String,
 ) -> ApiResponseType<String> {
@@ -278,50 +516,19 @@ pub(crate) async fn delete_peer(
     };
     let mut iface_state = iface_state_lock.lock().unwrap();
 
-    let (peercfg, tx_counter, rx_counter) = match iface_state.peer_cfgs.get(&pubk) {
-        Some(x) => x.clone(),
-        None => {
-            return (Status::NotFound, ApiResponse::err(-1, "Not found"));
-        }
-    };
-
-    if let Some(_endpt) = &peercfg.endpoint {
-        //let mut rm = rms.route_manager.lock().unwrap();
-        //match rm.delete_route_bypass(&endpt) {
-        //    Ok(_) => {}
-        //    Err(_x) => {
-        //        //return (
-        //        //    Status::InternalServerError,
-        //        //    ApiResponse::err(-1, "Failed to bypass peer endpt"),
-        //        //);
-        //    }
-        //}
-    }
-
-    let reg = prom_store.registry.lock().unwrap();
-    reg.unregister(Box::new(tx_counter)).ok();
-    reg.unregister(Box::new(rx_counter)).ok();
-    drop(reg);
-
-    iface_state.peer_cfgs.remove(&pubk);
-    match iface_state.interface.remove_peer(&pubk) {
-        Ok(_) => {
-            if let Some(x) = peercfg.autoalloc_v4 {
-                let v4store = &ip_store.v4;
-                v4store.remove(&x);
-            }
-            if let Some(x) = peercfg.autoalloc_v6 {
-                let v4store = &ip_store.v6;
-                v4store.remove(&x);
-            }
-        }
-        Err(e) => {
-            return (
-                Status::InternalServerError,
-                ApiResponse::err(-1, &e.to_string()),
-            )
-        }
+    let peercfg = match remove_peer(&mut iface_state, ip_store, &pubk) {
+        Ok(Some(x)) => x,
+        Ok(None) => return (Status::NotFound, ApiResponse::err(-1, "Not found")),
+        Err(e) => return (Status::InternalServerError, ApiResponse::err(-1, &e)),
     };
+    expiry_store.unschedule(&if_id, &pubk);
+    usage_store.forget(&if_id, &pubk);
+
+    let wanted = route::auto_routes(&iface_state);
+    drop(iface_state);
+    drop(iface_state_lock);
+    route::sync_auto_routes(rms, &if_id, wanted);
+    endpoint::release_bypass(rms, iface_store, peercfg.resolved_endpoint);
 
     (Status::Ok, ApiResponse::ok("Peer removed".to_string()))
 }
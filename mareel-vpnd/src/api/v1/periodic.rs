@@ -0,0 +1,153 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::tokio::sync::watch;
+use rocket::{Orbit, Rocket};
+use wgctrl::platform_specific::common::PlatformNetworkMonitor;
+use wgctrl::platform_specific::{NetworkMonitor, PlatformSpecificFactory};
+
+/// Longest a wait for network changes blocks, so that shutdown is not held up
+const CHANGE_WAIT: Duration = Duration::from_secs(1);
+
+/// One network monitor for everything following the physical network, as each change is only
+/// reported once
+#[derive(Clone)]
+pub(crate) struct NetworkMonitorStore {
+    /// Unset where the platform does not notify network changes
+    changes: Option<watch::Receiver<()>>,
+    /// Handed over to the watcher at liftoff
+    watcher: Arc<Mutex<Option<(NetworkMonitor, watch::Sender<()>)>>>,
+}
+
+impl NetworkMonitorStore {
+    pub(crate) fn new() -> Self {
+        match PlatformSpecificFactory::get_network_monitor() {
+            Ok(x) => {
+                let (tx, rx) = watch::channel(());
+                NetworkMonitorStore {
+                    changes: Some(rx),
+                    watcher: Arc::new(Mutex::new(Some((x, tx)))),
+                }
+            }
+            Err(e) => {
+                log::warn!("Following the network by polling only: {}", e);
+                NetworkMonitorStore {
+                    changes: None,
+                    watcher: Arc::new(Mutex::new(None)),
+                }
+            }
+        }
+    }
+}
+
+/// Waits for network changes and passes them on to the `periodic` tasks following the network
+pub(crate) fn network_watcher() -> AdHoc {
+    AdHoc::on_liftoff("Network watcher", |rocket| {
+        Box::pin(async move {
+            let watcher = rocket
+                .state::<NetworkMonitorStore>()
+                .unwrap()
+                .watcher
+                .lock()
+                .unwrap()
+                .take();
+            let (monitor, tx) = match watcher {
+                Some(x) => x,
+                None => return,
+            };
+            let monitor = Arc::new(Mutex::new(monitor));
+            let shutdown = rocket.shutdown();
+
+            rocket::tokio::spawn(async move {
+                rocket::tokio::pin!(shutdown);
+
+                loop {
+                    let monitor = Arc::clone(&monitor);
+                    let changed = rocket::tokio::select! {
+                        x = rocket::tokio::task::spawn_blocking(move || {
+                            monitor.lock().unwrap().wait(CHANGE_WAIT)
+                        }) => matches!(x, Ok(Ok(true))),
+                        _ = &mut shutdown => break,
+                    };
+                    if changed && tx.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Resolves on the next network change, or never once nothing reports them
+async fn next_change(changes: &mut Option<watch::Receiver<()>>) {
+    if let Some(x) = changes {
+        if x.changed().await.is_ok() {
+            return;
+        }
+    }
+    *changes = None;
+    std::future::pending().await
+}
+
+/// Runs `f` off the async runtime every `interval` until shutdown, and as soon as the network
+/// changes if `follow_network` is set. Runs never overlap.
+pub(crate) fn periodic<F>(
+    rocket: &Rocket<Orbit>,
+    name: &'static str,
+    interval: Duration,
+    follow_network: bool,
+    f: F,
+) where
+    F: FnMut() + Send + 'static,
+{
+    let mut changes = match follow_network {
+        true => rocket
+            .state::<NetworkMonitorStore>()
+            .unwrap()
+            .changes
+            .clone(),
+        false => None,
+    };
+    let shutdown = rocket.shutdown();
+    let f = Arc::new(Mutex::new(f));
+
+    rocket::tokio::spawn(async move {
+        // A zero period is refused
+        let mut ticks = rocket::tokio::time::interval(interval.max(Duration::from_secs(1)));
+        rocket::tokio::pin!(shutdown);
+
+        loop {
+            rocket::tokio::select! {
+                _ = ticks.tick() => {},
+                _ = next_change(&mut changes) => {},
+                _ = &mut shutdown => break,
+            };
+
+            let f = Arc::clone(&f);
+            if let Err(e) = rocket::tokio::task::spawn_blocking(move || (f.lock().unwrap())()).await
+            {
+                log::error!("{} failed: {}", name, e);
+            }
+        }
+    });
+}
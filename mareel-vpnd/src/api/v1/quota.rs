@@ -0,0 +1,370 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use rocket::serde;
+
+use wgctrl::platform_specific::common::PeerTrafficStat;
+
+use crate::util::state;
+
+use super::events::{DaemonEvent, EventStore};
+use super::stats::parse_window;
+use super::types::IfaceState;
+
+const QUOTA_STATE: &str = "quota.json";
+
+/// Usage percentages at which a `quota_threshold` event is emitted
+pub(crate) const QUOTA_THRESHOLDS: [u8; 3] = [80, 90, 100];
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct PeerQuota {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rx_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tx_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total_bytes: Option<u64>,
+    /// Billing period, e.g. `30d`. Usage never resets by itself if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) period: Option<String>,
+}
+
+impl PeerQuota {
+    pub(crate) fn period_secs(&self) -> Option<u64> {
+        self.period.as_deref().and_then(parse_window)
+    }
+
+    /// Returns `(used, limit)` of whichever limit is closest to being exhausted
+    pub(crate) fn most_used(&self, usage: &PeerUsage) -> Option<(u64, u64)> {
+        [
+            (usage.rx_bytes, self.rx_bytes),
+            (usage.tx_bytes, self.tx_bytes),
+            (
+                usage.rx_bytes.saturating_add(usage.tx_bytes),
+                self.total_bytes,
+            ),
+        ]
+        .iter()
+        .filter_map(|(used, limit)| limit.map(|limit| (*used, limit)))
+        .max_by(|(a_used, a_limit), (b_used, b_limit)| {
+            // Compare a_used / a_limit with b_used / b_limit without dividing
+            (*a_used as u128 * *b_limit as u128).cmp(&(*b_used as u128 * *a_limit as u128))
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct PeerUsage {
+    pub(crate) rx_bytes: u64,
+    pub(crate) tx_bytes: u64,
+    pub(crate) period_start: u64,
+    #[serde(skip)]
+    last: Option<(u64, u64)>,
+    #[serde(skip)]
+    notified: u8,
+}
+
+impl PeerUsage {
+    pub(crate) fn new(period_start: u64) -> Self {
+        PeerUsage {
+            rx_bytes: 0,
+            tx_bytes: 0,
+            period_start,
+            last: None,
+            notified: 0,
+        }
+    }
+
+    /// Feeds raw counters from the interface. Counter going backwards means that the peer has
+    /// been re-added, so the new value is taken as the amount transferred since then.
+    pub(crate) fn account(&mut self, rx_bytes: u64, tx_bytes: u64) {
+        let (rx_delta, tx_delta) = match self.last {
+            Some((last_rx, last_tx)) => (
+                rx_bytes.checked_sub(last_rx).unwrap_or(rx_bytes),
+                tx_bytes.checked_sub(last_tx).unwrap_or(tx_bytes),
+            ),
+            None => (rx_bytes, tx_bytes),
+        };
+
+        self.rx_bytes = self.rx_bytes.saturating_add(rx_delta);
+        self.tx_bytes = self.tx_bytes.saturating_add(tx_delta);
+        self.last = Some((rx_bytes, tx_bytes));
+    }
+
+    /// Zeroes accumulated usage. `counters` are the raw counters of the peer at the time of the
+    /// reset, if it is on the interface. Traffic up to them is accounted to the closing period,
+    /// everything after them to the new one.
+    pub(crate) fn reset(&mut self, counters: Option<(u64, u64)>) {
+        if let Some((rx_bytes, tx_bytes)) = counters {
+            self.account(rx_bytes, tx_bytes);
+        }
+        self.rx_bytes = 0;
+        self.tx_bytes = 0;
+        self.notified = 0;
+        self.last = counters;
+    }
+}
+
+#[test]
+fn test_peer_usage_account() {
+    let mut usage = PeerUsage::new(0);
+    usage.account(100, 50);
+    usage.account(300, 50);
+    assert_eq!((usage.rx_bytes, usage.tx_bytes), (300, 50));
+
+    // Peer was re-added, counters start over
+    usage.account(20, 10);
+    assert_eq!((usage.rx_bytes, usage.tx_bytes), (320, 60));
+
+    // Traffic since the last sample belongs to the closing period
+    usage.reset(Some((30, 10)));
+    assert_eq!((usage.rx_bytes, usage.tx_bytes), (0, 0));
+    usage.account(40, 15);
+    assert_eq!((usage.rx_bytes, usage.tx_bytes), (10, 5));
+
+    // Peer is re-added after the reset, so counters start from zero
+    usage.reset(None);
+    usage.account(5, 1);
+    assert_eq!((usage.rx_bytes, usage.tx_bytes), (5, 1));
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct UsageEntry {
+    interface: String,
+    pubkey: String,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    period_start: u64,
+    notified: u8,
+}
+
+/// Quota usage of peers. Persisted to the state directory, so that peers pushed again after a
+/// restart don't start over with a fresh quota.
+#[derive(Clone)]
+pub(crate) struct UsageStore {
+    entries: Arc<DashMap<(String, String), UsageEntry>>,
+}
+
+impl UsageStore {
+    pub(crate) fn load() -> Self {
+        let entries = DashMap::new();
+        for entry in state::load::<Vec<UsageEntry>>(QUOTA_STATE).unwrap_or_default() {
+            entries.insert((entry.interface.clone(), entry.pubkey.clone()), entry);
+        }
+
+        UsageStore {
+            entries: Arc::new(entries),
+        }
+    }
+
+    fn persist(&self) {
+        let entries: Vec<UsageEntry> = self.entries.iter().map(|x| x.value().clone()).collect();
+        if let Err(e) = state::save(QUOTA_STATE, &entries) {
+            log::error!("Failed to save quota usage state: {}", e);
+        }
+    }
+
+    /// Returns the saved usage of a peer, or a fresh one. Counters of a newly added peer start
+    /// from zero, so none are carried over.
+    pub(crate) fn restore(&self, if_id: &str, pubk: &str, now: u64) -> PeerUsage {
+        match self.entries.get(&(if_id.to_string(), pubk.to_string())) {
+            Some(x) => PeerUsage {
+                rx_bytes: x.rx_bytes,
+                tx_bytes: x.tx_bytes,
+                period_start: x.period_start,
+                last: None,
+                notified: x.notified,
+            },
+            None => PeerUsage::new(now),
+        }
+    }
+
+    /// Saves the usage of the peers of an interface if it changed
+    pub(crate) fn update(&self, if_id: &str, iface_state: &IfaceState) {
+        let mut dirty = false;
+        for (pubk, peercfg) in iface_state.peer_cfgs.iter() {
+            let usage = match &peercfg.usage {
+                Some(x) => x,
+                None => continue,
+            };
+            let entry = UsageEntry {
+                interface: if_id.to_string(),
+                pubkey: pubk.clone(),
+                rx_bytes: usage.rx_bytes,
+                tx_bytes: usage.tx_bytes,
+                period_start: usage.period_start,
+                notified: usage.notified,
+            };
+
+            let key = (if_id.to_string(), pubk.clone());
+            if self.entries.get(&key).map(|x| *x != entry).unwrap_or(true) {
+                self.entries.insert(key, entry);
+                dirty = true;
+            }
+        }
+
+        if dirty {
+            self.persist();
+        }
+    }
+
+    pub(crate) fn forget(&self, if_id: &str, pubk: &str) {
+        if self
+            .entries
+            .remove(&(if_id.to_string(), pubk.to_string()))
+            .is_some()
+        {
+            self.persist();
+        }
+    }
+
+    pub(crate) fn forget_iface(&self, if_id: &str) {
+        let before = self.entries.len();
+        self.entries.retain(|k, _| k.0 != if_id);
+        if self.entries.len() != before {
+            self.persist();
+        }
+    }
+}
+
+#[test]
+fn test_quota_most_used() {
+    let quota = PeerQuota {
+        rx_bytes: Some(1000),
+        tx_bytes: Some(100),
+        total_bytes: None,
+        period: None,
+    };
+    let mut usage = PeerUsage::new(0);
+    assert_eq!(quota.most_used(&usage), Some((0, 1000)));
+
+    usage.account(500, 90);
+    assert_eq!(quota.most_used(&usage), Some((90, 100)));
+
+    let quota = PeerQuota {
+        rx_bytes: None,
+        tx_bytes: None,
+        total_bytes: None,
+        period: Some("30d".to_string()),
+    };
+    assert_eq!(quota.most_used(&usage), None);
+    assert_eq!(quota.period_secs(), Some(30 * 24 * 60 * 60));
+}
+
+/// Accounts traffic of the peers on a single interface, then suspends peers which ran out of
+/// quota and resumes the ones whose billing period rolled over.
+pub(crate) fn enforce(
+    if_id: &str,
+    iface_state: &mut IfaceState,
+    trafficstat: &[PeerTrafficStat],
+    event_store: &EventStore,
+    now: u64,
+) {
+    let IfaceState {
+        interface,
+        peer_cfgs,
+        ..
+    } = iface_state;
+
+    for (pubk, peercfg) in peer_cfgs.iter_mut() {
+        let quota = match &peercfg.quota {
+            Some(x) => x,
+            None => continue,
+        };
+        let usage = peercfg.usage.get_or_insert_with(|| PeerUsage::new(now));
+        let counters = trafficstat
+            .iter()
+            .find(|x| &x.pubkey == pubk)
+            .map(|x| (x.rx_bytes, x.tx_bytes));
+
+        if let Some(period) = quota.period_secs().filter(|x| *x > 0) {
+            if now >= usage.period_start.saturating_add(period) {
+                usage.period_start = now - (now - usage.period_start) % period;
+                // Suspended peers are not on the interface, their counters start over on resume
+                usage.reset(counters.filter(|_| !peercfg.suspended));
+                event_store.emit(DaemonEvent::QuotaReset {
+                    interface: if_id.to_string(),
+                    pubkey: pubk.clone(),
+                });
+
+                if peercfg.suspended {
+                    match interface.add_peer(peercfg.wg_peer_cfg()) {
+                        Ok(_) => {
+                            peercfg.suspended = false;
+                            // No handshake is due before the peer is back
+                            peercfg.endpoint_since = now;
+                        }
+                        Err(e) => log::error!("Failed to resume peer {}: {}", pubk, e),
+                    }
+                }
+                continue;
+            }
+        }
+
+        if peercfg.suspended {
+            continue;
+        }
+
+        if let Some((rx_bytes, tx_bytes)) = counters {
+            usage.account(rx_bytes, tx_bytes);
+        }
+
+        let (used, limit) = match quota.most_used(usage) {
+            Some(x) => x,
+            None => continue,
+        };
+        let percent = if limit == 0 {
+            100
+        } else {
+            (used as u128 * 100 / limit as u128).min(100) as u8
+        };
+
+        for threshold in QUOTA_THRESHOLDS {
+            if threshold > usage.notified && percent >= threshold {
+                usage.notified = threshold;
+                event_store.emit(DaemonEvent::QuotaThreshold {
+                    interface: if_id.to_string(),
+                    pubkey: pubk.clone(),
+                    percent: threshold,
+                    used,
+                    limit,
+                });
+            }
+        }
+
+        if used >= limit {
+            match interface.remove_peer(pubk) {
+                Ok(_) => {
+                    peercfg.suspended = true;
+                    event_store.emit(DaemonEvent::QuotaExceeded {
+                        interface: if_id.to_string(),
+                        pubkey: pubk.clone(),
+                    });
+                }
+                Err(e) => log::error!("Failed to suspend peer {}: {}", pubk, e),
+            }
+        }
+    }
+}
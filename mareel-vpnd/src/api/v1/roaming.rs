@@ -0,0 +1,154 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use wgctrl::platform_specific::common::PlatformRoute;
+
+use super::endpoint;
+use super::events::{DaemonEvent, EventStore};
+use super::forwarder::ForwarderStore;
+use super::interface::forward_dns;
+use super::peer;
+use super::periodic::periodic;
+use super::types::{DnsConfigureReq, DnsMonStore, InterfaceStore, RouteManagerStore};
+
+/// How often the default gateways are looked up where network changes are not notified
+const ROAMING_CHECK_INTERVAL: u64 = 10;
+
+/// Sets the DNS of every interface again, as the system may have replaced it with the one handed
+/// out by the new network
+fn reapply_dns(
+    iface_store: &InterfaceStore,
+    dns_store: &DnsMonStore,
+    fwd_store: &ForwarderStore,
+    event_store: &EventStore,
+) {
+    let configs: Vec<(String, String, DnsConfigureReq)> = iface_store
+        .iface_states
+        .iter()
+        .filter_map(|x| {
+            let iface_state = x.value().lock().unwrap();
+            let platformid = iface_state.interface.get_platformid().ok()?;
+            Some((x.key().clone(), platformid, iface_state.dns.clone()?))
+        })
+        .collect();
+
+    for (if_id, platformid, dns) in configs {
+        let mut config = match dns.to_config() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        forward_dns(fwd_store, &platformid, &mut config);
+
+        match dns_store.dnsmon.lock().unwrap().set(&platformid, &config) {
+            Ok(_) => event_store.emit(DaemonEvent::DnsReapplied { interface: if_id }),
+            Err(e) => log::error!("Failed to set DNS of {} again: {}", if_id, e),
+        }
+    }
+}
+
+fn refresh_endpoints(iface_store: &InterfaceStore, event_store: &EventStore) {
+    for x in iface_store.iface_states.iter() {
+        let peers = peer::refresh_endpoints(&mut x.value().lock().unwrap());
+        if !peers.is_empty() {
+            event_store.emit(DaemonEvent::EndpointsRefreshed {
+                interface: x.key().clone(),
+                peers,
+            });
+        }
+    }
+}
+
+/// Looks up the default gateways, and brings the tunnels over to the new network if they changed
+fn check_once(
+    rms: &RouteManagerStore,
+    iface_store: &InterfaceStore,
+    dns_store: &DnsMonStore,
+    fwd_store: &ForwarderStore,
+    event_store: &EventStore,
+    gateways: &mut Option<Vec<String>>,
+) {
+    let mut current = match rms.route_manager.lock().unwrap().refresh_default_route() {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("Failed to look up default gateways: {}", e);
+            return;
+        }
+    };
+    current.sort();
+
+    let previous = match gateways.replace(current.clone()) {
+        // Nothing to compare against on the first check
+        None => return,
+        Some(x) if x == current => return,
+        Some(x) => x,
+    };
+    log::info!(
+        "Network changed: gateways [{}] -> [{}]",
+        previous.join(", "),
+        current.join(", ")
+    );
+    event_store.emit(DaemonEvent::NetworkChanged {
+        previous,
+        gateways: current.clone(),
+    });
+
+    // Off the network, there is nothing to reach yet
+    if current.is_empty() {
+        return;
+    }
+    reapply_dns(iface_store, dns_store, fwd_store, event_store);
+    // Host names may resolve differently from the new network
+    endpoint::resolve_once(rms, iface_store, event_store);
+    refresh_endpoints(iface_store, event_store);
+}
+
+/// Follows the physical network, e.g. a laptop moving from Wi-Fi to Ethernet. Runs on network
+/// change notifications where the platform has them, and every few seconds otherwise.
+pub(crate) fn monitor() -> AdHoc {
+    AdHoc::on_liftoff("Roaming monitor", |rocket| {
+        Box::pin(async move {
+            let rms = rocket.state::<RouteManagerStore>().unwrap().clone();
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let dns_store = rocket.state::<DnsMonStore>().unwrap().clone();
+            let fwd_store = rocket.state::<ForwarderStore>().unwrap().clone();
+            let event_store = rocket.state::<EventStore>().unwrap().clone();
+            let mut gateways = None;
+
+            periodic(
+                rocket,
+                "Roaming check",
+                Duration::from_secs(ROAMING_CHECK_INTERVAL),
+                true,
+                move || {
+                    check_once(
+                        &rms,
+                        &iface_store,
+                        &dns_store,
+                        &fwd_store,
+                        &event_store,
+                        &mut gateways,
+                    )
+                },
+            );
+        })
+    })
+}
@@ -17,14 +17,29 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use rocket::fairing::AdHoc;
 use rocket::serde::json::Json;
 use rocket::{http::Status, State};
 
 use crate::api::common::{ApiResponse, ApiResponseType};
 use crate::api::tokenauth::ApiKey;
-use wgctrl::platform_specific::common::PlatformRoute;
+use wgctrl::platform_specific::common::{
+    default_route_family, IpFamily, PlatformRoute, RouteDrift, RouteOptions,
+};
+
+use super::events::{DaemonEvent, EventStore};
+use super::firewall::FirewallStore;
+use super::periodic::periodic;
+use super::stats;
+use super::types::{IfaceState, InterfaceStore, RouteConfigurationMessage, RouteManagerStore};
 
-use super::types::RouteManagerStore;
+const LAN_REFRESH_INTERVAL: u64 = 10;
+pub(crate) const DEFAULT_RECONCILE_INTERVAL: u64 = 30;
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -81,3 +96,300 @@ pub(crate) async fn delete_bypass(
         ),
     }
 }
+
+/// Union of the allowed IPs, with host bits cleared the way WireGuard stores them
+fn allowed_ips_union<'a>(allowed_ips: impl Iterator<Item = &'a String>) -> BTreeSet<String> {
+    allowed_ips
+        .filter_map(|x| x.parse::<IpNet>().ok())
+        .map(|x| x.trunc().to_string())
+        .collect()
+}
+
+#[test]
+fn test_allowed_ips_union() {
+    let allowed_ips = vec![
+        "10.0.0.5/24".to_string(),
+        "10.0.0.0/24".to_string(),
+        "0.0.0.0/0".to_string(),
+        "fd00::1/64".to_string(),
+        "bogus".to_string(),
+    ];
+    let routes: Vec<String> = allowed_ips_union(allowed_ips.iter()).into_iter().collect();
+    assert_eq!(routes, vec!["0.0.0.0/0", "10.0.0.0/24", "fd00::/64"]);
+}
+
+/// Routes wanted by the interface, or `None` if it is not in `auto_routes` mode
+pub(crate) fn auto_routes(iface_state: &IfaceState) -> Option<BTreeSet<String>> {
+    if iface_state.iface_cfg.auto_routes != Some(true) {
+        return None;
+    }
+
+    // Suspended peers keep their routes so that their traffic does not leak out of the tunnel
+    Some(allowed_ips_union(
+        iface_state
+            .peer_cfgs
+            .values()
+            .flat_map(|x| x.allowed_ips.iter()),
+    ))
+}
+
+/// Whether a route of any interface, `auto` or added through the API, replaces the default route
+/// of `family`
+fn default_route_taken(rms: &RouteManagerStore, family: IpFamily) -> bool {
+    rms.route_store.iter().any(|x| {
+        x.value()
+            .keys()
+            .any(|cidr| default_route_family(cidr) == Some(family))
+    })
+}
+
+/// Adds and removes the `auto` routes of the interface to match `wanted`. Routes added through
+/// the API take precedence and are never touched. Must not be called with the interface locked.
+pub(crate) fn sync_auto_routes(
+    rms: &RouteManagerStore,
+    if_id: &str,
+    wanted: Option<BTreeSet<String>>,
+) {
+    let wanted = match wanted {
+        Some(x) => x,
+        None => return,
+    };
+
+    let mut rm = rms.route_manager.lock().unwrap();
+    let mut routemap = rms
+        .route_store
+        .entry(if_id.to_string())
+        .or_insert_with(HashMap::new);
+
+    let stale: Vec<String> = routemap
+        .values()
+        .filter(|x| x.auto && !wanted.contains(&x.cidr))
+        .map(|x| x.cidr.clone())
+        .collect();
+    let mut released = vec![];
+    for cidr in stale {
+        match rm.remove_route(if_id, &cidr) {
+            Ok(_) => {
+                routemap.remove(&cidr);
+                released.extend(default_route_family(&cidr));
+            }
+            Err(e) => log::warn!("Failed to remove route {} from {}: {}", cidr, if_id, e),
+        }
+    }
+
+    for cidr in wanted {
+        if routemap.contains_key(&cidr) {
+            continue;
+        }
+
+        // Same as a default route added through the API
+        if let Some(family) = default_route_family(&cidr) {
+            if let Err(e) = rm.remove_default_route(family) {
+                log::error!("Failed to take over default route for {}: {}", if_id, e);
+                continue;
+            }
+        }
+
+        match rm.add_route(if_id, &cidr, &RouteOptions::default()) {
+            Ok(_) => {
+                routemap.insert(cidr.clone(), RouteConfigurationMessage::auto(&cidr));
+            }
+            Err(e) => log::error!("Failed to add route {} to {}: {}", cidr, if_id, e),
+        }
+    }
+    drop(routemap);
+
+    for family in released {
+        if default_route_taken(rms, family) {
+            continue;
+        }
+        if let Err(e) = rm.restore_family_default_route(family) {
+            log::warn!("Failed to restore default route of {:?}: {}", family, e);
+        }
+    }
+}
+
+fn refresh_lan(
+    rms: &RouteManagerStore,
+    iface_store: &InterfaceStore,
+    fw_store: &FirewallStore,
+    bypassed: &mut Vec<String>,
+) {
+    if !fw_store.allow_lan() {
+        if bypassed.is_empty() {
+            return;
+        }
+        match rms.route_manager.lock().unwrap().clear_lan_bypass() {
+            Ok(_) => {
+                log::info!("Local networks no longer bypass the tunnels");
+                bypassed.clear();
+            }
+            Err(e) => log::error!("Failed to clear LAN bypass: {}", e),
+        }
+        return;
+    }
+
+    let tunnels: Vec<String> = iface_store
+        .iface_states
+        .iter()
+        .filter_map(|x| x.value().lock().unwrap().interface.get_platformid().ok())
+        .collect();
+
+    let mut rm = rms.route_manager.lock().unwrap();
+    match rm.refresh_lan_bypass(&tunnels) {
+        Ok(mut x) => {
+            x.sort();
+            if x != *bypassed {
+                log::info!("Local networks bypassing the tunnels: {}", x.join(", "));
+                *bypassed = x;
+            }
+        }
+        Err(e) => log::error!("Failed to refresh LAN bypass: {}", e),
+    }
+}
+
+/// Keeps the LAN bypass in line with the physical network and with the `allow_lan` setting of
+/// the firewall, so that routing and the kill switch agree on local networks
+pub(crate) fn lan_monitor() -> AdHoc {
+    AdHoc::on_liftoff("LAN bypass monitor", |rocket| {
+        Box::pin(async move {
+            let rms = rocket.state::<RouteManagerStore>().unwrap().clone();
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let fw_store = rocket.state::<FirewallStore>().unwrap().clone();
+            let mut bypassed = vec![];
+
+            periodic(
+                rocket,
+                "LAN bypass refresh",
+                Duration::from_secs(LAN_REFRESH_INTERVAL),
+                false,
+                move || refresh_lan(&rms, &iface_store, &fw_store, &mut bypassed),
+            );
+        })
+    })
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct DriftReport {
+    /// Time of the last check, unset until the first one
+    pub(crate) checked_at: Option<u64>,
+    #[serde(flatten)]
+    pub(crate) drift: RouteDrift,
+}
+
+/// Outcome of the last routing drift check
+#[derive(Clone, Default)]
+pub(crate) struct DriftStore {
+    report: Arc<Mutex<DriftReport>>,
+}
+
+#[get("/route/drift")]
+pub(crate) async fn get_drift(
+    _apikey: ApiKey,
+    drift_store: &State<DriftStore>,
+) -> ApiResponseType<DriftReport> {
+    let report = drift_store.report.lock().unwrap().clone();
+    (Status::Ok, ApiResponse::ok(report))
+}
+
+/// Adds back addresses set through the API which went missing. Others are only reported, as
+/// `set_ip` would remove them.
+fn reconcile_addresses(iface_store: &InterfaceStore, drift: &mut RouteDrift) {
+    for x in iface_store.iface_states.iter() {
+        let ifname = x.key();
+        let mut iface_state = x.value().lock().unwrap();
+        if iface_state.ips.is_empty() {
+            continue;
+        }
+        let current: Vec<IpNet> = match iface_state.interface.get_ip() {
+            Ok(x) => x.iter().filter_map(|x| x.parse().ok()).collect(),
+            Err(_) => continue,
+        };
+        let wanted: Vec<IpNet> = iface_state
+            .ips
+            .iter()
+            .filter_map(|x| x.parse().ok())
+            .collect();
+
+        for addr in current.iter().filter(|x| !wanted.contains(x)) {
+            drift.extra.push(format!("address {} dev {}", addr, ifname));
+        }
+
+        let missing: Vec<&IpNet> = wanted.iter().filter(|x| !current.contains(x)).collect();
+        if missing.is_empty() {
+            continue;
+        }
+        let entries = missing
+            .iter()
+            .map(|x| format!("address {} dev {}", x, ifname));
+        let ips: Vec<String> = current
+            .iter()
+            .chain(missing.iter().cloned())
+            .map(|x| x.to_string())
+            .collect();
+        match iface_state.interface.set_ip(&ips) {
+            Ok(_) => drift.restored.extend(entries),
+            Err(e) => {
+                log::debug!("Failed to restore addresses of {}: {}", ifname, e);
+                drift.missing.extend(entries);
+            }
+        }
+    }
+}
+
+fn reconcile_once(
+    rms: &RouteManagerStore,
+    iface_store: &InterfaceStore,
+    drift_store: &DriftStore,
+    event_store: &EventStore,
+) {
+    let mut drift = match rms.route_manager.lock().unwrap().reconcile() {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("Failed to check routing state: {}", e);
+            return;
+        }
+    };
+    reconcile_addresses(iface_store, &mut drift);
+    drift.extra.sort();
+
+    let mut report = drift_store.report.lock().unwrap();
+    // Extra entries stay around, so only report changes
+    if !drift.is_empty() && drift != report.drift {
+        log::warn!(
+            "Routing drift: restored [{}], missing [{}], extra [{}]",
+            drift.restored.join(", "),
+            drift.missing.join(", "),
+            drift.extra.join(", ")
+        );
+        event_store.emit(DaemonEvent::RouteDrift {
+            restored: drift.restored.clone(),
+            missing: drift.missing.clone(),
+            extra: drift.extra.clone(),
+        });
+    }
+    report.checked_at = Some(stats::now());
+    report.drift = drift;
+}
+
+/// Puts back routes, rules and addresses removed behind our back. Runs on network change notifications
+/// where the platform has them, and every `reconcile_interval` seconds otherwise.
+pub(crate) fn reconciler() -> AdHoc {
+    AdHoc::on_liftoff("Route reconciler", |rocket| {
+        Box::pin(async move {
+            let rms = rocket.state::<RouteManagerStore>().unwrap().clone();
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let drift_store = rocket.state::<DriftStore>().unwrap().clone();
+            let event_store = rocket.state::<EventStore>().unwrap().clone();
+
+            periodic(
+                rocket,
+                "Route reconcile",
+                Duration::from_secs(rms.reconcile_interval),
+                true,
+                move || reconcile_once(&rms, &iface_store, &drift_store, &event_store),
+            );
+        })
+    })
+}
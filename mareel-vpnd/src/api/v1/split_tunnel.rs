@@ -0,0 +1,115 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use rocket::serde::json::Json;
+use rocket::{http::Status, State};
+
+use crate::api::common::{ApiResponse, ApiResponseType};
+use crate::api::tokenauth::ApiKey;
+use wgctrl::platform_specific::common::PlatformSplitTunnel;
+
+use super::types::SplitTunnelStore;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct SplitTunnelPid {
+    pub pid: u32,
+}
+
+/// Moves the split tunnel rule ahead of the bypass rules again, after the interfaces changed
+pub(crate) fn reprioritize(sts: &SplitTunnelStore, bypass_priority: u32) {
+    let mut st = sts.split_tunnel.lock().unwrap();
+    if let Err(e) = st.set_rule_priority(bypass_priority.saturating_sub(1)) {
+        log::warn!("Failed to move split tunnel rule: {}", e);
+    }
+}
+
+#[post("/split-tunnel/pids", format = "json", data = "<pid>")]
+pub(crate) async fn add_pid(
+    _apikey: ApiKey,
+    sts: &State<SplitTunnelStore>,
+    pid: Json<SplitTunnelPid>,
+) -> ApiResponseType<String> {
+    let mut st = sts.split_tunnel.lock().unwrap();
+
+    match st.add_pid(pid.pid) {
+        Ok(_) => (Status::Ok, ApiResponse::ok("ok".to_string())),
+        Err(e) => (
+            Status::InternalServerError,
+            ApiResponse::err(-1, &e.to_string()),
+        ),
+    }
+}
+
+#[get("/split-tunnel/pids")]
+pub(crate) async fn get_pids(
+    _apikey: ApiKey,
+    sts: &State<SplitTunnelStore>,
+) -> ApiResponseType<Vec<u32>> {
+    let st = sts.split_tunnel.lock().unwrap();
+
+    match st.get_pids() {
+        Ok(x) => (Status::Ok, ApiResponse::ok(x)),
+        Err(e) => (
+            Status::InternalServerError,
+            ApiResponse::err(-1, &e.to_string()),
+        ),
+    }
+}
+
+#[delete("/split-tunnel/pids/<pid>")]
+pub(crate) async fn delete_pid(
+    _apikey: ApiKey,
+    sts: &State<SplitTunnelStore>,
+    pid: u32,
+) -> ApiResponseType<String> {
+    let mut st = sts.split_tunnel.lock().unwrap();
+
+    match st.get_pids() {
+        Ok(x) if !x.contains(&pid) => {
+            return (Status::NotFound, ApiResponse::err(-1, "Not found"));
+        }
+        _ => {}
+    }
+
+    match st.remove_pid(pid) {
+        Ok(_) => (Status::Ok, ApiResponse::ok("ok".to_string())),
+        Err(e) => (
+            Status::InternalServerError,
+            ApiResponse::err(-1, &e.to_string()),
+        ),
+    }
+}
+
+/// Takes every process out of the split tunnel and tears down its rules
+#[delete("/split-tunnel/pids")]
+pub(crate) async fn delete_pids(
+    _apikey: ApiKey,
+    sts: &State<SplitTunnelStore>,
+) -> ApiResponseType<String> {
+    let mut st = sts.split_tunnel.lock().unwrap();
+
+    match st.reset() {
+        Ok(_) => (Status::Ok, ApiResponse::ok("ok".to_string())),
+        Err(e) => (
+            Status::InternalServerError,
+            ApiResponse::err(-1, &e.to_string()),
+        ),
+    }
+}
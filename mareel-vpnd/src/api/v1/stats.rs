@@ -0,0 +1,227 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::fairing::AdHoc;
+use rocket::serde;
+
+use super::events::EventStore;
+use super::periodic::periodic;
+use super::quota::{self, UsageStore};
+use super::types::{InterfaceStore, StatsStore};
+
+pub(crate) const DEFAULT_SAMPLE_INTERVAL: u64 = 10;
+pub(crate) const DEFAULT_RETENTION: u64 = 3600;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct RateSample {
+    pub(crate) timestamp: u64,
+    pub(crate) rx_bps: f64,
+    pub(crate) tx_bps: f64,
+}
+
+/// Ring buffer of traffic rates for a single peer
+pub(crate) struct PeerHistory {
+    last: Option<(u64, u64, u64)>,
+    capacity: usize,
+    pub(crate) rates: VecDeque<RateSample>,
+}
+
+impl PeerHistory {
+    pub(crate) fn new(capacity: usize) -> Self {
+        PeerHistory {
+            last: None,
+            capacity,
+            rates: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Feeds raw counters sampled at `timestamp`. Counter going backwards means that the peer has
+    /// been re-added, so the new value is taken as the amount transferred since then.
+    pub(crate) fn push(&mut self, timestamp: u64, rx_bytes: u64, tx_bytes: u64) {
+        if let Some((last_ts, last_rx, last_tx)) = self.last {
+            let elapsed = timestamp.saturating_sub(last_ts);
+            if elapsed > 0 {
+                let rx_delta = rx_bytes.checked_sub(last_rx).unwrap_or(rx_bytes);
+                let tx_delta = tx_bytes.checked_sub(last_tx).unwrap_or(tx_bytes);
+
+                if self.rates.len() >= self.capacity {
+                    self.rates.pop_front();
+                }
+                self.rates.push_back(RateSample {
+                    timestamp,
+                    rx_bps: rx_delta as f64 / elapsed as f64,
+                    tx_bps: tx_delta as f64 / elapsed as f64,
+                });
+            }
+        }
+
+        self.last = Some((timestamp, rx_bytes, tx_bytes));
+    }
+
+    /// Returns samples taken within `window` seconds from `now`
+    pub(crate) fn window(&self, now: u64, window: u64) -> Vec<RateSample> {
+        let since = now.saturating_sub(window);
+        self.rates
+            .iter()
+            .filter(|x| x.timestamp >= since)
+            .cloned()
+            .collect()
+    }
+}
+
+#[test]
+fn test_peer_history_rate() {
+    let mut hist = PeerHistory::new(2);
+    hist.push(100, 0, 0);
+    hist.push(110, 1000, 500);
+    hist.push(120, 3000, 500);
+
+    assert_eq!(hist.rates.len(), 2);
+    assert_eq!(hist.rates[0].rx_bps, 100.0);
+    assert_eq!(hist.rates[0].tx_bps, 50.0);
+    assert_eq!(hist.rates[1].rx_bps, 200.0);
+    assert_eq!(hist.rates[1].tx_bps, 0.0);
+
+    // Oldest sample is dropped once capacity is reached
+    hist.push(130, 3000, 500);
+    assert_eq!(hist.rates.len(), 2);
+    assert_eq!(hist.rates[0].timestamp, 120);
+    assert_eq!(hist.window(130, 5).len(), 1);
+}
+
+#[test]
+fn test_peer_history_counter_reset() {
+    let mut hist = PeerHistory::new(10);
+    hist.push(100, 5000, 5000);
+    hist.push(110, 100, 200);
+
+    assert_eq!(hist.rates[0].rx_bps, 10.0);
+    assert_eq!(hist.rates[0].tx_bps, 20.0);
+}
+
+/// Parses window strings like `90`, `90s`, `15m`, `1h` or `7d` into seconds
+pub(crate) fn parse_window(window: &str) -> Option<u64> {
+    let window = window.trim();
+    let (num, unit) = match window.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => window.split_at(idx),
+        None => (window, "s"),
+    };
+
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    num.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[test]
+fn test_parse_window() {
+    assert_eq!(parse_window("90"), Some(90));
+    assert_eq!(parse_window("90s"), Some(90));
+    assert_eq!(parse_window("15m"), Some(900));
+    assert_eq!(parse_window("1h"), Some(3600));
+    assert_eq!(parse_window("7d"), Some(604800));
+    assert_eq!(parse_window("1w"), None);
+    assert_eq!(parse_window("h"), None);
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+fn sample_once(
+    iface_store: &InterfaceStore,
+    stats_store: &StatsStore,
+    usage_store: &UsageStore,
+    event_store: &EventStore,
+) {
+    let timestamp = now();
+    let capacity = (stats_store.retention / stats_store.interval).max(1) as usize;
+    let mut live = HashSet::new();
+
+    for iface in iface_store.iface_states.iter() {
+        let if_id = iface.key();
+        let mut iface_state = iface.lock().unwrap();
+        for pubk in iface_state.peer_cfgs.keys() {
+            live.insert((if_id.clone(), pubk.clone()));
+        }
+
+        let trafficstat = match iface_state.interface.get_trafficstats() {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Failed to sample traffic stats of {}: {}", if_id, e);
+                continue;
+            }
+        };
+
+        for stat in trafficstat.iter() {
+            if !iface_state.peer_cfgs.contains_key(&stat.pubkey) {
+                continue;
+            }
+
+            stats_store
+                .history
+                .entry((if_id.clone(), stat.pubkey.clone()))
+                .or_insert_with(|| PeerHistory::new(capacity))
+                .push(timestamp, stat.rx_bytes, stat.tx_bytes);
+        }
+
+        quota::enforce(
+            if_id,
+            &mut iface_state,
+            &trafficstat,
+            event_store,
+            timestamp,
+        );
+        usage_store.update(if_id, &iface_state);
+    }
+
+    // Forget peers which are gone
+    stats_store.history.retain(|k, _| live.contains(k));
+}
+
+pub(crate) fn sampler() -> AdHoc {
+    AdHoc::on_liftoff("Statistics sampler", |rocket| {
+        Box::pin(async move {
+            let iface_store = rocket.state::<InterfaceStore>().unwrap().clone();
+            let stats_store = rocket.state::<StatsStore>().unwrap().clone();
+            let usage_store = rocket.state::<UsageStore>().unwrap().clone();
+            let event_store = rocket.state::<EventStore>().unwrap().clone();
+
+            periodic(
+                rocket,
+                "Statistics sampling",
+                Duration::from_secs(stats_store.interval),
+                false,
+                move || sample_once(&iface_store, &stats_store, &usage_store, &event_store),
+            );
+        })
+    })
+}
@@ -19,14 +19,22 @@
 
 use std::{
     collections::HashMap,
+    net::IpAddr,
     sync::{Arc, Mutex, RwLock},
 };
 
 use dashmap::{DashMap, DashSet};
-use prometheus::Counter;
 
-use wgctrl::platform_specific::common::{DnsMonitor, PlatformInterface};
-use wgctrl::platform_specific::Route;
+use wgctrl::platform_specific::common::{
+    DnsConfig, DnsMonitor, PeerTrafficStat, PlatformInterface, RouteOptions, RoutePolicy,
+    RouteTable, WgPeerCfg,
+};
+use wgctrl::platform_specific::{Route, SplitTunnel};
+
+use crate::config::DnsLeakProtection;
+
+use super::quota::{PeerQuota, PeerUsage};
+use super::stats::PeerHistory;
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -38,6 +46,16 @@ pub(crate) struct InterfaceConfig {
     pub(crate) public_key: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) listen_port: Option<u16>,
+    /// Routing table, as in wg-quick's `Table=`: `auto`, `main`, `off` or a table number
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) table: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) fwmark: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rule_priority: Option<u32>,
+    /// Keep the routes of the interface in sync with the allowed IPs of its peers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) auto_routes: Option<bool>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -48,6 +66,18 @@ pub(crate) struct PeerConfig {
     pub(crate) psk: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) endpoint: Option<String>,
+    /// Endpoints to fail over between, in order of preference. `endpoint` is the one in use.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) endpoints: Vec<String>,
+    /// Time without a handshake after which the next of `endpoints` is tried, e.g. `3m`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) failover_timeout: Option<String>,
+    /// When the peer was moved to `endpoint`
+    #[serde(skip)]
+    pub(crate) endpoint_since: u64,
+    /// Address `endpoint` resolved to
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub(crate) resolved_endpoint: Option<String>,
     pub(crate) allowed_ips: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) keepalive: Option<u16>,
@@ -57,12 +87,43 @@ pub(crate) struct PeerConfig {
     pub(crate) autoalloc_v4: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) autoalloc_v6: Option<u64>,
+    /// Unix timestamp at which the peer is removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<u64>,
+    /// Peer is removed after going this long without a handshake, e.g. `12h`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) idle_timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) quota: Option<PeerQuota>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub(crate) usage: Option<PeerUsage>,
+    /// Removed from the interface after running out of quota
+    #[serde(skip_deserializing, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) suspended: bool,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub(crate) stats: Option<PeerTrafficStat>,
+}
+
+impl PeerConfig {
+    pub(crate) fn wg_peer_cfg(&self) -> WgPeerCfg {
+        WgPeerCfg {
+            pubkey: self.pubkey.clone(),
+            psk: None,
+            endpoint: self.resolved_endpoint.clone(),
+            allowed_ips: self.allowed_ips.clone(),
+            keep_alive: self.keepalive,
+        }
+    }
 }
 
 pub(crate) struct IfaceState {
     pub interface: Box<dyn PlatformInterface + Send>,
     pub iface_cfg: InterfaceConfig,
-    pub peer_cfgs: HashMap<String, (PeerConfig, Counter, Counter)>,
+    pub peer_cfgs: HashMap<String, PeerConfig>,
+    /// Addresses last set through the API
+    pub ips: Vec<String>,
+    /// DNS last set through the API
+    pub dns: Option<DnsConfigureReq>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -77,28 +138,231 @@ pub(crate) struct IpConfigurationMessage {
     pub(crate) ipaddr: Vec<String>,
 }
 
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct DnsConfigureReq {
+    pub dns: Vec<String>,
+    /// Overrides `dns_leak_protection` from the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leak_protection: Option<DnsLeakProtection>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_domains: Vec<String>,
+    /// Only names under these are resolved through `dns`, e.g. `~corp.example`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routing_domains: Vec<String>,
+}
+
+/// Strips the `~` of routing domains and the trailing dot of absolute names
+pub(crate) fn parse_domain(domain: &str) -> Result<String, String> {
+    let name = domain.trim().trim_start_matches('~').trim_end_matches('.');
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    match valid {
+        true => Ok(name.to_ascii_lowercase()),
+        false => Err(format!("Invalid domain: {}", domain)),
+    }
+}
+
+impl DnsConfigureReq {
+    pub(crate) fn to_config(&self) -> Result<DnsConfig, String> {
+        let servers = self
+            .dns
+            .iter()
+            .map(|x| x.parse().map_err(|_| format!("Invalid address: {}", x)))
+            .collect::<Result<Vec<IpAddr>, String>>()?;
+        let search_domains = self
+            .search_domains
+            .iter()
+            .map(|x| parse_domain(x))
+            .collect::<Result<Vec<String>, String>>()?;
+        let routing_domains = self
+            .routing_domains
+            .iter()
+            .map(|x| parse_domain(x))
+            .collect::<Result<Vec<String>, String>>()?;
+
+        Ok(DnsConfig {
+            servers,
+            search_domains,
+            routing_domains,
+        })
+    }
+
+    /// Leak protection asked for. Split DNS needs the local resolver, so it is off by default.
+    pub(crate) fn leak_protection(&self) -> Option<DnsLeakProtection> {
+        match self.routing_domains.is_empty() {
+            true => self.leak_protection,
+            false => self.leak_protection.or(Some(DnsLeakProtection::Off)),
+        }
+    }
+}
+
+#[test]
+fn test_dns_configure_req() {
+    let mut req = DnsConfigureReq {
+        dns: vec!["10.0.0.1".to_string()],
+        leak_protection: None,
+        search_domains: vec!["Corp.Example.".to_string()],
+        routing_domains: vec!["~corp.example".to_string(), "lab.example".to_string()],
+    };
+    let config = req.to_config().unwrap();
+    assert_eq!(config.servers, vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+    assert_eq!(config.search_domains, vec!["corp.example"]);
+    assert_eq!(config.routing_domains, vec!["corp.example", "lab.example"]);
+    assert!(matches!(
+        req.leak_protection(),
+        Some(DnsLeakProtection::Off)
+    ));
+
+    req.routing_domains = vec!["~".to_string()];
+    assert_eq!(req.to_config().unwrap_err(), "Invalid domain: ~");
+    req.routing_domains = vec![];
+    req.search_domains = vec!["bad domain".to_string()];
+    assert!(req.to_config().is_err());
+    req.search_domains = vec![];
+    req.dns = vec!["10.0.0.256".to_string()];
+    assert_eq!(req.to_config().unwrap_err(), "Invalid address: 10.0.0.256");
+    assert!(req.leak_protection().is_none());
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(crate = "rocket::serde")]
 pub(crate) struct RouteConfigurationMessage {
     pub(crate) cidr: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) metric: Option<u32>,
+    /// Gateway to route through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) via: Option<String>,
+    /// Preferred source address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) src: Option<String>,
+    /// `main` or a table number, overriding the table of the interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) table: Option<String>,
+    /// Added by `auto_routes` rather than through the API
+    #[serde(skip_deserializing, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) auto: bool,
+}
+
+impl RouteConfigurationMessage {
+    pub(crate) fn auto(cidr: &str) -> Self {
+        RouteConfigurationMessage {
+            cidr: cidr.to_string(),
+            metric: None,
+            via: None,
+            src: None,
+            table: None,
+            auto: true,
+        }
+    }
+
+    /// Parses and validates the route options, listing every invalid field
+    pub(crate) fn options(&self) -> Result<RouteOptions, Vec<String>> {
+        let mut errors = vec![];
+        let mut parse_addr = |name: &str, addr: &Option<String>| match addr {
+            Some(x) => match x.parse::<IpAddr>() {
+                Ok(x) => Some(x),
+                Err(_) => {
+                    errors.push(format!("Invalid {} address: {}", name, x));
+                    None
+                }
+            },
+            None => None,
+        };
+        let via = parse_addr("via", &self.via);
+        let src = parse_addr("src", &self.src);
+
+        let table = match self.table.as_deref() {
+            Some("auto") | None => None,
+            Some(x) => match x.parse::<RouteTable>() {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    errors.push(e.to_string());
+                    None
+                }
+            },
+        };
+
+        let opts = RouteOptions {
+            metric: self.metric,
+            via,
+            src,
+            table,
+        };
+        if let Err(e) = opts.validate(&self.cidr) {
+            errors.push(e.to_string());
+        }
+
+        match errors.is_empty() {
+            true => Ok(opts),
+            false => Err(errors),
+        }
+    }
 }
 
+#[test]
+fn test_route_options() {
+    let mut route = RouteConfigurationMessage {
+        cidr: "10.1.0.0/16".to_string(),
+        metric: Some(100),
+        via: Some("10.0.0.1".to_string()),
+        src: None,
+        table: Some("main".to_string()),
+        auto: false,
+    };
+    let opts = route.options().unwrap();
+    assert_eq!(opts.metric, Some(100));
+    assert_eq!(opts.table, Some(RouteTable::Main));
+
+    route.via = Some("bogus".to_string());
+    route.src = Some("fd00::1".to_string());
+    route.table = Some("local".to_string());
+    assert_eq!(route.options().unwrap_err().len(), 3);
+}
+
+#[derive(Clone)]
 pub(crate) struct InterfaceStore {
-    pub(crate) iface_states: DashMap<String, Arc<Mutex<IfaceState>>>,
+    pub(crate) iface_states: Arc<DashMap<String, Arc<Mutex<IfaceState>>>>,
 }
 
+#[derive(Clone)]
 pub(crate) struct IpStore {
-    pub(crate) v4: DashSet<u32>,
-    pub(crate) v4_last_count: RwLock<u32>,
-    pub(crate) v6: DashSet<u64>,
-    pub(crate) v6_last_count: RwLock<u64>,
+    pub(crate) v4: Arc<DashSet<u32>>,
+    pub(crate) v4_last_count: Arc<RwLock<u32>>,
+    pub(crate) v6: Arc<DashSet<u64>>,
+    pub(crate) v6_last_count: Arc<RwLock<u64>>,
 }
 
+#[derive(Clone)]
 pub(crate) struct RouteManagerStore {
-    pub route_manager: Mutex<Box<Route>>,
-    pub route_store: DashMap<String, HashMap<String, bool>>,
+    pub route_manager: Arc<Mutex<Box<Route>>>,
+    pub route_store: Arc<DashMap<String, HashMap<String, RouteConfigurationMessage>>>,
+    /// Routing policy of interfaces which do not specify their own
+    pub defaults: RoutePolicy,
+    /// Seconds between drift checks
+    pub reconcile_interval: u64,
+}
+
+pub(crate) struct SplitTunnelStore {
+    pub split_tunnel: Arc<Mutex<SplitTunnel>>,
 }
 
+#[derive(Clone)]
 pub(crate) struct DnsMonStore {
     pub dnsmon: Arc<Mutex<DnsMonitor>>,
 }
+
+#[derive(Clone)]
+pub(crate) struct StatsStore {
+    pub interval: u64,
+    pub retention: u64,
+    pub history: Arc<DashMap<(String, String), PeerHistory>>,
+}
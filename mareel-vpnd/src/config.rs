@@ -19,34 +19,151 @@
 
 use std::fs;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use wgctrl::platform_specific::common::{DnsBackend, Instance, DEFAULT_INSTANCE};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Config {
     pub api: Api,
     pub wireguard: Option<WireguardConfig>,
     pub cnc: Option<CnC>,
+    pub stats: Option<StatsConfig>,
+    pub expiry: Option<ExpiryConfig>,
+    pub routing: Option<RoutingConfig>,
+    pub firewall: Option<FirewallConfig>,
+    pub dns: Option<DnsConfig>,
+    pub instance: Option<InstanceConfig>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Api {
     pub listen: Option<String>,
     pub port: Option<u16>,
     pub apikey: String,
 }
 
-#[derive(Deserialize)]
+/// Lets several daemons, or a daemon and another VPN client, share a system
+#[derive(Deserialize, Clone)]
+pub struct InstanceConfig {
+    /// Goes into the names of DNS records and backups, defaults to `mareel`
+    pub name: Option<String>,
+    /// Defaults to the platform state directory, or a directory named after the instance in it
+    pub state_dir: Option<String>,
+    /// Where backups of system files go, defaults to next to the files
+    pub backup_dir: Option<String>,
+}
+
+impl InstanceConfig {
+    pub fn instance(&self) -> Result<Instance, String> {
+        let name = self.name.as_deref().unwrap_or(DEFAULT_INSTANCE);
+        Instance::new(name, self.backup_dir.as_ref().map(|x| x.into())).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Deserialize, Clone)]
 pub struct WireguardConfig {
     pub userspace: Option<String>,
     pub use_kernel: Option<bool>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct CnC {
     pub cnc_url: String,
     pub max_attempts: Option<usize>,
 }
 
+#[derive(Deserialize, Clone)]
+pub struct StatsConfig {
+    /// Traffic sampling interval in seconds
+    pub sample_interval: Option<u64>,
+    /// How long traffic history is kept, in seconds
+    pub retention: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ExpiryConfig {
+    /// Seconds the expiry schedule kept across a restart waits for its peers to be pushed
+    /// again before it is dropped, defaults to 600
+    pub restore_grace: Option<u64>,
+}
+
+/// Defaults for interfaces which do not specify their own routing policy
+#[derive(Deserialize, Clone)]
+pub struct RoutingConfig {
+    /// `auto`, `main`, `off` or a table number
+    pub table: Option<String>,
+    pub fwmark: Option<u32>,
+    pub rule_priority: Option<u32>,
+    /// Seconds between checks of the routing state when no change is notified, defaults to 30
+    pub reconcile_interval: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FirewallConfig {
+    /// Block all traffic when an interface carrying a default route fails, defaults to true
+    pub block_on_failure: Option<bool>,
+    /// Keep local networks reachable, both around the tunnels and through the kill switch.
+    /// Defaults to false, and can be changed through `PUT /firewall`.
+    pub allow_lan: Option<bool>,
+    /// Default handling of DNS queries bypassing the resolvers set through the API
+    pub dns_leak_protection: Option<DnsLeakProtection>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsLeakProtection {
+    Block,
+    Redirect,
+    Off,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DnsConfig {
+    /// `auto`, `systemd-resolved`, `network-manager`, `resolvconf` or `static-file`
+    pub backend: Option<String>,
+    /// Backends tried in order when `backend` is not usable, defaults to none
+    pub fallback: Option<Vec<String>>,
+    /// Answer DNS from the daemon and point the system at it, off unless set
+    pub forwarder: Option<ForwarderConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ForwarderConfig {
+    /// Defaults to 127.0.0.1:53, the port can't be changed
+    pub listen: Option<String>,
+    /// Answers kept, 0 disables the cache. Defaults to 1024.
+    pub cache_size: Option<usize>,
+    pub rules: Option<Vec<ForwarderRuleConfig>>,
+}
+
+/// Names under `domain` are asked to `servers` rather than to the tunnel DNS servers
+#[derive(Deserialize, Clone)]
+pub struct ForwarderRuleConfig {
+    pub domain: String,
+    /// `ip` or `ip:port`
+    pub servers: Vec<String>,
+}
+
+impl DnsConfig {
+    /// Backends to try, in order. Only Linux has a choice.
+    pub fn backends(&self) -> Result<Vec<DnsBackend>, String> {
+        let mut backends = match self.backend.as_deref() {
+            None | Some("auto") => DnsBackend::AUTO.to_vec(),
+            Some(x) => vec![x.parse::<DnsBackend>().map_err(|e| e.to_string())?],
+        };
+        for x in self.fallback.iter().flatten() {
+            let backend = match x.as_str() {
+                "auto" => return Err("auto can't be used as a fallback".to_string()),
+                _ => x.parse::<DnsBackend>().map_err(|e| e.to_string())?,
+            };
+            if !backends.contains(&backend) {
+                backends.push(backend);
+            }
+        }
+        Ok(backends)
+    }
+}
+
 const WG_USERSPACE_IMPL: &str = "./boringtun";
 
 fn get_wgpath() -> String {
@@ -78,6 +195,12 @@ fn get_default_config() -> Config {
             use_kernel: Some(platform_default_use_wgkernel()),
         }),
         cnc: None,
+        stats: None,
+        expiry: None,
+        routing: None,
+        firewall: None,
+        dns: None,
+        instance: None,
     }
 }
 
@@ -140,4 +263,136 @@ mod test {
 
         assert_eq!(res.cnc.unwrap().cnc_url, "https://example.com");
     }
+
+    #[test]
+    fn test_stats_config() {
+        let res = super::parse_toml(
+            r##"
+        [api]
+        apikey = "crowbar"
+        [stats]
+        sample_interval = 5
+        "##,
+        );
+
+        let stats = res.stats.unwrap();
+        assert_eq!(stats.sample_interval, Some(5));
+        assert_eq!(stats.retention, None);
+    }
+
+    #[test]
+    fn test_routing_config() {
+        let res = super::parse_toml(
+            r##"
+        [api]
+        apikey = "crowbar"
+        [routing]
+        table = "main"
+        rule_priority = 1000
+        reconcile_interval = 60
+        "##,
+        );
+
+        let routing = res.routing.unwrap();
+        assert_eq!(routing.table.as_deref(), Some("main"));
+        assert_eq!(routing.fwmark, None);
+        assert_eq!(routing.rule_priority, Some(1000));
+        assert_eq!(routing.reconcile_interval, Some(60));
+    }
+
+    #[test]
+    fn test_firewall_config() {
+        let res = super::parse_toml(
+            r##"
+        [api]
+        apikey = "crowbar"
+        [firewall]
+        block_on_failure = false
+        dns_leak_protection = "redirect"
+        "##,
+        );
+
+        let firewall = res.firewall.unwrap();
+        assert_eq!(firewall.block_on_failure, Some(false));
+        assert_eq!(firewall.allow_lan, None);
+        assert_eq!(
+            firewall.dns_leak_protection,
+            Some(super::DnsLeakProtection::Redirect)
+        );
+    }
+
+    #[test]
+    fn test_dns_config() {
+        use wgctrl::platform_specific::common::DnsBackend;
+
+        let res = super::parse_toml(
+            r##"
+        [api]
+        apikey = "crowbar"
+        [dns]
+        backend = "resolvconf"
+        fallback = ["static-file", "resolvconf"]
+        "##,
+        );
+
+        let mut dns = res.dns.unwrap();
+        assert_eq!(
+            dns.backends().unwrap(),
+            vec![DnsBackend::Resolvconf, DnsBackend::StaticFile]
+        );
+
+        dns.backend = Some("auto".to_string());
+        dns.fallback = None;
+        assert_eq!(dns.backends().unwrap(), DnsBackend::AUTO.to_vec());
+
+        dns.fallback = Some(vec!["auto".to_string()]);
+        assert!(dns.backends().is_err());
+        dns.backend = Some("systemd".to_string());
+        dns.fallback = None;
+        assert!(dns.backends().is_err());
+        assert!(dns.forwarder.is_none());
+
+        let res = super::parse_toml(
+            r##"
+        [api]
+        apikey = "crowbar"
+        [dns.forwarder]
+        cache_size = 0
+        [[dns.forwarder.rules]]
+        domain = "corp.example"
+        servers = ["10.1.0.53"]
+        "##,
+        );
+
+        let forwarder = res.dns.unwrap().forwarder.unwrap();
+        assert_eq!(forwarder.listen, None);
+        assert_eq!(forwarder.cache_size, Some(0));
+        let rules = forwarder.rules.unwrap();
+        assert_eq!(rules[0].domain, "corp.example");
+        assert_eq!(rules[0].servers, vec!["10.1.0.53".to_string()]);
+    }
+
+    #[test]
+    fn test_instance_config() {
+        let res = super::parse_toml(
+            r##"
+        [api]
+        apikey = "crowbar"
+        [instance]
+        name = "office"
+        backup_dir = "/var/lib/office"
+        "##,
+        );
+
+        let mut cfg = res.instance.unwrap();
+        assert_eq!(cfg.state_dir, None);
+        let instance = cfg.instance().unwrap();
+        assert_eq!(instance.name, "office");
+        assert_eq!(instance.backup_dir, Some("/var/lib/office".into()));
+
+        cfg.name = None;
+        assert_eq!(cfg.instance().unwrap().name, "mareel");
+        cfg.name = Some("office/2".to_string());
+        assert!(cfg.instance().is_err());
+    }
 }
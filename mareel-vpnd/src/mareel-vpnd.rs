@@ -78,10 +78,7 @@ pub(crate) async fn launch(
 
     rocket::custom(cfg)
         // TODO: FIXME
-        .attach(api::stage(
-            &daemon_cfg.api.apikey,
-            Arc::clone(&PROM_REGISTRY),
-        ))
+        .attach(api::stage(daemon_cfg, Arc::clone(&PROM_REGISTRY)))
         .attach(AdHoc::on_liftoff("Shutdown", move |rocket| {
             Box::pin(async move {
                 let shutdown = rocket.shutdown();
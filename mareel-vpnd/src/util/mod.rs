@@ -17,5 +17,6 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+pub(crate) mod state;
 pub(crate) mod svcman;
 pub(crate) mod wakeup;
@@ -0,0 +1,85 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use rocket::serde::json;
+use rocket::serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(target_os = "linux")]
+const DEFAULT_STATE_DIR: &str = "/var/lib/mareel-vpnd";
+#[cfg(target_os = "macos")]
+const DEFAULT_STATE_DIR: &str = "/Library/Application Support/mareel-vpnd";
+#[cfg(target_os = "windows")]
+const DEFAULT_STATE_DIR: &str = "C:\\ProgramData\\mareel-vpnd";
+
+lazy_static! {
+    static ref STATE_DIR: RwLock<PathBuf> = RwLock::new(PathBuf::from(DEFAULT_STATE_DIR));
+}
+
+/// Uses `dir`, or else a directory of its own for a named instance, so that instances don't
+/// share state
+pub(crate) fn init(dir: Option<&str>, instance: Option<&str>) {
+    let dir = match (dir, instance) {
+        (Some(x), _) => PathBuf::from(x),
+        (None, Some(x)) => PathBuf::from(DEFAULT_STATE_DIR).join(x),
+        (None, None) => PathBuf::from(DEFAULT_STATE_DIR),
+    };
+    *STATE_DIR.write().unwrap() = dir;
+}
+
+/// Directory where the daemon keeps state which has to survive restarts
+pub(crate) fn state_dir() -> PathBuf {
+    STATE_DIR.read().unwrap().clone()
+}
+
+/// Loads `name` from the state directory. Missing or unreadable state is treated as empty.
+pub(crate) fn load<T: DeserializeOwned>(name: &str) -> Option<T> {
+    let path = state_dir().join(name);
+    let data = match fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            log::warn!("Failed to read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    match json::from_str(&data) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            log::warn!("Ignoring corrupted state file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Atomically replaces `name` in the state directory
+pub(crate) fn save<T: Serialize>(name: &str, value: &T) -> io::Result<()> {
+    let dir = state_dir();
+    fs::create_dir_all(&dir)?;
+
+    let data = json::to_string(value).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let tmp = dir.join(format!("{}.tmp", name));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, dir.join(name))
+}
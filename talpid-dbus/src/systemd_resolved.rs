@@ -583,13 +583,18 @@ impl AsyncHandle {
     pub async fn set_domains(
         &self,
         interface_index: u32,
-        domains: &[(&'static str, bool)],
+        domains: Vec<(String, bool)>,
     ) -> Result<()> {
         let interface = self.dbus_interface.clone();
-        let domains = domains.to_vec();
-        tokio::task::spawn_blocking(move || interface.set_domains(interface_index, &domains))
-            .await
-            .map_err(Error::AsyncTaskError)?
+        tokio::task::spawn_blocking(move || {
+            let domains = domains
+                .iter()
+                .map(|(domain, routing_only)| (domain.as_str(), *routing_only))
+                .collect::<Vec<_>>();
+            interface.set_domains(interface_index, &domains)
+        })
+        .await
+        .map_err(Error::AsyncTaskError)?
     }
 
     pub async fn revert_link(&self, state: DnsState) -> Result<()> {
@@ -20,5 +20,7 @@
 mod error;
 pub mod platform_specific;
 
+pub use error::VpnctrlError;
+
 #[cfg(target_os = "linux")]
 mod netlink;
@@ -27,6 +27,7 @@ use ipnetwork::IpNetwork;
 use netlink_packet_core::{
     NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST,
 };
+use netlink_packet_generic::GenlMessage;
 
 use netlink_packet_route::address;
 use netlink_packet_route::constants::*;
@@ -36,11 +37,22 @@ use netlink_packet_route::{
     route, rule, AddressHeader, AddressMessage, LinkHeader, LinkMessage, RouteHeader, RouteMessage,
     RtnlMessage, RuleHeader, RuleMessage, RTN_UNICAST, RT_SCOPE_LINK,
 };
+use netlink_packet_wireguard::nlas::{WgDeviceAttrs, WgPeerAttrs};
+use netlink_packet_wireguard::{Wireguard, WireguardCmd};
+use netlink_request::netlink_request_genl;
 use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
-use std::{io, net::IpAddr};
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
 use wireguard_control::InterfaceName;
 
-fn if_nametoindex(interface: &InterfaceName) -> Result<u32, io::Error> {
+use crate::platform_specific::common::RouteOptions;
+
+pub fn if_nametoindex(interface: &InterfaceName) -> Result<u32, io::Error> {
     match unsafe { libc::if_nametoindex(interface.as_ptr()) } {
         0 => Err(io::Error::new(
             io::ErrorKind::NotFound,
@@ -127,8 +139,7 @@ pub fn set_down(interface: &InterfaceName) -> Result<(), io::Error> {
     Ok(())
 }
 
-pub fn set_addr(interface: &InterfaceName, addr: IpNetwork) -> Result<(), io::Error> {
-    let index = if_nametoindex(interface)?;
+fn addr_message(index: u32, addr: IpNetwork) -> AddressMessage {
     let (family, nlas) = match addr {
         IpNetwork::V4(network) => {
             let addr_bytes = network.ip().octets().to_vec();
@@ -145,7 +156,7 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNetwork) -> Result<(), io::Er
             vec![address::Nla::Address(network.ip().octets().to_vec())],
         ),
     };
-    let message = AddressMessage {
+    AddressMessage {
         header: AddressHeader {
             index,
             family,
@@ -154,20 +165,70 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNetwork) -> Result<(), io::Er
             ..Default::default()
         },
         nlas,
-    };
+    }
+}
+
+pub fn set_addr(interface: &InterfaceName, addr: IpNetwork) -> Result<(), io::Error> {
+    let index = if_nametoindex(interface)?;
     netlink_call(
-        RtnlMessage::NewAddress(message),
+        RtnlMessage::NewAddress(addr_message(index, addr)),
         Some(NLM_F_REQUEST | NLM_F_ACK | NLM_F_REPLACE | NLM_F_CREATE),
     )?;
     Ok(())
 }
 
-pub fn add_rule(fwmark: u32, table: u32, prio: u32) -> Result<bool, io::Error> {
-    let message = RuleMessage {
+pub fn del_addr(interface: &InterfaceName, addr: IpNetwork) -> Result<bool, io::Error> {
+    let index = if_nametoindex(interface)?;
+    match netlink_call(
+        RtnlMessage::DelAddress(addr_message(index, addr)),
+        Some(NLM_F_REQUEST | NLM_F_ACK),
+    ) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EADDRNOTAVAIL) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Addresses of `interface`, along with their prefix length
+pub fn get_addrs(interface: &InterfaceName) -> Result<Vec<IpNetwork>, io::Error> {
+    let index = if_nametoindex(interface)?;
+    let addr_responses = netlink_call(
+        RtnlMessage::GetAddress(AddressMessage::default()),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+
+    Ok(addr_responses
+        .into_iter()
+        .filter_map(|response| match response {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(RtnlMessage::NewAddress(addr)),
+                ..
+            } => Some(addr),
+            _ => None,
+        })
+        .filter(|addr| addr.header.index == index)
+        .filter_map(|addr| {
+            let ip = addr.nlas.iter().find_map(|nla| match nla {
+                address::nlas::Nla::Address(x) => parse_addr(x),
+                _ => None,
+            })?;
+            IpNetwork::new(ip, addr.header.prefix_len).ok()
+        })
+        .collect())
+}
+
+fn fwmark_rule_message(
+    family: u16,
+    fwmark: u32,
+    table: u32,
+    prio: u32,
+    invert: bool,
+) -> RuleMessage {
+    RuleMessage {
         header: RuleHeader {
-            family: AF_INET as u8,
+            family: family as u8,
             action: FR_ACT_TO_TBL,
-            flags: FIB_RULE_INVERT,
+            flags: if invert { FIB_RULE_INVERT } else { 0 },
             ..Default::default()
         },
         nlas: vec![
@@ -175,7 +236,12 @@ pub fn add_rule(fwmark: u32, table: u32, prio: u32) -> Result<bool, io::Error> {
             rule::Nla::Table(table),
             rule::Nla::Priority(prio),
         ],
-    };
+    }
+}
+
+/// Adds `not fwmark <fwmark> lookup <table>` rule for the given address family
+pub fn add_rule(family: u16, fwmark: u32, table: u32, prio: u32) -> Result<bool, io::Error> {
+    let message = fwmark_rule_message(family, fwmark, table, prio, true);
 
     match netlink_call(RtnlMessage::NewRule(message), None) {
         Ok(_) => Ok(true),
@@ -184,53 +250,357 @@ pub fn add_rule(fwmark: u32, table: u32, prio: u32) -> Result<bool, io::Error> {
     }
 }
 
-pub fn _remove_rule(fwmark: u32, table: u32, prio: u32) -> Result<bool, io::Error> {
-    let message = RuleMessage {
+pub fn remove_rule(family: u16, fwmark: u32, table: u32, prio: u32) -> Result<bool, io::Error> {
+    let message = fwmark_rule_message(family, fwmark, table, prio, true);
+
+    match netlink_call(RtnlMessage::DelRule(message), None) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Adds `fwmark <fwmark> lookup <table>` rule for the given address family
+pub fn add_mark_rule(family: u16, fwmark: u32, table: u32, prio: u32) -> Result<bool, io::Error> {
+    let message = fwmark_rule_message(family, fwmark, table, prio, false);
+
+    match netlink_call(RtnlMessage::NewRule(message), None) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn remove_mark_rule(
+    family: u16,
+    fwmark: u32,
+    table: u32,
+    prio: u32,
+) -> Result<bool, io::Error> {
+    let message = fwmark_rule_message(family, fwmark, table, prio, false);
+
+    match netlink_call(RtnlMessage::DelRule(message), None) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn bypass_rule_message(dst: IpNetwork, table: u32, prio: u32) -> RuleMessage {
+    let (family, addr) = match dst {
+        IpNetwork::V4(network) => (AF_INET as u8, network.network().octets().to_vec()),
+        IpNetwork::V6(network) => (AF_INET6 as u8, network.network().octets().to_vec()),
+    };
+
+    RuleMessage {
         header: RuleHeader {
-            family: AF_INET as u8,
+            family,
+            dst_len: dst.prefix(),
             action: FR_ACT_TO_TBL,
-            flags: FIB_RULE_INVERT,
             ..Default::default()
         },
         nlas: vec![
-            rule::Nla::FwMark(fwmark),
+            rule::Nla::Destination(addr),
             rule::Nla::Table(table),
             rule::Nla::Priority(prio),
         ],
-    };
+    }
+}
 
-    match netlink_call(RtnlMessage::DelRule(message), None) {
+/// Adds `to <dst> lookup <table>` rule, which takes `dst` out of the tunnel when placed before
+/// the fwmark rule.
+pub fn add_bypass_rule(dst: IpNetwork, table: u32, prio: u32) -> Result<bool, io::Error> {
+    match netlink_call(
+        RtnlMessage::NewRule(bypass_rule_message(dst, table, prio)),
+        None,
+    ) {
         Ok(_) => Ok(true),
         Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
         Err(e) => Err(e),
     }
 }
 
-pub fn add_route(
-    interface: &InterfaceName,
-    table: u32,
-    cidr: IpNetwork,
-) -> Result<bool, io::Error> {
-    let if_index = if_nametoindex(interface)?;
-    let (address_family, dst) = match cidr {
-        IpNetwork::V4(network) => (AF_INET as u8, network.network().octets().to_vec()),
-        IpNetwork::V6(network) => (AF_INET6 as u8, network.network().octets().to_vec()),
+pub fn remove_bypass_rule(dst: IpNetwork, table: u32, prio: u32) -> Result<bool, io::Error> {
+    match netlink_call(
+        RtnlMessage::DelRule(bypass_rule_message(dst, table, prio)),
+        None,
+    ) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Dumps routing rules of the given address family
+pub fn get_rules(family: u16) -> Result<Vec<RuleMessage>, io::Error> {
+    let responses = netlink_call(
+        RtnlMessage::GetRule(RuleMessage {
+            header: RuleHeader {
+                family: family as u8,
+                ..Default::default()
+            },
+            nlas: vec![],
+        }),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+
+    Ok(responses
+        .into_iter()
+        .filter_map(|response| match response {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(RtnlMessage::NewRule(rule)),
+                ..
+            } => Some(rule),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Dumps routes of the given address family in `table`
+pub fn get_routes(family: u16, table: u32) -> Result<Vec<RouteMessage>, io::Error> {
+    let responses = netlink_call(
+        RtnlMessage::GetRoute(RouteMessage {
+            header: RouteHeader {
+                address_family: family as u8,
+                ..Default::default()
+            },
+            nlas: vec![],
+        }),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+
+    Ok(responses
+        .into_iter()
+        .filter_map(|response| match response {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(RtnlMessage::NewRoute(route)),
+                ..
+            } => Some(route),
+            _ => None,
+        })
+        // Tables above 255 only show up as an attribute
+        .filter(|x| {
+            x.nlas
+                .iter()
+                .find_map(|nla| match nla {
+                    route::Nla::Table(x) => Some(*x),
+                    _ => None,
+                })
+                .unwrap_or(x.header.table as u32)
+                == table
+        })
+        .collect())
+}
+
+/// Destination, output link and metric of a dumped route
+pub fn route_info(route: &RouteMessage) -> Option<(IpNetwork, Option<u32>, Option<u32>)> {
+    // Default routes carry no destination
+    let dst = route
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            route::Nla::Destination(x) => parse_addr(x),
+            _ => None,
+        })
+        .or(match route.header.address_family as u16 {
+            AF_INET => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            AF_INET6 => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            _ => None,
+        })?;
+    let dst = IpNetwork::new(dst, route.header.destination_prefix_length).ok()?;
+
+    let oif = route.nlas.iter().find_map(|nla| match nla {
+        route::Nla::Oif(x) => Some(*x),
+        _ => None,
+    });
+    let metric = route.nlas.iter().find_map(|nla| match nla {
+        route::Nla::Priority(x) => Some(*x),
+        _ => None,
+    });
+
+    Some((dst, oif, metric))
+}
+
+/// Next hops of the default routes of the given address family in the main table, as
+/// `via <gateway> dev <link>`, or `dev <link>` for point-to-point links
+pub fn get_default_gateways(family: u16) -> Result<Vec<String>, io::Error> {
+    let links = get_links()?;
+    Ok(get_routes(family, RT_TABLE_MAIN as u32)?
+        .iter()
+        .filter(|x| x.header.destination_prefix_length == 0)
+        .filter_map(|route| {
+            let oif = route.nlas.iter().find_map(|nla| match nla {
+                route::Nla::Oif(x) => Some(*x),
+                _ => None,
+            })?;
+            // Links which are down carry no traffic
+            let (_, name) = links.iter().find(|(index, _)| *index == oif)?;
+            let gateway = route.nlas.iter().find_map(|nla| match nla {
+                route::Nla::Gateway(x) => parse_addr(x),
+                _ => None,
+            });
+            Some(match gateway {
+                Some(x) => format!("via {} dev {}", x, name),
+                None => format!("dev {}", name),
+            })
+        })
+        .collect())
+}
+
+fn rule_matches(rule: &RuleMessage, table: u32, prio: u32) -> bool {
+    let rule_table = rule
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            rule::Nla::Table(x) => Some(*x),
+            _ => None,
+        })
+        .unwrap_or(rule.header.table as u32);
+
+    rule_table == table && rule.nlas.contains(&rule::Nla::Priority(prio))
+}
+
+/// Whether `rules` hold the rule installed by `add_rule`
+pub fn has_fwmark_rule(rules: &[RuleMessage], fwmark: u32, table: u32, prio: u32) -> bool {
+    rules.iter().any(|x| {
+        x.header.flags & FIB_RULE_INVERT != 0
+            && x.nlas.contains(&rule::Nla::FwMark(fwmark))
+            && rule_matches(x, table, prio)
+    })
+}
+
+/// Whether `rules` hold the rule installed by `add_mark_rule`
+pub fn has_mark_rule(rules: &[RuleMessage], fwmark: u32, table: u32, prio: u32) -> bool {
+    rules.iter().any(|x| {
+        x.header.flags & FIB_RULE_INVERT == 0
+            && x.nlas.contains(&rule::Nla::FwMark(fwmark))
+            && rule_matches(x, table, prio)
+    })
+}
+
+/// Whether `rules` hold the rule installed by `add_bypass_rule`
+pub fn has_bypass_rule(rules: &[RuleMessage], dst: IpNetwork, table: u32, prio: u32) -> bool {
+    rules.iter().any(|x| {
+        x.header.dst_len == dst.prefix()
+            && x.nlas
+                .contains(&rule::Nla::Destination(addr_bytes(dst.network())))
+            && rule_matches(x, table, prio)
+    })
+}
+
+/// Netlink socket subscribed to changes of the links, addresses, routes and rules
+pub struct ChangeListener {
+    socket: Socket,
+}
+
+impl ChangeListener {
+    pub fn new() -> Result<Self, io::Error> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        for group in [
+            RTNLGRP_LINK,
+            RTNLGRP_IPV4_IFADDR,
+            RTNLGRP_IPV6_IFADDR,
+            RTNLGRP_IPV4_ROUTE,
+            RTNLGRP_IPV6_ROUTE,
+            RTNLGRP_IPV4_RULE,
+            RTNLGRP_IPV6_RULE,
+        ] {
+            socket.add_membership(group)?;
+        }
+
+        Ok(Self { socket })
+    }
+
+    /// Blocks until a change is notified or `timeout` passes, then drains the pending
+    /// notifications. Returns whether anything changed.
+    pub fn wait(&self, timeout: Duration) -> Result<bool, io::Error> {
+        let mut pollfd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            0 => return Ok(false),
+            x if x < 0 => {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+            _ => {}
+        }
+
+        let mut buf = [0; 8192];
+        loop {
+            match self.socket.recv(&mut &mut buf[..], libc::MSG_DONTWAIT) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Notifications overflowed the socket, which is a change all the same
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn addr_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(x) => x.octets().to_vec(),
+        IpAddr::V6(x) => x.octets().to_vec(),
+    }
+}
+
+fn route_message(if_index: u32, table: u32, cidr: IpNetwork, opts: &RouteOptions) -> RouteMessage {
+    let address_family = match cidr {
+        IpNetwork::V4(_) => AF_INET as u8,
+        IpNetwork::V6(_) => AF_INET6 as u8,
     };
-    let message = RouteMessage {
+    let mut nlas = vec![
+        route::Nla::Destination(addr_bytes(cidr.network())),
+        route::Nla::Oif(if_index),
+        route::Nla::Table(table),
+    ];
+    if let Some(x) = opts.metric {
+        nlas.push(route::Nla::Priority(x));
+    }
+    if let Some(x) = opts.via {
+        nlas.push(route::Nla::Gateway(addr_bytes(x)));
+    }
+    if let Some(x) = opts.src {
+        nlas.push(route::Nla::PrefSource(addr_bytes(x)));
+    }
+
+    RouteMessage {
         header: RouteHeader {
             protocol: RTPROT_BOOT,
-            scope: RT_SCOPE_LINK,
+            // Routes through a gateway reach beyond the link
+            scope: if opts.via.is_some() {
+                RT_SCOPE_UNIVERSE
+            } else {
+                RT_SCOPE_LINK
+            },
             kind: RTN_UNICAST,
             destination_prefix_length: cidr.prefix(),
             address_family,
             ..Default::default()
         },
-        nlas: vec![
-            route::Nla::Destination(dst),
-            route::Nla::Oif(if_index),
-            route::Nla::Table(table),
-        ],
-    };
+        nlas,
+    }
+}
+
+/// Adds a route to `cidr` through `interface`. `opts.table` is ignored in favour of `table`.
+pub fn add_route(
+    interface: &InterfaceName,
+    table: u32,
+    cidr: IpNetwork,
+    opts: &RouteOptions,
+) -> Result<bool, io::Error> {
+    let if_index = if_nametoindex(interface)?;
+    let message = route_message(if_index, table, cidr, opts);
 
     match netlink_call(RtnlMessage::NewRoute(message), None) {
         Ok(_) => Ok(true),
@@ -243,36 +613,20 @@ pub fn del_route(
     interface: &InterfaceName,
     table: u32,
     cidr: IpNetwork,
+    opts: &RouteOptions,
 ) -> Result<bool, io::Error> {
     let if_index = if_nametoindex(interface)?;
-    let (address_family, dst) = match cidr {
-        IpNetwork::V4(network) => (AF_INET as u8, network.network().octets().to_vec()),
-        IpNetwork::V6(network) => (AF_INET6 as u8, network.network().octets().to_vec()),
-    };
-    let message = RouteMessage {
-        header: RouteHeader {
-            protocol: RTPROT_BOOT,
-            scope: RT_SCOPE_LINK,
-            kind: RTN_UNICAST,
-            destination_prefix_length: cidr.prefix(),
-            address_family,
-            ..Default::default()
-        },
-        nlas: vec![
-            route::Nla::Destination(dst),
-            route::Nla::Oif(if_index),
-            route::Nla::Table(table),
-        ],
-    };
+    let message = route_message(if_index, table, cidr, opts);
 
     match netlink_call(RtnlMessage::DelRoute(message), None) {
         Ok(_) => Ok(true),
-        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(false),
         Err(e) => Err(e),
     }
 }
 
-fn get_links() -> Result<Vec<String>, io::Error> {
+/// Index and name of the non-loopback links which are up
+fn get_links() -> Result<Vec<(u32, String)>, io::Error> {
     let link_responses = netlink_call(
         RtnlMessage::GetLink(LinkMessage::default()),
         Some(NLM_F_DUMP | NLM_F_REQUEST),
@@ -288,21 +642,16 @@ fn get_links() -> Result<Vec<String>, io::Error> {
             _ => None,
         })
         // Filter out loopback links
-        .filter_map(|link| {
-            if link.header.flags & IFF_LOOPBACK == 0 {
-                Some(link.nlas)
-            } else {
-                None
-            }
-        })
+        .filter(|link| link.header.flags & IFF_LOOPBACK == 0)
         // Find and filter out addresses for interfaces
-        .filter(|nlas| {
-            nlas.iter()
+        .filter(|link| {
+            link.nlas
+                .iter()
                 .any(|nla| nla == &link::nlas::Nla::OperState(State::Up))
         })
-        .filter_map(|nlas| {
-            nlas.iter().find_map(|nla| match nla {
-                link::nlas::Nla::IfName(name) => Some(name.clone()),
+        .filter_map(|link| {
+            link.nlas.iter().find_map(|nla| match nla {
+                link::nlas::Nla::IfName(name) => Some((link.header.index, name.clone())),
                 _ => None,
             })
         })
@@ -338,7 +687,7 @@ pub fn get_local_addrs() -> Result<impl Iterator<Item = IpAddr>, io::Error> {
         // Only select addresses for helpful links
         .filter(move |nlas| {
             nlas.iter()
-                .any(|nla| matches!(nla, address::nlas::Nla::Label(label) if links.contains(label)))
+                .any(|nla| matches!(nla, address::nlas::Nla::Label(label) if links.iter().any(|(_, name)| name == label)))
         })
         .filter_map(|nlas| {
             nlas.iter().find_map(|nla| match nla {
@@ -358,6 +707,94 @@ pub fn get_local_addrs() -> Result<impl Iterator<Item = IpAddr>, io::Error> {
     Ok(addrs)
 }
 
+fn parse_addr(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => {
+            let mut addr = [0u8; 4];
+            addr.copy_from_slice(bytes);
+            Some(IpAddr::V4(addr.into()))
+        }
+        16 => {
+            let mut addr = [0u8; 16];
+            addr.copy_from_slice(bytes);
+            Some(IpAddr::V6(addr.into()))
+        }
+        _ => None,
+    }
+}
+
+/// Globally scoped networks attached to the links which are up, along with the link name
+pub fn get_local_networks() -> Result<Vec<(String, IpNetwork)>, io::Error> {
+    let links = get_links()?;
+    let addr_responses = netlink_call(
+        RtnlMessage::GetAddress(AddressMessage::default()),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+
+    Ok(addr_responses
+        .into_iter()
+        .filter_map(|response| match response {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(RtnlMessage::NewAddress(addr)),
+                ..
+            } => Some(addr),
+            _ => None,
+        })
+        .filter(|addr| addr.header.scope == RT_SCOPE_UNIVERSE)
+        .filter_map(|addr| {
+            // IPv6 addresses carry no label, so match links by index
+            let (_, name) = links
+                .iter()
+                .find(|(index, _)| *index == addr.header.index)?;
+            let ip = addr.nlas.iter().find_map(|nla| match nla {
+                address::nlas::Nla::Address(x) => parse_addr(x),
+                _ => None,
+            })?;
+            let network = IpNetwork::new(ip, addr.header.prefix_len).ok()?;
+            let network = IpNetwork::new(network.network(), network.prefix()).ok()?;
+            Some((name.clone(), network))
+        })
+        .collect())
+}
+
+/// Protocol version of each peer of a kernel WireGuard interface, by public key. wireguard-control
+/// drops WGPEER_A_PROTOCOL_VERSION, so it is read here.
+pub fn get_protocol_versions(
+    interface: &InterfaceName,
+) -> Result<HashMap<[u8; 32], u32>, io::Error> {
+    let message: GenlMessage<Wireguard> = GenlMessage::from_payload(Wireguard {
+        cmd: WireguardCmd::GetDevice,
+        nlas: vec![WgDeviceAttrs::IfName(interface.to_string())],
+    });
+    let responses = netlink_request_genl(message, Some(NLM_F_REQUEST | NLM_F_ACK | NLM_F_DUMP))?;
+
+    Ok(responses
+        .into_iter()
+        .filter_map(|response| match response.payload {
+            NetlinkPayload::InnerMessage(x) => Some(x.payload.nlas),
+            _ => None,
+        })
+        .flatten()
+        // Large devices are split across messages, each carrying some of the peers
+        .filter_map(|nla| match nla {
+            WgDeviceAttrs::Peers(peers) => Some(peers),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|peer| {
+            let pubkey = peer.iter().find_map(|nla| match nla {
+                WgPeerAttrs::PublicKey(x) => Some(*x),
+                _ => None,
+            })?;
+            let version = peer.iter().find_map(|nla| match nla {
+                WgPeerAttrs::ProtocolVersion(x) => Some(*x),
+                _ => None,
+            })?;
+            Some((pubkey, version))
+        })
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -367,4 +804,274 @@ mod tests {
         let addrs = get_local_addrs().unwrap();
         println!("{:?}", addrs.collect::<Vec<_>>());
     }
+
+    #[test]
+    fn test_local_networks() {
+        let networks = get_local_networks().unwrap();
+        for (_, network) in networks.iter() {
+            assert_eq!(network.ip(), network.network());
+        }
+    }
+
+    /// Moves the test thread into a fresh network namespace, so that tests do not touch the
+    /// host. Returns false if we lack the privilege to do so.
+    fn enter_netns() -> bool {
+        unsafe { libc::unshare(libc::CLONE_NEWNET) == 0 }
+    }
+
+    fn has_rule(family: u16, nlas: &[rule::Nla]) -> bool {
+        get_rules(family)
+            .unwrap()
+            .iter()
+            .any(|rule| nlas.iter().all(|nla| rule.nlas.contains(nla)))
+    }
+
+    #[test]
+    fn test_fwmark_rule() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let nlas = [
+            rule::Nla::FwMark(0x1234),
+            rule::Nla::Table(0x1234),
+            rule::Nla::Priority(0x7000),
+        ];
+        for family in [AF_INET, AF_INET6] {
+            assert!(add_rule(family, 0x1234, 0x1234, 0x7000).unwrap());
+            assert!(!add_rule(family, 0x1234, 0x1234, 0x7000).unwrap());
+            assert!(has_rule(family, &nlas));
+
+            assert!(remove_rule(family, 0x1234, 0x1234, 0x7000).unwrap());
+            assert!(!remove_rule(family, 0x1234, 0x1234, 0x7000).unwrap());
+            assert!(!has_rule(family, &nlas));
+        }
+    }
+
+    #[test]
+    fn test_default_routes() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let lo: InterfaceName = "lo".parse().unwrap();
+        set_up(&lo, 65536).unwrap();
+
+        for (family, cidr) in [
+            (AF_INET, "0.0.0.0/0"),
+            (AF_INET, "0.0.0.0/1"),
+            (AF_INET, "128.0.0.0/1"),
+            (AF_INET6, "::/0"),
+            (AF_INET6, "::/1"),
+            (AF_INET6, "8000::/1"),
+        ] {
+            let cidr: IpNetwork = cidr.parse().unwrap();
+            let has_route = || {
+                get_routes(family, 0x1234)
+                    .unwrap()
+                    .iter()
+                    .any(|x| x.header.destination_prefix_length == cidr.prefix())
+            };
+
+            let opts = RouteOptions::default();
+            assert!(add_route(&lo, 0x1234, cidr, &opts).unwrap());
+            assert!(!add_route(&lo, 0x1234, cidr, &opts).unwrap());
+            assert!(has_route());
+
+            assert!(del_route(&lo, 0x1234, cidr, &opts).unwrap());
+            assert!(!del_route(&lo, 0x1234, cidr, &opts).unwrap());
+            assert!(!has_route());
+        }
+    }
+
+    #[test]
+    fn test_addrs() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let lo: InterfaceName = "lo".parse().unwrap();
+        set_up(&lo, 65536).unwrap();
+        let addr: IpNetwork = "10.99.0.1/24".parse().unwrap();
+        let addr6: IpNetwork = "fd99::1/64".parse().unwrap();
+
+        set_addr(&lo, addr).unwrap();
+        set_addr(&lo, addr6).unwrap();
+        let addrs = get_addrs(&lo).unwrap();
+        assert!(addrs.contains(&addr));
+        assert!(addrs.contains(&addr6));
+
+        assert!(del_addr(&lo, addr).unwrap());
+        assert!(!del_addr(&lo, addr).unwrap());
+        assert!(del_addr(&lo, addr6).unwrap());
+        let addrs = get_addrs(&lo).unwrap();
+        assert!(!addrs.contains(&addr));
+        assert!(!addrs.contains(&addr6));
+    }
+
+    #[test]
+    fn test_route_info() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let lo: InterfaceName = "lo".parse().unwrap();
+        set_up(&lo, 65536).unwrap();
+        let index = if_nametoindex(&lo).unwrap();
+
+        for (family, cidr) in [(AF_INET, "0.0.0.0/0"), (AF_INET6, "2001:db8::/32")] {
+            let cidr: IpNetwork = cidr.parse().unwrap();
+            let opts = RouteOptions {
+                metric: Some(50),
+                ..Default::default()
+            };
+            add_route(&lo, 0x1234, cidr, &opts).unwrap();
+
+            let routes = get_routes(family, 0x1234).unwrap();
+            let info: Vec<_> = routes.iter().filter_map(route_info).collect();
+            assert_eq!(info, vec![(cidr, Some(index), Some(50))]);
+
+            del_route(&lo, 0x1234, cidr, &opts).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rule_lookup() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let dst: IpNetwork = "192.0.2.0/24".parse().unwrap();
+        add_rule(AF_INET, 0x1234, 0x1234, 0x7000).unwrap();
+        add_bypass_rule(dst, RT_TABLE_MAIN as u32, 0x6fff).unwrap();
+
+        let rules = get_rules(AF_INET).unwrap();
+        assert!(has_fwmark_rule(&rules, 0x1234, 0x1234, 0x7000));
+        assert!(!has_fwmark_rule(&rules, 0x1234, 0x1234, 0x7001));
+        assert!(has_bypass_rule(&rules, dst, RT_TABLE_MAIN as u32, 0x6fff));
+        assert!(!has_bypass_rule(
+            &rules,
+            "192.0.2.0/25".parse().unwrap(),
+            RT_TABLE_MAIN as u32,
+            0x6fff
+        ));
+    }
+
+    #[test]
+    fn test_change_listener() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let listener = ChangeListener::new().unwrap();
+        assert!(!listener.wait(Duration::from_millis(10)).unwrap());
+
+        add_rule(AF_INET, 0x1234, 0x1234, 0x7000).unwrap();
+        assert!(listener.wait(Duration::from_secs(1)).unwrap());
+        // Drained by the previous wait
+        assert!(!listener.wait(Duration::from_millis(10)).unwrap());
+    }
+
+    #[test]
+    fn test_route_options() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let lo: InterfaceName = "lo".parse().unwrap();
+        set_up(&lo, 65536).unwrap();
+        set_addr(&lo, "10.99.0.1/24".parse().unwrap()).unwrap();
+        set_addr(&lo, "fd99::1/64".parse().unwrap()).unwrap();
+
+        // Loopback has no on-link IPv6 prefix to put a gateway on
+        for (family, cidr, via, src) in [
+            (AF_INET, "198.51.100.0/24", Some("10.99.0.2"), "10.99.0.1"),
+            (AF_INET6, "2001:db8::/32", None, "fd99::1"),
+        ] {
+            let cidr: IpNetwork = cidr.parse().unwrap();
+            let opts = RouteOptions {
+                metric: Some(50),
+                via: via.map(|x| x.parse().unwrap()),
+                src: Some(src.parse().unwrap()),
+                table: None,
+            };
+            let mut nlas = vec![
+                route::Nla::Priority(50),
+                route::Nla::PrefSource(addr_bytes(opts.src.unwrap())),
+            ];
+            if let Some(x) = opts.via {
+                nlas.push(route::Nla::Gateway(addr_bytes(x)));
+            }
+            let has_route = || {
+                get_routes(family, 0x1234)
+                    .unwrap()
+                    .iter()
+                    .any(|x| nlas.iter().all(|nla| x.nlas.contains(nla)))
+            };
+
+            assert!(add_route(&lo, 0x1234, cidr, &opts).unwrap());
+            assert!(has_route());
+
+            // Same prefix with another metric is a distinct route
+            let other = RouteOptions {
+                metric: Some(60),
+                ..opts
+            };
+            assert!(add_route(&lo, 0x1234, cidr, &other).unwrap());
+            assert!(del_route(&lo, 0x1234, cidr, &other).unwrap());
+            assert!(has_route());
+
+            assert!(del_route(&lo, 0x1234, cidr, &opts).unwrap());
+            assert!(!has_route());
+        }
+    }
+
+    #[test]
+    fn test_mark_rule() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        for family in [AF_INET, AF_INET6] {
+            let nlas = [rule::Nla::FwMark(0x4321), rule::Nla::Priority(0x7000)];
+            assert!(add_mark_rule(family, 0x4321, RT_TABLE_MAIN as u32, 0x7000).unwrap());
+            assert!(has_rule(family, &nlas));
+
+            assert!(remove_mark_rule(family, 0x4321, RT_TABLE_MAIN as u32, 0x7000).unwrap());
+            assert!(!remove_mark_rule(family, 0x4321, RT_TABLE_MAIN as u32, 0x7000).unwrap());
+            assert!(!has_rule(family, &nlas));
+        }
+    }
+
+    #[test]
+    fn test_bypass_rule() {
+        if !enter_netns() {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        for (family, dst) in [(AF_INET, "192.0.2.1/32"), (AF_INET6, "2001:db8::/64")] {
+            let dst: IpNetwork = dst.parse().unwrap();
+            let addr = match dst {
+                IpNetwork::V4(x) => x.network().octets().to_vec(),
+                IpNetwork::V6(x) => x.network().octets().to_vec(),
+            };
+            let nlas = [rule::Nla::Destination(addr), rule::Nla::Priority(0x7000)];
+
+            assert!(add_bypass_rule(dst, RT_TABLE_MAIN as u32, 0x7000).unwrap());
+            assert!(!add_bypass_rule(dst, RT_TABLE_MAIN as u32, 0x7000).unwrap());
+            assert!(has_rule(family, &nlas));
+
+            assert!(remove_bypass_rule(dst, RT_TABLE_MAIN as u32, 0x7000).unwrap());
+            assert!(!has_rule(family, &nlas));
+        }
+    }
 }
@@ -18,7 +18,12 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use custom_error::custom_error;
+use ipnet::IpNet;
 
 use crate::error::VpnctrlError;
 
@@ -57,12 +62,158 @@ impl ToString for InterfaceStatus {
     }
 }
 
+pub const DEFAULT_FWMARK: u32 = 0x7370616b;
+pub const DEFAULT_RULE_PRIORITY: u32 = 0x7363;
+
+/// Routing table used for interface routes, following wg-quick's `Table=` semantics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteTable {
+    /// Dedicated table with the same number as the fwmark
+    Auto,
+    Main,
+    /// Routes are not installed at all
+    Off,
+    Id(u32),
+}
+
+impl FromStr for RouteTable {
+    type Err = VpnctrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(RouteTable::Auto),
+            "main" => Ok(RouteTable::Main),
+            "off" => Ok(RouteTable::Off),
+            _ => match s.parse::<u32>() {
+                Ok(x) if x != 0 => Ok(RouteTable::Id(x)),
+                _ => Err(VpnctrlError::BadParameter {
+                    msg: format!("Invalid routing table: {}", s),
+                }),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RoutePolicy {
+    pub table: RouteTable,
+    pub fwmark: u32,
+    pub rule_priority: u32,
+}
+
+impl Default for RoutePolicy {
+    fn default() -> Self {
+        RoutePolicy {
+            table: RouteTable::Auto,
+            fwmark: DEFAULT_FWMARK,
+            rule_priority: DEFAULT_RULE_PRIORITY,
+        }
+    }
+}
+
+/// Optional attributes of a single route
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RouteOptions {
+    pub metric: Option<u32>,
+    /// Gateway to route through, instead of straight onto the link
+    pub via: Option<IpAddr>,
+    /// Preferred source address
+    pub src: Option<IpAddr>,
+    /// Overrides the table of the interface
+    pub table: Option<RouteTable>,
+}
+
+impl RouteOptions {
+    /// Checks the options make sense for a route to `cidr`
+    pub fn validate(&self, cidr: &str) -> Result<(), VpnctrlError> {
+        let family = match parse_cidr(cidr) {
+            Some(x) => IpFamily::from(x.addr()),
+            None => {
+                return Err(VpnctrlError::BadParameter {
+                    msg: format!("Invalid CIDR: {}", cidr),
+                })
+            }
+        };
+
+        for (name, addr) in [("via", self.via), ("src", self.src)] {
+            if let Some(x) = addr {
+                if IpFamily::from(x) != family {
+                    return Err(VpnctrlError::BadParameter {
+                        msg: format!("{} {} does not match the family of {}", name, x, cidr),
+                    });
+                }
+            }
+        }
+
+        if matches!(self.table, Some(RouteTable::Off)) {
+            return Err(VpnctrlError::BadParameter {
+                msg: "Routes cannot be installed to table off".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// Sessions are rejected after REJECT_AFTER_TIME (180s) without a new handshake.
+pub const WG_REJECT_AFTER_TIME: u64 = 180;
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct PeerTrafficStat {
     pub pubkey: String,
     pub rx_bytes: u64,
     pub tx_bytes: u64,
+    /// Last handshake time in seconds since UNIX epoch
+    pub last_handshake: Option<u64>,
+    /// Endpoint currently used by the peer. This may differ from configured one due to roaming.
+    pub endpoint: Option<String>,
+    pub keepalive: Option<u16>,
+    /// None where the backend does not report it
+    pub protocol_version: Option<u32>,
+    pub connected: bool,
+}
+
+impl PeerTrafficStat {
+    pub fn new(
+        pubkey: String,
+        rx_bytes: u64,
+        tx_bytes: u64,
+        last_handshake: Option<SystemTime>,
+        endpoint: Option<SocketAddr>,
+        keepalive: Option<u16>,
+        protocol_version: Option<u32>,
+    ) -> Self {
+        let last_handshake = last_handshake
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_secs())
+            .filter(|x| *x != 0);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+
+        PeerTrafficStat {
+            pubkey,
+            rx_bytes,
+            tx_bytes,
+            last_handshake,
+            endpoint: endpoint
+                .filter(|x| !x.ip().is_unspecified())
+                .map(|x| x.to_string()),
+            keepalive: keepalive.filter(|x| *x != 0),
+            protocol_version,
+            connected: is_connected(last_handshake, now),
+        }
+    }
+}
+
+/// Returns true if the handshake happened recently enough to keep the session alive.
+pub fn is_connected(last_handshake: Option<u64>, now: u64) -> bool {
+    match last_handshake {
+        Some(x) => now.saturating_sub(x) < WG_REJECT_AFTER_TIME,
+        None => false,
+    }
 }
 
 pub trait PlatformInterface {
@@ -74,27 +225,216 @@ pub trait PlatformInterface {
     fn get_peers(&self) -> Result<Vec<WgPeerCfg>, VpnctrlError>;
     fn get_peer(&self, pubkey: &str) -> Result<WgPeerCfg, VpnctrlError>;
     fn remove_peer(&mut self, pubkey: &str) -> Result<(), VpnctrlError>;
+    /// Points an existing peer at `endpoint` again, replacing the one learned from its traffic
+    fn set_peer_endpoint(&mut self, pubkey: &str, endpoint: &str) -> Result<(), VpnctrlError>;
     fn get_status(&self) -> InterfaceStatus;
     fn get_trafficstats(&self) -> Result<Vec<PeerTrafficStat>, VpnctrlError>;
     fn get_platformid(&self) -> Result<String, VpnctrlError>;
     fn up(&mut self) -> bool;
     fn down(&mut self) -> bool;
+    /// Makes `ips` the exact set of addresses of the interface
     fn set_ip(&mut self, ips: &[String]) -> Result<(), VpnctrlError>;
+    fn get_ip(&self) -> Result<Vec<String>, VpnctrlError>;
+}
+
+/// Parses interface addresses in CIDR notation, listing every invalid entry
+pub fn parse_addresses(ips: &[String]) -> Result<Vec<IpNet>, Vec<String>> {
+    let mut errors = vec![];
+    let mut addrs = vec![];
+    for ip in ips {
+        match ip.parse::<IpNet>() {
+            Ok(x) => addrs.push(x),
+            Err(_) => errors.push(format!("Invalid address: {}", ip)),
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(addrs),
+        false => Err(errors),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// Family of an address or a CIDR
+    pub fn of(addr: &str) -> Self {
+        if addr.contains(':') {
+            IpFamily::V6
+        } else {
+            IpFamily::V4
+        }
+    }
+}
+
+impl From<IpAddr> for IpFamily {
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => IpFamily::V4,
+            IpAddr::V6(_) => IpFamily::V6,
+        }
+    }
+}
+
+/// Parses `cidr`, where a bare address stands for a host route
+fn parse_cidr(cidr: &str) -> Option<IpNet> {
+    match cidr.parse::<IpNet>() {
+        Ok(x) => Some(x),
+        Err(_) => cidr.parse::<IpAddr>().ok().map(IpNet::from),
+    }
+}
+
+/// Family of `cidr` if it replaces the default route
+pub fn default_route_family(cidr: &str) -> Option<IpFamily> {
+    match parse_cidr(cidr) {
+        Some(x) if x.prefix_len() == 0 => Some(IpFamily::of(cidr)),
+        _ => None,
+    }
+}
+
+/// Whether `cidr` takes over all traffic of its family: the default route, or one of the `/1`
+/// halves it is commonly split into
+pub fn is_default_route(cidr: &str) -> bool {
+    matches!(parse_cidr(cidr), Some(x) if x.prefix_len() <= 1)
 }
 
 pub trait PlatformRoute {
-    fn new(fwmark: u32) -> Result<Self, VpnctrlError>
+    fn new(defaults: RoutePolicy) -> Result<Self, VpnctrlError>
     where
         Self: Sized;
     fn init(&mut self) -> Result<(), VpnctrlError>;
-    fn add_route(&mut self, ifname: &str, cidr: &str) -> Result<(), VpnctrlError>;
+    fn add_route(
+        &mut self,
+        ifname: &str,
+        cidr: &str,
+        opts: &RouteOptions,
+    ) -> Result<(), VpnctrlError>;
     fn remove_route(&mut self, ifname: &str, cidr: &str) -> Result<(), VpnctrlError>;
     fn add_route_bypass(&mut self, address: &str) -> Result<(), VpnctrlError>;
     fn remove_route_bypass(&mut self, address: &str) -> Result<(), VpnctrlError>;
     fn get_route_bypass(&self) -> Result<Vec<String>, VpnctrlError>;
+    /// Remembers the default gateways of both families
     fn backup_default_route(&mut self) -> Result<(), VpnctrlError>;
-    fn remove_default_route(&mut self) -> Result<(), VpnctrlError>;
+    fn remove_default_route(&mut self, family: IpFamily) -> Result<(), VpnctrlError>;
     fn restore_default_route(&mut self) -> Result<(), VpnctrlError>;
+    /// Restores the default route of `family` alone, the other one stays taken over
+    fn restore_family_default_route(&mut self, family: IpFamily) -> Result<(), VpnctrlError>;
+    /// Looks up the default gateways again after the physical network changed, moving the
+    /// bypass routes onto them. Returns the gateways in use.
+    fn refresh_default_route(&mut self) -> Result<Vec<String>, VpnctrlError>;
+    fn add_interface(&mut self, ifname: &str, policy: RoutePolicy) -> Result<(), VpnctrlError>;
+    fn remove_interface(&mut self, ifname: &str) -> Result<(), VpnctrlError>;
+    /// Priority of the bypass rules, ahead of the rules of every interface. Changes along with
+    /// the interfaces.
+    fn bypass_priority(&self) -> u32;
+    /// Keeps traffic to the local networks off the tunnels, returning the bypassed networks.
+    /// Networks of the `tunnels` interfaces are never treated as local.
+    fn refresh_lan_bypass(&mut self, tunnels: &[String]) -> Result<Vec<String>, VpnctrlError>;
+    fn clear_lan_bypass(&mut self) -> Result<(), VpnctrlError>;
+    /// Checks the routes and rules we installed against the system, installing missing ones again
+    fn reconcile(&mut self) -> Result<RouteDrift, VpnctrlError>;
+    fn cleanup(&mut self) -> Result<(), VpnctrlError>;
+}
+
+/// Difference between the routing state we installed and the one found on the system
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RouteDrift {
+    /// Entries which had gone missing and were installed again
+    pub restored: Vec<String>,
+    /// Entries which are missing and could not be installed again
+    pub missing: Vec<String>,
+    /// Entries in our tables which we did not install. These are left alone.
+    pub extra: Vec<String>,
+}
+
+impl RouteDrift {
+    pub fn is_empty(&self) -> bool {
+        self.restored.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Notifies of changes to the links, addresses, routes and rules of the system
+pub trait PlatformNetworkMonitor {
+    fn new() -> Result<Self, VpnctrlError>
+    where
+        Self: Sized;
+    /// Blocks until the network changes or `timeout` passes. Returns whether it changed.
+    fn wait(&mut self, timeout: Duration) -> Result<bool, VpnctrlError>;
+}
+
+/// Private ranges local subnets are looked up in when LAN access is allowed
+pub const PRIVATE_NETWORKS: &[&str] =
+    &["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "fc00::/7"];
+
+/// Link-local and multicast ranges, local whatever network we are on
+pub const LINK_NETWORKS: &[&str] = &[
+    "169.254.0.0/16",
+    "224.0.0.0/4",
+    "255.255.255.255/32",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// What the firewall lets through
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirewallPolicy {
+    /// Only traffic through the tunnels, WireGuard traffic to the peers and LAN exceptions pass
+    Connected {
+        tunnels: Vec<String>,
+        endpoints: Vec<SocketAddr>,
+        listen_ports: Vec<u16>,
+        allow_lan: bool,
+    },
+    /// Everything but LAN exceptions is blocked
+    Blocked {
+        allow_lan: bool,
+    },
+    Off,
+}
+
+/// Handling of DNS queries which bypass the configured resolvers
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnsLeakPolicy {
+    /// DNS and DNS over TLS not sent to `servers` is rejected
+    Block {
+        servers: Vec<IpAddr>,
+    },
+    /// Plain DNS not sent to `servers` is redirected to the first server of the same family,
+    /// DNS over TLS is rejected
+    Redirect {
+        servers: Vec<IpAddr>,
+    },
+    Off,
+}
+
+pub trait PlatformFirewall {
+    fn new() -> Result<Self, VpnctrlError>
+    where
+        Self: Sized;
+    fn apply_policy(&mut self, policy: &FirewallPolicy) -> Result<(), VpnctrlError>;
+    fn apply_dns_policy(&mut self, policy: &DnsLeakPolicy) -> Result<(), VpnctrlError>;
+    fn reset(&mut self) -> Result<(), VpnctrlError>;
+}
+
+/// Excludes traffic of selected processes from the tunnel
+pub trait PlatformSplitTunnel {
+    fn new(rule_priority: u32) -> Result<Self, VpnctrlError>
+    where
+        Self: Sized;
+    fn add_pid(&mut self, pid: u32) -> Result<(), VpnctrlError>;
+    fn remove_pid(&mut self, pid: u32) -> Result<(), VpnctrlError>;
+    fn get_pids(&self) -> Result<Vec<u32>, VpnctrlError>;
+    /// Moves the rule taking excluded traffic around the tunnels, to stay ahead of the rules of
+    /// interfaces added since
+    fn set_rule_priority(&mut self, rule_priority: u32) -> Result<(), VpnctrlError>;
+    fn reset(&mut self) -> Result<(), VpnctrlError>;
 }
 
 // Imported from Mullvad talpid-core
@@ -111,19 +451,170 @@ use super::super::platform_specific::windows::dns;
 
 pub use dns::Error;
 
+/// Name records and backups are given when no instance is configured
+pub const DEFAULT_INSTANCE: &str = "mareel";
+
+/// Tells this daemon apart from other instances, and from other VPN clients, on the same system
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instance {
+    /// Goes into the names of DNS records and backups
+    pub name: String,
+    /// Where backups of system files are kept, next to the files themselves if `None`
+    pub backup_dir: Option<std::path::PathBuf>,
+}
+
+impl Instance {
+    pub fn new(name: &str, backup_dir: Option<std::path::PathBuf>) -> Result<Self, VpnctrlError> {
+        let valid = !name.is_empty()
+            && name.len() <= 32
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(VpnctrlError::BadParameter {
+                msg: format!("Invalid instance name: {}", name),
+            });
+        }
+
+        Ok(Instance {
+            name: name.to_string(),
+            backup_dir,
+        })
+    }
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Instance {
+            name: DEFAULT_INSTANCE.to_string(),
+            backup_dir: None,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref INSTANCE: std::sync::RwLock<Instance> = Default::default();
+}
+
+/// Sets the instance for the whole process, before anything is set up
+pub fn set_instance(instance: Instance) {
+    *INSTANCE.write().unwrap() = instance;
+}
+
+pub fn instance() -> Instance {
+    INSTANCE.read().unwrap().clone()
+}
+
+/// DNS managers which can be picked on Linux, other platforms have a single one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsBackend {
+    SystemdResolved,
+    NetworkManager,
+    Resolvconf,
+    StaticFile,
+}
+
+impl DnsBackend {
+    /// Order the backends are tried in when none is configured
+    pub const AUTO: [DnsBackend; 4] = [
+        DnsBackend::SystemdResolved,
+        DnsBackend::NetworkManager,
+        DnsBackend::Resolvconf,
+        DnsBackend::StaticFile,
+    ];
+}
+
+impl FromStr for DnsBackend {
+    type Err = VpnctrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "systemd-resolved" => Ok(DnsBackend::SystemdResolved),
+            "network-manager" => Ok(DnsBackend::NetworkManager),
+            "resolvconf" => Ok(DnsBackend::Resolvconf),
+            "static-file" => Ok(DnsBackend::StaticFile),
+            _ => Err(VpnctrlError::BadParameter {
+                msg: format!("Invalid DNS backend: {}", s),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for DnsBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DnsBackend::SystemdResolved => "systemd-resolved",
+            DnsBackend::NetworkManager => "network-manager",
+            DnsBackend::Resolvconf => "resolvconf",
+            DnsBackend::StaticFile => "static-file",
+        })
+    }
+}
+
+/// DNS settings of one interface
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DnsConfig {
+    pub servers: Vec<IpAddr>,
+    /// Appended to single-label names
+    pub search_domains: Vec<String>,
+    /// When set, only names under these domains are resolved through `servers`
+    pub routing_domains: Vec<String>,
+}
+
+impl DnsConfig {
+    /// Combines the settings of several interfaces, for managers which only have a single global
+    /// configuration. Routing domains can't be honoured there and are dropped.
+    pub fn merge<'a>(configs: impl IntoIterator<Item = &'a DnsConfig>) -> DnsConfig {
+        let mut merged = DnsConfig::default();
+        for config in configs {
+            for server in &config.servers {
+                if !merged.servers.contains(server) {
+                    merged.servers.push(*server);
+                }
+            }
+            for domain in &config.search_domains {
+                if !merged.search_domains.contains(domain) {
+                    merged.search_domains.push(domain.clone());
+                }
+            }
+        }
+        merged
+    }
+}
+
+/// DNS of one interface, as currently seen on the system
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DnsStatus {
+    /// Manager enforcing the settings of the interface
+    pub manager: Option<String>,
+    /// Resolvers in use right now, if the platform lets us observe them
+    pub resolvers: Option<Vec<IpAddr>>,
+    /// Times our settings were found overwritten and put back, `None` if the manager is not
+    /// watched for that
+    pub corrections: Option<u64>,
+}
+
 /// Sets and monitors system DNS settings. Makes sure the desired DNS servers are being used.
 pub struct DnsMonitor {
     inner: dns::DnsMonitor,
 }
 
 impl DnsMonitor {
-    /// Returns a new `DnsMonitor` that can set and monitor the system DNS.
-    pub fn new(handle: tokio::runtime::Handle) -> Result<Self, Error> {
+    /// Returns a new `DnsMonitor` that can set and monitor the system DNS. The first usable of
+    /// `backends` manages DNS.
+    pub fn new(handle: tokio::runtime::Handle, backends: &[DnsBackend]) -> Result<Self, Error> {
         Ok(DnsMonitor {
-            inner: dns::DnsMonitor::new(handle)?,
+            inner: dns::DnsMonitor::new(handle, backends)?,
         })
     }
 
+    /// Checks which of `backends` could be used, with the reason of those which can't
+    pub fn probe(backends: &[DnsBackend]) -> Vec<(DnsBackend, Result<(), String>)> {
+        dns::DnsMonitor::probe(backends)
+    }
+
     /// Returns a map of interfaces and respective list of resolvers that don't contain our
     /// changes.
     #[cfg(target_os = "macos")]
@@ -131,17 +622,26 @@ impl DnsMonitor {
         self.inner.get_system_config()
     }
 
-    /// Set DNS to the given servers. And start monitoring the system for changes.
-    pub fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<(), Error> {
+    /// Set DNS of `interface` to the given config, replacing what was set for it before. And
+    /// start monitoring the system for changes.
+    pub fn set(&mut self, interface: &str, config: &DnsConfig) -> Result<(), Error> {
         log::info!(
-            "Setting DNS servers to {}",
-            servers
+            "Setting DNS servers of {} to {}",
+            interface,
+            config
+                .servers
                 .iter()
                 .map(|ip| ip.to_string())
                 .collect::<Vec<String>>()
                 .join(", ")
         );
-        self.inner.set(interface, servers)
+        self.inner.set(interface, config)
+    }
+
+    /// Reset the DNS settings made for `interface`, keeping those of other interfaces.
+    pub fn reset_interface(&mut self, interface: &str) -> Result<(), Error> {
+        log::info!("Resetting DNS of {}", interface);
+        self.inner.reset_interface(interface)
     }
 
     /// Reset system DNS settings to what it was before being set by this instance.
@@ -150,14 +650,175 @@ impl DnsMonitor {
         log::info!("Resetting DNS");
         self.inner.reset()
     }
+
+    /// Name of the DNS manager currently enforcing our settings, if any.
+    pub fn manager_name(&self) -> Option<String> {
+        self.inner.manager_name()
+    }
+
+    /// What the system currently uses for DNS of `interface`
+    pub fn status(&self, interface: &str) -> Result<DnsStatus, Error> {
+        self.inner.status(interface)
+    }
 }
 
 pub trait DnsMonitorT: Sized {
     type Error: std::error::Error;
 
-    fn new(handle: tokio::runtime::Handle) -> Result<Self, Self::Error>;
+    fn new(handle: tokio::runtime::Handle, backends: &[DnsBackend]) -> Result<Self, Self::Error>;
+
+    fn probe(backends: &[DnsBackend]) -> Vec<(DnsBackend, Result<(), String>)>;
+
+    fn set(&mut self, interface: &str, config: &DnsConfig) -> Result<(), Self::Error>;
 
-    fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<(), Self::Error>;
+    fn reset_interface(&mut self, interface: &str) -> Result<(), Self::Error>;
 
     fn reset(&mut self) -> Result<(), Self::Error>;
+
+    fn manager_name(&self) -> Option<String>;
+
+    fn status(&self, interface: &str) -> Result<DnsStatus, Self::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_connected() {
+        assert!(is_connected(Some(1000), 1000));
+        assert!(is_connected(Some(1000), 1179));
+        assert!(!is_connected(Some(1000), 1180));
+        assert!(!is_connected(None, 1000));
+    }
+
+    #[test]
+    fn test_trafficstat_unspecified() {
+        let stat = PeerTrafficStat::new(
+            "pubk".to_string(),
+            1,
+            2,
+            Some(UNIX_EPOCH),
+            Some("0.0.0.0:0".parse().unwrap()),
+            Some(0),
+            None,
+        );
+
+        assert_eq!(stat.last_handshake, None);
+        assert_eq!(stat.endpoint, None);
+        assert_eq!(stat.keepalive, None);
+        assert!(!stat.connected);
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let ips = vec!["10.0.0.1/24".to_string(), "fd00::1/64".to_string()];
+        assert_eq!(parse_addresses(&ips).unwrap().len(), 2);
+
+        let ips = vec![
+            "10.0.0.1".to_string(),
+            "fd00::1/64".to_string(),
+            "10.0.0.1/33".to_string(),
+        ];
+        assert_eq!(
+            parse_addresses(&ips).unwrap_err(),
+            vec!["Invalid address: 10.0.0.1", "Invalid address: 10.0.0.1/33"]
+        );
+    }
+
+    #[test]
+    fn test_instance() {
+        assert_eq!(instance(), Instance::default());
+        assert_eq!(Instance::new("office-2", None).unwrap().name, "office-2");
+        assert!(Instance::new("", None).is_err());
+        assert!(Instance::new("../etc", None).is_err());
+    }
+
+    #[test]
+    fn test_dns_backend_parse() {
+        for backend in DnsBackend::AUTO {
+            assert_eq!(backend.to_string().parse::<DnsBackend>().unwrap(), backend);
+        }
+        assert!("systemd".parse::<DnsBackend>().is_err());
+    }
+
+    #[test]
+    fn test_dns_config_merge() {
+        let a = DnsConfig {
+            servers: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            search_domains: vec!["corp.example".to_string()],
+            routing_domains: vec!["corp.example".to_string()],
+        };
+        let b = DnsConfig {
+            servers: vec!["10.0.0.2".parse().unwrap(), "fd00::1".parse().unwrap()],
+            search_domains: vec!["lab.example".to_string(), "corp.example".to_string()],
+            routing_domains: vec![],
+        };
+
+        let merged = DnsConfig::merge(&[a, b]);
+        assert_eq!(
+            merged.servers,
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap(),
+                "fd00::1".parse().unwrap(),
+            ]
+        );
+        assert_eq!(merged.search_domains, vec!["corp.example", "lab.example"]);
+        assert!(merged.routing_domains.is_empty());
+    }
+
+    #[test]
+    fn test_route_table_parse() {
+        assert_eq!("auto".parse::<RouteTable>().unwrap(), RouteTable::Auto);
+        assert_eq!("main".parse::<RouteTable>().unwrap(), RouteTable::Main);
+        assert_eq!("off".parse::<RouteTable>().unwrap(), RouteTable::Off);
+        assert_eq!("1234".parse::<RouteTable>().unwrap(), RouteTable::Id(1234));
+        assert!("0".parse::<RouteTable>().is_err());
+        assert!("local".parse::<RouteTable>().is_err());
+    }
+
+    #[test]
+    fn test_route_options() {
+        let mut opts = RouteOptions {
+            metric: Some(100),
+            via: Some("10.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(opts.validate("10.1.0.0/16").is_ok());
+        assert!(opts.validate("2001:db8::/32").is_err());
+        assert!(opts.validate("bogus").is_err());
+
+        opts.via = None;
+        opts.src = Some("fd00::1".parse().unwrap());
+        assert!(opts.validate("2001:db8::/32").is_ok());
+        assert!(opts.validate("10.1.0.0/16").is_err());
+
+        opts.table = Some(RouteTable::Off);
+        assert!(opts.validate("2001:db8::/32").is_err());
+    }
+
+    #[test]
+    fn test_default_route() {
+        assert_eq!(default_route_family("0.0.0.0/0"), Some(IpFamily::V4));
+        assert_eq!(default_route_family("::/0"), Some(IpFamily::V6));
+        assert_eq!(default_route_family("::/1"), None);
+
+        for cidr in [
+            "0.0.0.0/0",
+            "0.0.0.0/1",
+            "128.0.0.0/1",
+            "::/0",
+            "::/1",
+            "8000::/1",
+        ] {
+            assert!(is_default_route(cidr), "{}", cidr);
+        }
+        assert!(!is_default_route("10.0.0.0/8"));
+        assert!(!is_default_route("2000::/3"));
+        assert!(!is_default_route("bogus"));
+        // Bare addresses are host routes
+        assert!(!is_default_route("10.0.0.1"));
+        assert!(RouteOptions::default().validate("10.0.0.1").is_ok());
+    }
 }
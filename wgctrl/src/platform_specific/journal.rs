@@ -0,0 +1,296 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(not(test))]
+use std::sync::Mutex;
+
+#[cfg(not(test))]
+use lazy_static::lazy_static;
+use rocket::serde::json;
+
+use super::common::{DnsBackend, IpFamily};
+use crate::error::VpnctrlError;
+
+/// System change which outlives the daemon, recorded so that it can be undone after a crash
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Mutation {
+    /// Interface we created
+    Interface {
+        name: String,
+    },
+    Address {
+        interface: String,
+        address: String,
+    },
+    Route {
+        interface: String,
+        destination: String,
+        table: u32,
+    },
+    /// `not fwmark <fwmark> lookup <table>` rule, or `fwmark <fwmark> lookup <table>` if `mark`
+    Rule {
+        fwmark: u32,
+        table: u32,
+        priority: u32,
+        mark: bool,
+    },
+    /// `to <destination> lookup <table>` rule
+    BypassRule {
+        destination: String,
+        table: u32,
+        priority: u32,
+    },
+    NftTable {
+        name: String,
+    },
+    /// Default route of `family` through `nexthop_type` `nexthop`, which we removed
+    DefaultRoute {
+        family: IpFamily,
+        nexthop_type: String,
+        nexthop: String,
+    },
+    /// Host route to `destination` through the default route rather than the tunnels
+    BypassRoute {
+        destination: String,
+    },
+    /// Kernel parameter at `path`, to be set back to `value`
+    Sysctl {
+        path: String,
+        value: String,
+    },
+    /// DNS set through `backend`, on `interface` or globally
+    Dns {
+        backend: DnsBackend,
+        interface: Option<String>,
+    },
+}
+
+struct Journal {
+    path: PathBuf,
+    entries: Vec<Mutation>,
+}
+
+impl Journal {
+    /// Returns the journal at `path`, along with what it holds, oldest first
+    fn open(path: &Path) -> io::Result<(Self, Vec<Mutation>)> {
+        let leftovers = match fs::read_to_string(path) {
+            Ok(x) => json::from_str(&x).unwrap_or_else(|e| {
+                log::warn!("Ignoring corrupted journal {}: {}", path.display(), e);
+                vec![]
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Leftovers stay until undone, in case we crash again while recovering
+        let journal = Journal {
+            path: path.to_path_buf(),
+            entries: leftovers.clone(),
+        };
+        journal.save()?;
+        Ok((journal, leftovers))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data =
+            json::to_string(&self.entries).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn update(&mut self, f: impl FnOnce(&mut Vec<Mutation>) -> bool) {
+        if f(&mut self.entries) {
+            if let Err(e) = self.save() {
+                log::error!("Failed to save journal: {}", e);
+            }
+        }
+    }
+
+    fn record(&mut self, mutation: Mutation) {
+        self.update(|entries| {
+            if entries.contains(&mutation) {
+                return false;
+            }
+            entries.push(mutation);
+            true
+        })
+    }
+
+    fn forget_where(&mut self, f: impl Fn(&Mutation) -> bool) {
+        self.update(|entries| {
+            let len = entries.len();
+            entries.retain(|x| !f(x));
+            entries.len() != len
+        })
+    }
+
+    fn forget(&mut self, mutation: &Mutation) {
+        self.forget_where(|x| x == mutation)
+    }
+
+    /// Removes the journal once nothing is left in it. Changes which could not be undone stay,
+    /// so that the next start tries again.
+    fn close(self) {
+        if !self.entries.is_empty() {
+            log::warn!(
+                "Keeping {} system changes which could not be undone for the next start",
+                self.entries.len()
+            );
+            if let Err(e) = self.save() {
+                log::error!("Failed to save journal: {}", e);
+            }
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::error!("Failed to remove journal: {}", e);
+        }
+    }
+}
+
+#[cfg(not(test))]
+lazy_static! {
+    static ref JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+}
+
+#[cfg(not(test))]
+fn with_journal<R>(f: impl FnOnce(&mut Option<Journal>) -> R) -> R {
+    f(&mut JOURNAL.lock().unwrap())
+}
+
+/// Tests run on threads of their own, so each of them gets a journal nobody else records to
+#[cfg(test)]
+fn with_journal<R>(f: impl FnOnce(&mut Option<Journal>) -> R) -> R {
+    thread_local! {
+        static JOURNAL: std::cell::RefCell<Option<Journal>> = std::cell::RefCell::new(None);
+    }
+    JOURNAL.with(|x| f(&mut x.borrow_mut()))
+}
+
+/// Starts journaling to `path`. Returns what a run which did not close its journal left behind,
+/// oldest first.
+pub fn open(path: &Path) -> io::Result<Vec<Mutation>> {
+    let (journal, leftovers) = Journal::open(path)?;
+    with_journal(|x| *x = Some(journal));
+    Ok(leftovers)
+}
+
+/// Records `mutation` once it is made. Does nothing unless the journal is open.
+pub fn record(mutation: Mutation) {
+    with_journal(|x| {
+        if let Some(journal) = x.as_mut() {
+            journal.record(mutation);
+        }
+    })
+}
+
+/// Drops `mutation` once it is undone
+pub fn forget(mutation: &Mutation) {
+    with_journal(|x| {
+        if let Some(journal) = x.as_mut() {
+            journal.forget(mutation);
+        }
+    })
+}
+
+/// Drops every mutation `f` matches, once they went away along with something else
+pub fn forget_where(f: impl Fn(&Mutation) -> bool) {
+    with_journal(|x| {
+        if let Some(journal) = x.as_mut() {
+            journal.forget_where(f);
+        }
+    })
+}
+
+/// Undo of changes a platform never makes
+pub(crate) fn unsupported(mutation: &Mutation) -> Result<(), VpnctrlError> {
+    log::warn!("Don't know how to undo {:?}", mutation);
+    Ok(())
+}
+
+/// Undoes `leftovers` of a previous run, newest first. What fails is kept for the next start.
+pub fn recover(leftovers: &[Mutation]) {
+    for mutation in leftovers.iter().rev() {
+        match super::undo(mutation) {
+            Ok(_) => {
+                log::info!("Undid {:?}", mutation);
+                forget(mutation);
+            }
+            Err(e) => log::error!("Failed to undo {:?}: {}", mutation, e),
+        }
+    }
+}
+
+/// Marks a clean exit. Only what could not be undone is left in the journal.
+pub fn close() {
+    if let Some(journal) = with_journal(|x| x.take()) {
+        journal.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal() {
+        let path = std::env::temp_dir().join(format!("wgctrl-journal-{}.json", std::process::id()));
+        let route = Mutation::Route {
+            interface: "wg0".to_string(),
+            destination: "10.0.0.0/24".to_string(),
+            table: 51820,
+        };
+        let dns = Mutation::Dns {
+            backend: DnsBackend::StaticFile,
+            interface: None,
+        };
+
+        let (mut journal, leftovers) = Journal::open(&path).unwrap();
+        assert!(leftovers.is_empty());
+        journal.record(route.clone());
+        journal.record(dns.clone());
+        journal.record(route.clone());
+        journal.forget(&route);
+        journal.record(route.clone());
+
+        // As if we had crashed
+        let (journal, leftovers) = Journal::open(&path).unwrap();
+        assert_eq!(leftovers, vec![dns.clone(), route.clone()]);
+        let data = fs::read_to_string(&path).unwrap();
+        assert!(data.contains(r#"{"kind":"dns","backend":"static-file","interface":null}"#));
+
+        // What could not be undone is kept
+        journal.close();
+        let (mut journal, leftovers) = Journal::open(&path).unwrap();
+        assert_eq!(leftovers, vec![dns.clone(), route.clone()]);
+
+        journal.forget(&dns);
+        journal.forget(&route);
+        journal.close();
+        assert!(!path.exists());
+    }
+}
@@ -32,10 +32,21 @@ use self::{network_manager::NetworkManager, systemd_resolved::SystemdResolved};
 
 use self::{resolvconf::Resolvconf, static_resolv_conf::StaticResolvConf};
 
-use std::{env, fmt, net::IpAddr};
+use super::super::common::{DnsBackend, DnsConfig, DnsStatus};
+use super::super::journal::{self, Mutation};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs,
+    net::IpAddr,
+};
+use talpid_types::ErrorExt;
 
 const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
 
+/// Environment variable forcing a single backend, superseded by `[dns] backend`
+const LEGACY_DNS_MODULE_VAR: &str = "TALPID_DNS_MODULE";
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Errors that can happen in the Linux DNS monitor
@@ -59,6 +70,10 @@ pub enum Error {
     #[error(display = "Error in static /etc/resolv.conf DNS monitor")]
     StaticResolvConf(#[error(source)] static_resolv_conf::Error),
 
+    /// Backend left out of this build
+    #[error(display = "{} support is not built in", _0)]
+    NotBuiltIn(DnsBackend),
+
     /// No suitable DNS monitor implementation detected
     #[error(display = "No suitable DNS monitor implementation detected")]
     NoDnsMonitor,
@@ -66,35 +81,186 @@ pub enum Error {
 
 pub struct DnsMonitor {
     handle: tokio::runtime::Handle,
-    inner: Option<DnsMonitorHolder>,
+    /// Backends to try, in order
+    backends: Vec<DnsBackend>,
+    /// Managers configuring a single link, by interface
+    links: HashMap<String, DnsMonitorHolder>,
+    /// Manager of the global configuration, which gets the merge of `global_configs`
+    global: Option<DnsMonitorHolder>,
+    global_configs: BTreeMap<String, DnsConfig>,
+}
+
+impl DnsMonitor {
+    fn apply_global(&mut self) -> Result<()> {
+        let interface = match self.global_configs.keys().next() {
+            Some(x) => x.clone(),
+            None => match self.global.take() {
+                Some(mut global) => {
+                    global.reset(&self.handle)?;
+                    journal::forget(&global.mutation(None));
+                    return Ok(());
+                }
+                None => return Ok(()),
+            },
+        };
+        let merged = DnsConfig::merge(self.global_configs.values());
+        match self.global.as_mut() {
+            Some(global) => {
+                global.set(&self.handle, &interface, &merged)?;
+                journal::record(global.mutation(None));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Backend forced through the environment, as older releases allowed
+fn legacy_backend() -> Option<DnsBackend> {
+    let value = env::var(LEGACY_DNS_MODULE_VAR).ok()?;
+    let backend = match value.as_str() {
+        "static-file" => DnsBackend::StaticFile,
+        "resolvconf" => DnsBackend::Resolvconf,
+        "systemd" => DnsBackend::SystemdResolved,
+        "network-manager" => DnsBackend::NetworkManager,
+        _ => {
+            log::warn!("Ignoring unknown {}={}", LEGACY_DNS_MODULE_VAR, value);
+            return None;
+        }
+    };
+    log::warn!(
+        "{} is deprecated, set backend = \"{}\" in the [dns] section of the config instead",
+        LEGACY_DNS_MODULE_VAR,
+        backend
+    );
+    Some(backend)
 }
 
 impl super::super::common::DnsMonitorT for DnsMonitor {
     type Error = Error;
 
-    fn new(handle: tokio::runtime::Handle) -> Result<Self> {
+    fn new(handle: tokio::runtime::Handle, backends: &[DnsBackend]) -> Result<Self> {
+        let backends = match legacy_backend() {
+            Some(backend) => vec![backend],
+            None => backends.to_vec(),
+        };
         Ok(DnsMonitor {
             handle,
-            inner: None,
+            backends,
+            links: HashMap::new(),
+            global: None,
+            global_configs: BTreeMap::new(),
         })
     }
 
-    fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<()> {
-        self.reset()?;
+    fn set(&mut self, interface: &str, config: &DnsConfig) -> Result<()> {
+        self.reset_interface(interface)?;
+        if config.servers.is_empty() {
+            return Ok(());
+        }
+
         // Creating a new DNS monitor for each set, in case the system changed how it manages DNS.
-        let mut inner = DnsMonitorHolder::new()?;
-        if !servers.is_empty() {
-            inner.set(&self.handle, interface, servers)?;
-            self.inner = Some(inner);
+        let mut inner = DnsMonitorHolder::new(&self.backends)?;
+        if inner.is_per_link() {
+            inner.set(&self.handle, interface, config)?;
+            journal::record(inner.mutation(Some(interface)));
+            self.links.insert(interface.to_string(), inner);
+            return Ok(());
+        }
+
+        if let Some(mut old) = self.global.replace(inner) {
+            old.reset(&self.handle)?;
+            journal::forget(&old.mutation(None));
+        }
+        self.global_configs
+            .insert(interface.to_string(), config.clone());
+        self.apply_global()
+    }
+
+    fn probe(backends: &[DnsBackend]) -> Vec<(DnsBackend, std::result::Result<(), String>)> {
+        backends
+            .iter()
+            .map(|backend| {
+                let res = match backend {
+                    // Setting up the monitor would already restore a left over backup
+                    DnsBackend::StaticFile => {
+                        static_resolv_conf::check().map_err(Error::StaticResolvConf)
+                    }
+                    _ => DnsMonitorHolder::with_backend(*backend).map(|_| ()),
+                };
+                (*backend, res.map_err(|e| e.display_chain()))
+            })
+            .collect()
+    }
+
+    fn reset_interface(&mut self, interface: &str) -> Result<()> {
+        if let Some(mut inner) = self.links.remove(interface) {
+            inner.reset(&self.handle)?;
+            journal::forget(&inner.mutation(Some(interface)));
+            return Ok(());
+        }
+        if self.global_configs.remove(interface).is_some() {
+            return self.apply_global();
         }
         Ok(())
     }
 
     fn reset(&mut self) -> Result<()> {
-        if let Some(mut inner) = self.inner.take() {
+        let mut result = Ok(());
+        for (interface, mut inner) in self.links.drain() {
+            match inner.reset(&self.handle) {
+                Ok(_) => journal::forget(&inner.mutation(Some(&interface))),
+                Err(e) => result = Err(e),
+            }
+        }
+        self.global_configs.clear();
+        if let Some(mut inner) = self.global.take() {
             inner.reset(&self.handle)?;
+            journal::forget(&inner.mutation(None));
+        }
+        result
+    }
+
+    fn manager_name(&self) -> Option<String> {
+        self.links
+            .values()
+            .next()
+            .or(self.global.as_ref())
+            .map(|inner| inner.to_string())
+    }
+
+    fn status(&self, interface: &str) -> Result<DnsStatus> {
+        let inner = match self.links.get(interface) {
+            Some(x) => Some(x),
+            None if self.global_configs.contains_key(interface) => self.global.as_ref(),
+            None => None,
+        };
+        match inner {
+            Some(inner) => Ok(DnsStatus {
+                manager: Some(inner.to_string()),
+                resolvers: inner.resolvers(),
+                corrections: inner.corrections(),
+            }),
+            None => Ok(DnsStatus {
+                manager: None,
+                resolvers: Some(resolv_conf_servers()),
+                corrections: None,
+            }),
+        }
+    }
+}
+
+/// Name servers listed in /etc/resolv.conf
+fn resolv_conf_servers() -> Vec<IpAddr> {
+    let config = fs::read_to_string(RESOLV_CONF_PATH)
+        .ok()
+        .and_then(|x| resolv_conf::Config::parse(&x).ok());
+    match config {
+        Some(config) => config.nameservers.into_iter().map(|x| x.into()).collect(),
+        None => {
+            log::warn!("Failed to read name servers from {}", RESOLV_CONF_PATH);
+            vec![]
         }
-        Ok(())
     }
 }
 
@@ -123,72 +289,120 @@ impl fmt::Display for DnsMonitorHolder {
 }
 
 impl DnsMonitorHolder {
-    fn new() -> Result<Self> {
-        let dns_module = env::var_os("TALPID_DNS_MODULE");
+    fn new(backends: &[DnsBackend]) -> Result<Self> {
+        for backend in backends {
+            match Self::with_backend(*backend) {
+                Ok(manager) => {
+                    log::debug!("Managing DNS via {}", manager);
+                    return Ok(manager);
+                }
+                Err(error) => log::debug!("Not using {}: {}", backend, error.display_chain()),
+            }
+        }
+        Err(Error::NoDnsMonitor)
+    }
 
-        let manager = match dns_module.as_ref().and_then(|value| value.to_str()) {
-            Some("static-file") => DnsMonitorHolder::StaticResolvConf(StaticResolvConf::new()?),
-            Some("resolvconf") => DnsMonitorHolder::Resolvconf(Resolvconf::new()?),
+    fn with_backend(backend: DnsBackend) -> Result<Self> {
+        Ok(match backend {
+            DnsBackend::StaticFile => DnsMonitorHolder::StaticResolvConf(StaticResolvConf::new()?),
+            DnsBackend::Resolvconf => DnsMonitorHolder::Resolvconf(Resolvconf::new()?),
             #[cfg(feature = "dbus")]
-            Some("systemd") => DnsMonitorHolder::SystemdResolved(SystemdResolved::new()?),
+            DnsBackend::SystemdResolved => {
+                DnsMonitorHolder::SystemdResolved(SystemdResolved::new()?)
+            }
             #[cfg(feature = "dbus")]
-            Some("network-manager") => DnsMonitorHolder::NetworkManager(NetworkManager::new()?),
-            Some(_) | None => Self::with_detected_dns_manager()?,
-        };
-        log::debug!("Managing DNS via {}", manager);
-        Ok(manager)
-    }
-
-    fn with_detected_dns_manager() -> Result<Self> {
-        #[cfg(not(feature = "dbus"))]
-        return Resolvconf::new()
-            .map(DnsMonitorHolder::Resolvconf)
-            .or_else(|_| StaticResolvConf::new().map(DnsMonitorHolder::StaticResolvConf))
-            .map_err(|_| Error::NoDnsMonitor);
-
-        #[cfg(feature = "dbus")]
-        SystemdResolved::new()
-            .map(DnsMonitorHolder::SystemdResolved)
-            .or_else(|err| {
-                match err {
-                    systemd_resolved::Error::SystemdResolvedError(
-                        systemd_resolved::SystemdDbusError::NoSystemdResolved(_),
-                    ) => (),
-                    other_error => {
-                        log::debug!("NetworkManager is not being used because {}", other_error)
-                    }
-                }
-                NetworkManager::new().map(DnsMonitorHolder::NetworkManager)
-            })
-            .or_else(|_| Resolvconf::new().map(DnsMonitorHolder::Resolvconf))
-            .or_else(|_| StaticResolvConf::new().map(DnsMonitorHolder::StaticResolvConf))
-            .map_err(|_| Error::NoDnsMonitor)
+            DnsBackend::NetworkManager => DnsMonitorHolder::NetworkManager(NetworkManager::new()?),
+            #[cfg(not(feature = "dbus"))]
+            DnsBackend::SystemdResolved | DnsBackend::NetworkManager => {
+                return Err(Error::NotBuiltIn(backend))
+            }
+        })
+    }
+
+    fn backend(&self) -> DnsBackend {
+        match self {
+            #[cfg(feature = "dbus")]
+            DnsMonitorHolder::SystemdResolved(..) => DnsBackend::SystemdResolved,
+            #[cfg(feature = "dbus")]
+            DnsMonitorHolder::NetworkManager(..) => DnsBackend::NetworkManager,
+            DnsMonitorHolder::Resolvconf(..) => DnsBackend::Resolvconf,
+            DnsMonitorHolder::StaticResolvConf(..) => DnsBackend::StaticFile,
+        }
+    }
+
+    /// Journal entry of settings made on `interface`, or globally
+    fn mutation(&self, interface: Option<&str>) -> Mutation {
+        Mutation::Dns {
+            backend: self.backend(),
+            interface: interface.map(|x| x.to_string()),
+        }
+    }
+
+    /// Whether the manager configures each link on its own, rather than the whole system
+    fn is_per_link(&self) -> bool {
+        !matches!(self, DnsMonitorHolder::StaticResolvConf(..))
     }
 
     fn set(
         &mut self,
         handle: &tokio::runtime::Handle,
         interface: &str,
-        servers: &[IpAddr],
+        config: &DnsConfig,
     ) -> Result<()> {
         use self::DnsMonitorHolder::*;
-        match self {
-            Resolvconf(ref mut resolvconf) => resolvconf.set_dns(interface, servers)?,
+        let servers = &config.servers;
+        // Which of search and routing domains the manager honours
+        let (search, routing) = match self {
+            Resolvconf(ref mut resolvconf) => {
+                resolvconf.set_dns(interface, servers, &config.search_domains)?;
+                (true, false)
+            }
             StaticResolvConf(ref mut static_resolv_conf) => {
-                static_resolv_conf.set_dns(servers.to_vec())?
+                static_resolv_conf.set_dns(servers.to_vec(), config.search_domains.clone())?;
+                (true, false)
             }
             #[cfg(feature = "dbus")]
             SystemdResolved(ref mut systemd_resolved) => {
-                handle.block_on(systemd_resolved.set_dns(interface, &servers))?
+                handle.block_on(systemd_resolved.set_dns(interface, config))?;
+                (true, true)
             }
             #[cfg(feature = "dbus")]
             NetworkManager(ref mut network_manager) => {
-                network_manager.set_dns(interface, servers)?
+                network_manager.set_dns(interface, servers)?;
+                (false, false)
             }
+        };
+        if !search && !config.search_domains.is_empty() {
+            log::warn!("{} does not support search domains, ignoring them", self);
+        }
+        if !routing && !config.routing_domains.is_empty() {
+            log::warn!(
+                "{} does not support routing domains, all names are resolved through {}",
+                self,
+                interface
+            );
         }
         Ok(())
     }
 
+    /// Resolvers currently in use, where we watch for them being overwritten. The other
+    /// managers would only report back what we set, so nothing is claimed for them.
+    fn resolvers(&self) -> Option<Vec<IpAddr>> {
+        match self {
+            DnsMonitorHolder::StaticResolvConf(_) => Some(resolv_conf_servers()),
+            _ => None,
+        }
+    }
+
+    fn corrections(&self) -> Option<u64> {
+        match self {
+            DnsMonitorHolder::StaticResolvConf(ref static_resolv_conf) => {
+                Some(static_resolv_conf.corrections())
+            }
+            _ => None,
+        }
+    }
+
     fn reset(&mut self, handle: &tokio::runtime::Handle) -> Result<()> {
         use self::DnsMonitorHolder::*;
         match self {
@@ -205,6 +419,17 @@ impl DnsMonitorHolder {
     }
 }
 
+/// Takes back DNS set by a previous run
+pub fn undo(backend: DnsBackend, interface: Option<&str>) -> Result<()> {
+    match (backend, interface) {
+        (DnsBackend::StaticFile, _) => static_resolv_conf::restore_from_backup()?,
+        (DnsBackend::Resolvconf, Some(interface)) => Resolvconf::remove_stale(interface)?,
+        // Link settings of systemd-resolved and NetworkManager went away along with the link
+        _ => {}
+    }
+    Ok(())
+}
+
 /// Returns true if DnsMonitor will use NetworkManager to manage DNS.
 #[cfg(feature = "dbus")]
 pub fn will_use_nm() -> bool {
@@ -28,6 +28,8 @@ use std::{
 
 use which::which;
 
+use super::super::super::common::instance;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(err_derive::Error, Debug)]
@@ -54,6 +56,10 @@ pub enum Error {
     ResolvconfNotInUseError,
 }
 
+fn record_name(interface: &str) -> String {
+    format!("{}.{}", interface, instance().name)
+}
+
 pub struct Resolvconf {
     record_names: HashSet<String>,
     resolvconf: PathBuf,
@@ -95,10 +101,21 @@ impl Resolvconf {
             .unwrap_or_else(|_| false)
     }
 
-    pub fn set_dns(&mut self, interface: &str, servers: &[IpAddr]) -> Result<()> {
-        let record_name = format!("{}.mullvad", interface);
+    pub fn set_dns(
+        &mut self,
+        interface: &str,
+        servers: &[IpAddr],
+        search_domains: &[String],
+    ) -> Result<()> {
+        let record_name = record_name(interface);
         let mut record_contents = String::new();
 
+        if !search_domains.is_empty() {
+            record_contents.push_str("search ");
+            record_contents.push_str(&search_domains.join(" "));
+            record_contents.push('\n');
+        }
+
         for address in servers {
             record_contents.push_str("nameserver ");
             record_contents.push_str(&address.to_string());
@@ -145,6 +162,16 @@ impl Resolvconf {
         result
     }
 
+    /// Deletes the record a previous run left for `interface`, without the checks of `new`
+    pub fn remove_stale(interface: &str) -> Result<()> {
+        let mut resolvconf = Resolvconf {
+            record_names: HashSet::new(),
+            resolvconf: which("resolvconf").map_err(|_| Error::NoResolvconf)?,
+        };
+        resolvconf.record_names.insert(record_name(interface));
+        resolvconf.reset()
+    }
+
     fn is_dnsmasq_running() -> bool {
         let pid = match fs::read_to_string("/var/run/dnsmasq/dnsmasq.pid") {
             Ok(pid) => pid,
@@ -18,6 +18,7 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use super::super::super::common::instance;
 use super::RESOLV_CONF_PATH;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use parking_lot::Mutex;
@@ -25,12 +26,14 @@ use resolv_conf::{Config, ScopedIp};
 use std::{
     fs, io,
     net::IpAddr,
+    path::{Path, PathBuf},
     sync::{mpsc, Arc},
     thread,
 };
 use talpid_types::ErrorExt;
 
-const RESOLV_CONF_BACKUP_PATH: &str = "/etc/resolv.conf.mullvadbackup";
+/// Backup left by the Mullvad client, which this code comes from
+const MULLVAD_BACKUP_PATH: &str = "/etc/resolv.conf.mullvadbackup";
 const RESOLV_CONF_DIR: &str = "/etc/";
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -49,8 +52,24 @@ pub enum Error {
     #[error(display = "resolv.conf at {} could not be parsed", _0)]
     ParseError(&'static str, #[error(source)] resolv_conf::ParseError),
 
+    #[error(display = "Failed to write resolv.conf backup to {}", _0)]
+    WriteBackup(String, #[error(source)] io::Error),
+
+    #[error(display = "Failed to read resolv.conf backup from {}", _0)]
+    ReadBackup(String, #[error(source)] io::Error),
+
+    #[error(display = "resolv.conf backup at {} could not be parsed", _0)]
+    ParseBackup(String, #[error(source)] resolv_conf::ParseError),
+
     #[error(display = "Failed to remove stale resolv.conf backup at {}", _0)]
-    RemoveBackup(&'static str, #[error(source)] io::Error),
+    RemoveBackup(String, #[error(source)] io::Error),
+
+    #[error(
+        display = "resolv.conf backup at {} was not made by instance {}, leaving it alone",
+        _0,
+        _1
+    )]
+    ForeignBackup(String, String),
 }
 
 pub struct StaticResolvConf {
@@ -71,7 +90,7 @@ impl StaticResolvConf {
         })
     }
 
-    pub fn set_dns(&mut self, servers: Vec<IpAddr>) -> Result<()> {
+    pub fn set_dns(&mut self, servers: Vec<IpAddr>, search_domains: Vec<String>) -> Result<()> {
         let mut state = self.state.lock();
         let new_state = match state.take() {
             None => {
@@ -81,11 +100,15 @@ impl StaticResolvConf {
                 State {
                     backup,
                     desired_dns: servers,
+                    search_domains,
+                    corrections: 0,
                 }
             }
             Some(previous_state) => State {
                 backup: previous_state.backup,
                 desired_dns: servers,
+                search_domains,
+                corrections: previous_state.corrections,
             },
         };
 
@@ -96,10 +119,19 @@ impl StaticResolvConf {
         write_config(&new_config)
     }
 
+    /// Times /etc/resolv.conf was found changed and our servers put back
+    pub fn corrections(&self) -> u64 {
+        self.state
+            .lock()
+            .as_ref()
+            .map(|x| x.corrections)
+            .unwrap_or(0)
+    }
+
     pub fn reset(&mut self) -> Result<()> {
         if let Some(state) = self.state.lock().take() {
             write_config(&state.backup)?;
-            let _ = fs::remove_file(RESOLV_CONF_BACKUP_PATH);
+            let _ = fs::remove_file(backup_path());
         }
 
         Ok(())
@@ -109,6 +141,9 @@ impl StaticResolvConf {
 struct State {
     backup: Config,
     desired_dns: Vec<IpAddr>,
+    /// Replaces the search list of the backup when not empty
+    search_domains: Vec<String>,
+    corrections: u64,
 }
 
 impl State {
@@ -120,6 +155,9 @@ impl State {
             .iter()
             .map(|&address| ScopedIp::from(address))
             .collect();
+        if !self.search_domains.is_empty() {
+            config.set_search(self.search_domains.clone());
+        }
 
         config
     }
@@ -173,14 +211,21 @@ impl DnsWatcher {
                 .map(|&address| ScopedIp::from(address))
                 .collect();
 
-            if new_config.nameservers != desired_nameservers {
-                state.backup = new_config.clone();
-                new_config.nameservers = desired_nameservers;
+            let search_changed = !state.search_domains.is_empty()
+                && new_config.get_search() != Some(&state.search_domains);
 
-                write_config(&new_config)
+            if new_config.nameservers != desired_nameservers || search_changed {
+                state.backup = new_config;
+                state.corrections += 1;
+                log::debug!("/etc/resolv.conf was changed, restoring our DNS servers");
+
+                write_config(&state.desired_config())
             } else {
                 new_config.nameservers.clear();
                 new_config.nameservers.append(&mut state.backup.nameservers);
+                if let Some(search) = state.backup.get_search() {
+                    new_config.set_search(search.clone());
+                }
                 state.backup = new_config;
 
                 write_backup(&state.backup)
@@ -191,6 +236,28 @@ impl DnsWatcher {
     }
 }
 
+/// Whether /etc/resolv.conf can be taken over, without touching it
+pub fn check() -> Result<()> {
+    read_config()?;
+    read_backup()?;
+    if Path::new(MULLVAD_BACKUP_PATH).exists() {
+        log::warn!(
+            "Found {}, left by the Mullvad client or a release before instances. It is never \
+             restored automatically, restore or remove it by hand once nothing uses it.",
+            MULLVAD_BACKUP_PATH
+        );
+    }
+    let metadata =
+        fs::metadata(RESOLV_CONF_DIR).map_err(|e| Error::WriteResolvConf(RESOLV_CONF_DIR, e))?;
+    if metadata.permissions().readonly() {
+        return Err(Error::WriteResolvConf(
+            RESOLV_CONF_DIR,
+            io::Error::from(io::ErrorKind::PermissionDenied),
+        ));
+    }
+    Ok(())
+}
+
 fn read_config() -> Result<Config> {
     if !std::path::Path::new(RESOLV_CONF_PATH).exists() {
         return Ok(Config::new());
@@ -208,27 +275,66 @@ fn write_config(config: &Config) -> Result<()> {
         .map_err(|e| Error::WriteResolvConf(RESOLV_CONF_PATH, e))
 }
 
+fn backup_path() -> PathBuf {
+    let instance = instance();
+    instance
+        .backup_dir
+        .unwrap_or_else(|| PathBuf::from(RESOLV_CONF_DIR))
+        .join(format!("resolv.conf.{}.backup", instance.name))
+}
+
+/// First line of our backups, telling them apart from those of other instances
+fn backup_header() -> String {
+    format!(
+        "# Backup of {} by instance {}",
+        RESOLV_CONF_PATH,
+        instance().name
+    )
+}
+
 fn write_backup(backup: &Config) -> Result<()> {
-    fs::write(RESOLV_CONF_BACKUP_PATH, backup.to_string().as_bytes())
-        .map_err(|e| Error::WriteResolvConf(RESOLV_CONF_BACKUP_PATH, e))
+    let path = backup_path();
+    let write = || {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, format!("{}\n{}", backup_header(), backup))
+    };
+    write().map_err(|e| Error::WriteBackup(path.display().to_string(), e))
 }
 
-fn restore_from_backup() -> Result<()> {
-    match fs::read_to_string(RESOLV_CONF_BACKUP_PATH) {
-        Ok(backup) => {
-            log::info!("Restoring DNS state from backup");
-            let config = Config::parse(&backup)
-                .map_err(|e| Error::ParseError(RESOLV_CONF_BACKUP_PATH, e))?;
+/// Our backup, if there is one. Anything else found in its place is an error.
+fn read_backup() -> Result<Option<Config>> {
+    let path = backup_path();
+    let backup = match fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(Error::ReadBackup(path.display().to_string(), error)),
+    };
+
+    if backup.lines().next() != Some(backup_header().as_str()) {
+        return Err(Error::ForeignBackup(
+            path.display().to_string(),
+            instance().name,
+        ));
+    }
+    Config::parse(&backup)
+        .map(Some)
+        .map_err(|e| Error::ParseBackup(path.display().to_string(), e))
+}
 
+pub fn restore_from_backup() -> Result<()> {
+    match read_backup()? {
+        Some(config) => {
+            log::info!("Restoring DNS state from backup");
             write_config(&config)?;
 
-            fs::remove_file(RESOLV_CONF_BACKUP_PATH)
-                .map_err(|e| Error::RemoveBackup(RESOLV_CONF_BACKUP_PATH, e))
+            let path = backup_path();
+            fs::remove_file(&path).map_err(|e| Error::RemoveBackup(path.display().to_string(), e))
         }
-        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+        None => {
             log::debug!("No DNS state backup to restore");
             Ok(())
         }
-        Err(error) => Err(Error::ReadResolvConf(RESOLV_CONF_BACKUP_PATH, error)),
     }
 }
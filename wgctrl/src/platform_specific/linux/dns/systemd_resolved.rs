@@ -18,8 +18,8 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use super::super::super::common::DnsConfig;
 use super::iface::{iface_index, IfaceIndexLookupError};
-use std::net::IpAddr;
 use talpid_dbus::systemd_resolved::{AsyncHandle, SystemdResolved as DbusInterface};
 use talpid_types::ErrorExt;
 
@@ -53,7 +53,7 @@ impl SystemdResolved {
         Ok(systemd_resolved)
     }
 
-    pub async fn set_dns(&mut self, interface_name: &str, servers: &[IpAddr]) -> Result<()> {
+    pub async fn set_dns(&mut self, interface_name: &str, config: &DnsConfig) -> Result<()> {
         let tunnel_index = iface_index(interface_name)?;
         self.tunnel_index = tunnel_index;
 
@@ -63,13 +63,32 @@ impl SystemdResolved {
 
         let _ = self
             .dbus_interface
-            .set_dns(self.tunnel_index, servers.to_vec())
+            .set_dns(self.tunnel_index, config.servers.clone())
+            .await?;
+
+        // Routing-only domains are prefixed with `~` by resolved, the flag marks them
+        let domains = config
+            .search_domains
+            .iter()
+            .map(|x| (x.clone(), false))
+            .chain(config.routing_domains.iter().map(|x| (x.clone(), true)))
+            .collect::<Vec<_>>();
+        self.dbus_interface
+            .set_domains(self.tunnel_index, domains)
             .await?;
 
         Ok(())
     }
 
     pub async fn reset(&mut self) -> Result<()> {
+        if let Err(error) = self
+            .dbus_interface
+            .set_domains(self.tunnel_index, vec![])
+            .await
+        {
+            log::debug!("Failed to clear DNS domains: {}", error.display_chain());
+        }
+
         let _ = self
             .dbus_interface
             .set_dns(self.tunnel_index, vec![])
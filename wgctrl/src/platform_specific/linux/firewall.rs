@@ -0,0 +1,293 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+
+use super::super::common::{
+    DnsLeakPolicy, FirewallPolicy, PlatformFirewall, LINK_NETWORKS, PRIVATE_NETWORKS,
+};
+use super::nft;
+use super::split_tunnel::SPLIT_TUNNEL_MARK;
+use crate::error::VpnctrlError;
+
+const FIREWALL_NFT_TABLE: &str = "mareel_fw";
+const DNS_NFT_TABLE: &str = "mareel_dns";
+
+fn family(net: &str) -> &'static str {
+    if net.contains(':') {
+        "ip6"
+    } else {
+        "ip"
+    }
+}
+
+/// Traffic allowed whatever the policy: loopback, DHCP, neighbour discovery and replies
+fn base_rules(output: &mut String, input: &mut String, allow_lan: bool) {
+    output.push_str("        oifname \"lo\" accept\n");
+    output.push_str("        udp sport 68 udp dport 67 accept\n");
+    output.push_str("        udp sport 546 udp dport 547 accept\n");
+    output.push_str("        icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept\n");
+
+    input.push_str("        iifname \"lo\" accept\n");
+    input.push_str("        ct state established,related accept\n");
+    input.push_str("        udp sport 67 udp dport 68 accept\n");
+    input.push_str("        udp sport 547 udp dport 546 accept\n");
+    input.push_str("        icmpv6 type { nd-router-advert, nd-neighbor-solicit, nd-neighbor-advert, nd-redirect } accept\n");
+
+    if allow_lan {
+        for net in PRIVATE_NETWORKS.iter().chain(LINK_NETWORKS) {
+            writeln!(output, "        {} daddr {} accept", family(net), net).unwrap();
+            writeln!(input, "        {} saddr {} accept", family(net), net).unwrap();
+        }
+    }
+}
+
+fn ruleset(policy: &FirewallPolicy) -> Option<String> {
+    let mut output = String::new();
+    let mut input = String::new();
+
+    match policy {
+        FirewallPolicy::Off => return None,
+        FirewallPolicy::Blocked { allow_lan } => base_rules(&mut output, &mut input, *allow_lan),
+        FirewallPolicy::Connected {
+            tunnels,
+            endpoints,
+            listen_ports,
+            allow_lan,
+        } => {
+            base_rules(&mut output, &mut input, *allow_lan);
+            for tunnel in tunnels {
+                writeln!(output, "        oifname \"{}\" accept", tunnel).unwrap();
+                writeln!(input, "        iifname \"{}\" accept", tunnel).unwrap();
+            }
+            for endpoint in endpoints {
+                let ip = match endpoint {
+                    SocketAddr::V4(x) => format!("ip daddr {}", x.ip()),
+                    SocketAddr::V6(x) => format!("ip6 daddr {}", x.ip()),
+                };
+                writeln!(
+                    output,
+                    "        {} udp dport {} accept",
+                    ip,
+                    endpoint.port()
+                )
+                .unwrap();
+            }
+            for port in listen_ports {
+                writeln!(output, "        udp sport {} accept", port).unwrap();
+                writeln!(input, "        udp dport {} accept", port).unwrap();
+            }
+            // Split tunnel exclusions are meant to bypass the tunnel
+            writeln!(output, "        meta mark {:#x} accept", SPLIT_TUNNEL_MARK).unwrap();
+        }
+    }
+
+    Some(format!(
+        r#"    chain output {{
+        type filter hook output priority filter; policy drop;
+{}    }}
+    chain input {{
+        type filter hook input priority filter; policy drop;
+{}    }}"#,
+        output, input
+    ))
+}
+
+fn ip_family(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    }
+}
+
+fn dns_ruleset(policy: &DnsLeakPolicy) -> Option<String> {
+    let (servers, redirect) = match policy {
+        DnsLeakPolicy::Off => return None,
+        DnsLeakPolicy::Block { servers } => (servers, false),
+        DnsLeakPolicy::Redirect { servers } => (servers, true),
+    };
+
+    // Local stub resolvers forward to the configured servers themselves
+    let mut filter = String::from("        oifname \"lo\" accept\n");
+    let mut nat = filter.clone();
+    for server in servers {
+        writeln!(
+            filter,
+            "        {} daddr {} meta l4proto {{ tcp, udp }} th dport {{ 53, 853 }} accept",
+            ip_family(server),
+            server
+        )
+        .unwrap();
+        writeln!(
+            nat,
+            "        {} daddr {} meta l4proto {{ tcp, udp }} th dport 53 accept",
+            ip_family(server),
+            server
+        )
+        .unwrap();
+    }
+    filter.push_str("        meta l4proto { tcp, udp } th dport { 53, 853 } reject\n");
+
+    let mut ruleset = format!(
+        r#"    chain output {{
+        type filter hook output priority filter; policy accept;
+{}    }}"#,
+        filter
+    );
+
+    if redirect {
+        for (family, nfproto) in [("ip", "ipv4"), ("ip6", "ipv6")] {
+            // Queries of a family without a server are left to the filter chain
+            if let Some(x) = servers.iter().find(|x| ip_family(x) == family) {
+                writeln!(
+                    nat,
+                    "        meta nfproto {} meta l4proto {{ tcp, udp }} th dport 53 dnat {} to {}",
+                    nfproto, family, x
+                )
+                .unwrap();
+            }
+        }
+        write!(
+            ruleset,
+            r#"
+    chain output_nat {{
+        type nat hook output priority dstnat; policy accept;
+{}    }}"#,
+            nat
+        )
+        .unwrap();
+    }
+
+    Some(ruleset)
+}
+
+/// Kill switch implemented as an nftables table with drop policies
+pub struct Firewall {}
+
+impl PlatformFirewall for Firewall {
+    fn new() -> Result<Self, VpnctrlError>
+    where
+        Self: Sized,
+    {
+        Ok(Self {})
+    }
+
+    fn apply_policy(&mut self, policy: &FirewallPolicy) -> Result<(), VpnctrlError> {
+        match ruleset(policy) {
+            Some(x) => nft::replace_table(FIREWALL_NFT_TABLE, &x),
+            None => nft::delete_table(FIREWALL_NFT_TABLE),
+        }
+    }
+
+    fn apply_dns_policy(&mut self, policy: &DnsLeakPolicy) -> Result<(), VpnctrlError> {
+        match dns_ruleset(policy) {
+            Some(x) => nft::replace_table(DNS_NFT_TABLE, &x),
+            None => nft::delete_table(DNS_NFT_TABLE),
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), VpnctrlError> {
+        let res = nft::delete_table(DNS_NFT_TABLE);
+        nft::delete_table(FIREWALL_NFT_TABLE)?;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_firewall_ruleset() {
+        assert!(ruleset(&FirewallPolicy::Off).is_none());
+
+        let blocked = ruleset(&FirewallPolicy::Blocked { allow_lan: false }).unwrap();
+        assert!(blocked.contains("policy drop"));
+        assert!(!blocked.contains("192.168.0.0/16"));
+
+        let connected = ruleset(&FirewallPolicy::Connected {
+            tunnels: vec!["wg0".to_string()],
+            endpoints: vec![
+                "1.2.3.4:51820".parse().unwrap(),
+                "[::1]:1234".parse().unwrap(),
+            ],
+            listen_ports: vec![51820],
+            allow_lan: true,
+        })
+        .unwrap();
+        assert!(connected.contains("oifname \"wg0\" accept"));
+        assert!(connected.contains("ip daddr 1.2.3.4 udp dport 51820 accept"));
+        assert!(connected.contains("ip6 daddr ::1 udp dport 1234 accept"));
+        assert!(connected.contains("ip6 daddr fe80::/10 accept"));
+    }
+
+    #[test]
+    fn test_dns_ruleset() {
+        assert!(dns_ruleset(&DnsLeakPolicy::Off).is_none());
+
+        let servers = vec!["10.0.0.1".parse().unwrap()];
+        let block = dns_ruleset(&DnsLeakPolicy::Block {
+            servers: servers.clone(),
+        })
+        .unwrap();
+        assert!(block
+            .contains("ip daddr 10.0.0.1 meta l4proto { tcp, udp } th dport { 53, 853 } accept"));
+        assert!(block.contains("th dport { 53, 853 } reject"));
+        assert!(!block.contains("dnat"));
+
+        let redirect = dns_ruleset(&DnsLeakPolicy::Redirect { servers }).unwrap();
+        assert!(redirect.contains("dnat ip to 10.0.0.1"));
+        assert!(!redirect.contains("dnat ip6"));
+    }
+
+    #[test]
+    fn test_ruleset_loads() {
+        if unsafe { libc::geteuid() } != 0
+            || duct::cmd!("nft", "--version").stdout_null().run().is_err()
+        {
+            eprintln!("Skipping: nft is not available");
+            return;
+        }
+
+        let servers = vec!["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+        let rulesets = vec![
+            ruleset(&FirewallPolicy::Blocked { allow_lan: false }),
+            ruleset(&FirewallPolicy::Connected {
+                tunnels: vec!["wg0".to_string()],
+                endpoints: vec![
+                    "1.2.3.4:51820".parse().unwrap(),
+                    "[::1]:1234".parse().unwrap(),
+                ],
+                listen_ports: vec![51820],
+                allow_lan: true,
+            }),
+            dns_ruleset(&DnsLeakPolicy::Block {
+                servers: servers.clone(),
+            }),
+            dns_ruleset(&DnsLeakPolicy::Redirect { servers }),
+        ];
+
+        for body in rulesets {
+            let body = body.unwrap();
+            let res = nft::check(&format!("table inet mareel_test {{\n{}\n}}\n", body));
+            assert!(res.is_ok(), "{:?}\n{}", res, body);
+        }
+    }
+}
@@ -25,8 +25,9 @@ use wireguard_control::{
 };
 
 use super::super::common::{
-    InterfaceStatus, PeerTrafficStat, PlatformInterface, WgIfCfg, WgPeerCfg,
+    parse_addresses, InterfaceStatus, PeerTrafficStat, PlatformInterface, WgIfCfg, WgPeerCfg,
 };
+use super::super::journal::{self, Mutation};
 use crate::error::VpnctrlError;
 
 use super::super::super::netlink;
@@ -42,6 +43,15 @@ pub struct Interface {
     status: InterfaceStatus,
 }
 
+impl Interface {
+    fn address_mutation(&self, ipn: &IpNetwork) -> Mutation {
+        Mutation::Address {
+            interface: self.ifname.to_string(),
+            address: ipn.to_string(),
+        }
+    }
+}
+
 impl PlatformInterface for Interface {
     fn new(name: &str) -> Result<Self, VpnctrlError>
     where
@@ -57,7 +67,9 @@ impl PlatformInterface for Interface {
         };
 
         match DeviceUpdate::new().apply(&ifname, Backend::Kernel) {
-            Ok(_) => (),
+            Ok(_) => journal::record(Mutation::Interface {
+                name: name.to_string(),
+            }),
             Err(e) => {
                 return Err(VpnctrlError::Internal { msg: e.to_string() });
             }
@@ -256,6 +268,50 @@ impl PlatformInterface for Interface {
         Ok(())
     }
 
+    fn set_peer_endpoint(&mut self, pubkey: &str, endpoint: &str) -> Result<(), VpnctrlError> {
+        let pk = match Key::from_base64(pubkey) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(VpnctrlError::BadParameter {
+                    msg: "Invalid pubkey format".to_string(),
+                })
+            }
+        };
+        let endpt: SocketAddr = match endpoint.parse() {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(VpnctrlError::BadParameter {
+                    msg: "Invalid endpoint format".to_string(),
+                })
+            }
+        };
+
+        let mut pubkey_raw: [u8; 32] = [0; 32];
+        pubkey_raw.copy_from_slice(pk.as_bytes());
+        let peer = match self.peers.get_mut(&pubkey_raw) {
+            Some(x) => x,
+            None => {
+                return Err(VpnctrlError::EntryNotFound {
+                    msg: "Entry not found".to_string(),
+                })
+            }
+        };
+
+        // Without replace_allowed_ips, only the endpoint of the peer changes
+        match DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&pk).set_endpoint(endpt))
+            .apply(&self.ifname, self.backend)
+        {
+            Ok(_) => {
+                peer.endpoint = Some(endpoint.to_string());
+                Ok(())
+            }
+            Err(_) => Err(VpnctrlError::Internal {
+                msg: "Failed to update interface".to_string(),
+            }),
+        }
+    }
+
     fn get_status(&self) -> InterfaceStatus {
         self.status.clone()
     }
@@ -270,13 +326,31 @@ impl PlatformInterface for Interface {
             }
         };
 
+        // Read back from the kernel only, wireguard-control drops what userspace reports
+        let versions = match self.backend {
+            Backend::Kernel => netlink::get_protocol_versions(&self.ifname).unwrap_or_else(|e| {
+                log::warn!("Failed to get protocol versions of {}: {}", self.ifname, e);
+                HashMap::new()
+            }),
+            _ => HashMap::new(),
+        };
+
         Ok(dev
             .peers
             .into_iter()
-            .map(|x| PeerTrafficStat {
-                pubkey: x.config.public_key.to_base64(),
-                rx_bytes: x.stats.rx_bytes,
-                tx_bytes: x.stats.tx_bytes,
+            .map(|x| {
+                let mut pubkey_raw: [u8; 32] = [0; 32];
+                pubkey_raw.copy_from_slice(x.config.public_key.as_bytes());
+
+                PeerTrafficStat::new(
+                    x.config.public_key.to_base64(),
+                    x.stats.rx_bytes,
+                    x.stats.tx_bytes,
+                    x.stats.last_handshake_time,
+                    x.config.endpoint,
+                    x.config.persistent_keepalive_interval,
+                    versions.get(&pubkey_raw).copied(),
+                )
             })
             .collect())
     }
@@ -302,26 +376,46 @@ impl PlatformInterface for Interface {
     }
 
     fn set_ip(&mut self, ips: &[String]) -> Result<(), VpnctrlError> {
-        let ipns: Vec<IpNetwork> = ips
-            .iter()
-            .map(|x| x.parse())
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
-            .collect();
+        let wanted: Vec<IpNetwork> = match parse_addresses(ips) {
+            Ok(x) => x
+                .into_iter()
+                .map(|x| IpNetwork::new(x.addr(), x.prefix_len()).unwrap())
+                .collect(),
+            Err(e) => return Err(VpnctrlError::BadParameter { msg: e.join(", ") }),
+        };
+        let current = netlink::get_addrs(&self.ifname).map_err(|e| VpnctrlError::Internal {
+            msg: format!("Failed to get addresses: {}", e),
+        })?;
 
-        for ipn in ipns {
-            match netlink::set_addr(&self.ifname, ipn) {
-                Ok(_) => {}
-                Err(_) => {
-                    return Err(VpnctrlError::Internal {
-                        msg: "Failed to set address".to_string(),
-                    })
-                }
+        for ipn in current.iter().filter(|x| !wanted.contains(x)) {
+            if let Err(e) = netlink::del_addr(&self.ifname, *ipn) {
+                return Err(VpnctrlError::Internal {
+                    msg: format!("Failed to remove address {}: {}", ipn, e),
+                });
+            }
+            journal::forget(&self.address_mutation(ipn));
+        }
+
+        for ipn in wanted.iter().filter(|x| !current.contains(x)) {
+            if let Err(e) = netlink::set_addr(&self.ifname, *ipn) {
+                return Err(VpnctrlError::Internal {
+                    msg: format!("Failed to set address {}: {}", ipn, e),
+                });
             }
+            journal::record(self.address_mutation(ipn));
         }
 
         Ok(())
     }
+
+    fn get_ip(&self) -> Result<Vec<String>, VpnctrlError> {
+        match netlink::get_addrs(&self.ifname) {
+            Ok(x) => Ok(x.iter().map(|x| x.to_string()).collect()),
+            Err(e) => Err(VpnctrlError::Internal {
+                msg: format!("Failed to get addresses: {}", e),
+            }),
+        }
+    }
 }
 
 impl Drop for Interface {
@@ -335,10 +429,48 @@ impl Drop for Interface {
         };
 
         match device.delete() {
-            Ok(_) => (),
-            Err(_) => {
-                println!("Warn: device delete error");
+            Ok(_) => {
+                let ifname = self.ifname.to_string();
+                journal::forget(&Mutation::Interface {
+                    name: ifname.clone(),
+                });
+                // Addresses went away along with the link
+                journal::forget_where(
+                    |x| matches!(x, Mutation::Address { interface, .. } if *interface == ifname),
+                );
             }
+            Err(e) => log::warn!("Failed to delete {}: {}", self.ifname, e),
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_cleared() {
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("wgctrl-iface-journal-{}.json", std::process::id()));
+        journal::open(&path).unwrap();
+        let mut iface = match Interface::new("wgjournal0") {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Skipping: cannot create WireGuard link: {}", e);
+                journal::close();
+                return;
+            }
+        };
+        iface.set_ip(&["10.0.0.1/24".to_string()]).unwrap();
+        drop(iface);
+
+        // Only an empty journal is removed
+        journal::close();
+        assert!(!path.exists());
+    }
+}
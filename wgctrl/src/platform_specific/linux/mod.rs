@@ -17,8 +17,18 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+pub mod firewall;
+pub use firewall::*;
 pub mod interface;
 pub use interface::*;
+pub mod netmon;
+pub use netmon::*;
+pub mod recovery;
+pub use recovery::*;
 pub mod route;
 pub use route::*;
+pub mod split_tunnel;
+pub use split_tunnel::*;
 pub mod dns;
+
+mod nft;
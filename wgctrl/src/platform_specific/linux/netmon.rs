@@ -0,0 +1,51 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::time::Duration;
+
+use super::super::common::PlatformNetworkMonitor;
+use crate::error::VpnctrlError;
+use crate::netlink::ChangeListener;
+
+/// Listens to rtnetlink notifications
+pub struct NetworkMonitor {
+    listener: ChangeListener,
+}
+
+impl PlatformNetworkMonitor for NetworkMonitor {
+    fn new() -> Result<Self, VpnctrlError>
+    where
+        Self: Sized,
+    {
+        match ChangeListener::new() {
+            Ok(listener) => Ok(Self { listener }),
+            Err(e) => Err(VpnctrlError::Internal {
+                msg: format!("Failed to subscribe to network changes: {}", e),
+            }),
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<bool, VpnctrlError> {
+        self.listener
+            .wait(timeout)
+            .map_err(|e| VpnctrlError::Internal {
+                msg: format!("Failed to wait for network changes: {}", e),
+            })
+    }
+}
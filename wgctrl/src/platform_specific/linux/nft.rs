@@ -0,0 +1,79 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::super::journal::{self, Mutation};
+use crate::error::VpnctrlError;
+
+/// Loads `ruleset` through `nft -f -`. The whole ruleset is applied atomically.
+pub(crate) fn apply(ruleset: &str) -> Result<(), VpnctrlError> {
+    run(&["-f", "-"], ruleset)
+}
+
+/// Has nft parse and validate `ruleset` without applying it
+#[cfg(test)]
+pub(crate) fn check(ruleset: &str) -> Result<(), VpnctrlError> {
+    run(&["-c", "-f", "-"], ruleset)
+}
+
+fn run(args: &[&str], ruleset: &str) -> Result<(), VpnctrlError> {
+    let output = duct::cmd("nft", args)
+        .stdin_bytes(ruleset)
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .map_err(|e| VpnctrlError::Internal {
+            msg: format!("Failed to run nft: {}", e),
+        })?;
+
+    if !output.status.success() {
+        return Err(VpnctrlError::Internal {
+            msg: format!(
+                "nft rejected ruleset: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+/// Replaces `inet <table>` with `body`. Creating the table first makes the delete succeed even
+/// if it did not exist yet.
+pub(crate) fn replace_table(table: &str, body: &str) -> Result<(), VpnctrlError> {
+    apply(&format!(
+        "table inet {table} {{}}\ndelete table inet {table}\ntable inet {table} {{\n{body}\n}}\n",
+        table = table,
+        body = body,
+    ))?;
+    journal::record(Mutation::NftTable {
+        name: table.to_string(),
+    });
+    Ok(())
+}
+
+pub(crate) fn delete_table(table: &str) -> Result<(), VpnctrlError> {
+    apply(&format!(
+        "table inet {table} {{}}\ndelete table inet {table}\n",
+        table = table,
+    ))?;
+    journal::forget(&Mutation::NftTable {
+        name: table.to_string(),
+    });
+    Ok(())
+}
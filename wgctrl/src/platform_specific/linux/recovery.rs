@@ -0,0 +1,104 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io;
+
+use ipnetwork::IpNetwork;
+use netlink_packet_route::constants::{AF_INET, AF_INET6};
+use wireguard_control::{Backend, Device, InterfaceName};
+
+use super::super::common::RouteOptions;
+use super::super::journal::{self, Mutation};
+use super::{dns, nft};
+use crate::error::VpnctrlError;
+use crate::netlink;
+
+fn parse<T: std::str::FromStr>(x: &str) -> Result<T, VpnctrlError> {
+    x.parse().map_err(|_| VpnctrlError::BadParameter {
+        msg: format!("Invalid journal entry: {}", x),
+    })
+}
+
+/// Addresses and routes go away along with the link
+fn ignore_missing_link(res: Result<bool, io::Error>) -> Result<(), io::Error> {
+    match res {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Undoes `mutation`, made by a previous run. Already undone changes are fine.
+pub fn undo(mutation: &Mutation) -> Result<(), VpnctrlError> {
+    let res = match mutation {
+        Mutation::Interface { name } => {
+            let ifname: InterfaceName = parse(name)?;
+            match Device::get(&ifname, Backend::Kernel) {
+                Ok(device) => device.delete(),
+                Err(_) => Ok(()),
+            }
+        }
+        Mutation::Address { interface, address } => {
+            ignore_missing_link(netlink::del_addr(&parse(interface)?, parse(address)?))
+        }
+        Mutation::Route {
+            interface,
+            destination,
+            table,
+        } => ignore_missing_link(netlink::del_route(
+            &parse(interface)?,
+            *table,
+            parse(destination)?,
+            &RouteOptions::default(),
+        )),
+        Mutation::Rule {
+            fwmark,
+            table,
+            priority,
+            mark,
+        } => {
+            let remove = match mark {
+                true => netlink::remove_mark_rule,
+                false => netlink::remove_rule,
+            };
+            // IPv6 may be disabled altogether
+            remove(AF_INET6, *fwmark, *table, *priority).ok();
+            remove(AF_INET, *fwmark, *table, *priority).map(|_| ())
+        }
+        Mutation::BypassRule {
+            destination,
+            table,
+            priority,
+        } => {
+            let dst: IpNetwork = parse(destination)?;
+            netlink::remove_bypass_rule(dst, *table, *priority).map(|_| ())
+        }
+        Mutation::NftTable { name } => return nft::delete_table(name),
+        Mutation::Sysctl { path, value } => fs::write(path, value),
+        Mutation::DefaultRoute { .. } | Mutation::BypassRoute { .. } => {
+            return journal::unsupported(mutation)
+        }
+        Mutation::Dns { backend, interface } => {
+            return dns::undo(*backend, interface.as_deref())
+                .map_err(|e| VpnctrlError::Internal { msg: e.to_string() })
+        }
+    };
+
+    res.map_err(|e| VpnctrlError::Internal { msg: e.to_string() })
+}
@@ -17,35 +17,136 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::{HashMap, HashSet};
+use std::io;
+
 use ipnetwork::IpNetwork;
+use netlink_packet_route::constants::{AF_INET, AF_INET6, RT_TABLE_MAIN};
 use wireguard_control::InterfaceName;
 
-use super::super::common::PlatformRoute;
+use super::super::common::{
+    is_default_route, IpFamily, PlatformRoute, RouteDrift, RouteOptions, RoutePolicy, RouteTable,
+    DEFAULT_RULE_PRIORITY, LINK_NETWORKS, PRIVATE_NETWORKS,
+};
+use super::super::journal::{self, Mutation};
 use crate::error::VpnctrlError;
 use crate::netlink;
 
+struct IfaceRouting {
+    policy: RoutePolicy,
+    routes: HashMap<IpNetwork, RouteOptions>,
+}
+
+impl IfaceRouting {
+    fn table(&self) -> Option<u32> {
+        match self.policy.table {
+            RouteTable::Auto => Some(self.policy.fwmark),
+            RouteTable::Main => Some(RT_TABLE_MAIN as u32),
+            RouteTable::Off => None,
+            RouteTable::Id(x) => Some(x),
+        }
+    }
+
+    /// Table of a route, which may override the one of the interface
+    fn route_table(&self, opts: &RouteOptions) -> Option<u32> {
+        match opts.table {
+            Some(RouteTable::Main) => Some(RT_TABLE_MAIN as u32),
+            Some(RouteTable::Id(x)) => Some(x),
+            _ => self.table(),
+        }
+    }
+
+    /// `(fwmark, table, priority)` of the rule steering unmarked traffic into our table
+    fn rule(&self) -> Option<(u32, u32, u32)> {
+        match self.policy.table {
+            RouteTable::Main | RouteTable::Off => None,
+            _ => self
+                .table()
+                .map(|table| (self.policy.fwmark, table, self.policy.rule_priority)),
+        }
+    }
+}
+
 pub struct Route {
-    fwmark: u32,
+    // Policy of interfaces not registered through `add_interface`
+    defaults: RoutePolicy,
+    ifaces: HashMap<String, IfaceRouting>,
+    // Interfaces may share a rule, so keep track of its users
+    rules: HashMap<(u32, u32, u32), usize>,
+    // Bypassed destination and priority of its rule
+    bypass: HashMap<IpNetwork, u32>,
+    // Same for local networks, which come and go with the physical network
+    lan_bypass: HashMap<IpNetwork, u32>,
 }
 
-impl PlatformRoute for Route {
-    fn new(fwmark: u32) -> Result<Self, VpnctrlError>
-    where
-        Self: Sized,
-    {
-        Ok(Self { fwmark })
+/// Private subnets of non-tunnel links, plus link-local and multicast ranges
+fn lan_networks(local: &[(String, IpNetwork)], tunnels: &[String]) -> HashSet<IpNetwork> {
+    let private: Vec<IpNetwork> = PRIVATE_NETWORKS
+        .iter()
+        .map(|x| x.parse().unwrap())
+        .collect();
+
+    local
+        .iter()
+        .filter(|(ifname, _)| !tunnels.contains(ifname))
+        .map(|(_, network)| *network)
+        .filter(|network| {
+            private
+                .iter()
+                .any(|x| x.prefix() <= network.prefix() && x.contains(network.ip()))
+        })
+        .chain(LINK_NETWORKS.iter().map(|x| x.parse().unwrap()))
+        .collect()
+}
+
+type RouteInfo = (IpNetwork, Option<u32>, Option<u32>);
+
+fn family_of(ipn: &IpNetwork) -> u16 {
+    match ipn {
+        IpNetwork::V4(_) => AF_INET,
+        IpNetwork::V6(_) => AF_INET6,
     }
+}
 
-    fn init(&mut self) -> Result<(), VpnctrlError> {
-        match netlink::add_rule(self.fwmark, self.fwmark, 0x7363) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(VpnctrlError::Internal {
-                msg: "Failed to set routing rule".to_string(),
-            }),
+fn route_mutation(ifname: &str, ipn: &IpNetwork, table: u32) -> Mutation {
+    Mutation::Route {
+        interface: ifname.to_string(),
+        destination: ipn.to_string(),
+        table,
+    }
+}
+
+fn bypass_mutation(dst: &IpNetwork, prio: u32) -> Mutation {
+    Mutation::BypassRule {
+        destination: dst.to_string(),
+        table: RT_TABLE_MAIN as u32,
+        priority: prio,
+    }
+}
+
+fn rule_mutation((fwmark, table, priority): (u32, u32, u32)) -> Mutation {
+    Mutation::Rule {
+        fwmark,
+        table,
+        priority,
+        mark: false,
+    }
+}
+
+/// Records the outcome of installing a missing entry again
+fn record_restore(drift: &mut RouteDrift, entry: String, res: Result<bool, io::Error>) {
+    match res {
+        Ok(_) => drift.restored.push(entry),
+        Err(e) => {
+            // Expected while the link is down, the caller reports the outcome
+            log::debug!("Failed to restore {}: {}", entry, e);
+            drift.missing.push(entry);
         }
     }
+}
 
-    fn add_route(&mut self, ifname: &str, cidr: &str) -> Result<(), VpnctrlError> {
+impl Route {
+    fn parse_route(ifname: &str, cidr: &str) -> Result<(InterfaceName, IpNetwork), VpnctrlError> {
         let wgc_ifname: InterfaceName = match ifname.parse() {
             Ok(ifname) => ifname,
             Err(_) => {
@@ -63,51 +164,208 @@ impl PlatformRoute for Route {
                 })
             }
         };
-        match netlink::add_route(&wgc_ifname, self.fwmark, ipn) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(VpnctrlError::Internal {
-                msg: "Internal error".to_string(),
+
+        Ok((wgc_ifname, ipn))
+    }
+
+    /// Interfaces not registered through `add_interface` get the default policy
+    fn ensure_iface(&mut self, ifname: &str) -> Result<(), VpnctrlError> {
+        if self.ifaces.contains_key(ifname) {
+            return Ok(());
+        }
+
+        self.add_interface(ifname, self.defaults)
+    }
+
+    /// Moves bypass rules in front of the fwmark rules again, after those changed
+    fn reprioritize_bypass(&mut self) {
+        let prio = self.bypass_priority();
+        for bypass in [&mut self.bypass, &mut self.lan_bypass] {
+            for (dst, old) in bypass.iter_mut().filter(|(_, x)| **x != prio) {
+                // Add first, so that the destination is never left without a bypass
+                if let Err(e) = netlink::add_bypass_rule(*dst, RT_TABLE_MAIN as u32, prio) {
+                    log::warn!("Failed to move bypass rule of {}: {}", dst, e);
+                    continue;
+                }
+                journal::record(bypass_mutation(dst, prio));
+                netlink::remove_bypass_rule(*dst, RT_TABLE_MAIN as u32, *old).ok();
+                journal::forget(&bypass_mutation(dst, *old));
+                *old = prio;
+            }
+        }
+    }
+
+    fn parse_bypass(address: &str) -> Result<IpNetwork, VpnctrlError> {
+        match address.parse() {
+            Ok(x) => Ok(x),
+            Err(_) => Err(VpnctrlError::BadParameter {
+                msg: format!("Invalid address: {}", address),
             }),
         }
     }
 
-    fn remove_route(&mut self, ifname: &str, cidr: &str) -> Result<(), VpnctrlError> {
-        let wgc_ifname: InterfaceName = match ifname.parse() {
-            Ok(ifname) => ifname,
-            Err(_) => {
-                return Err(VpnctrlError::BadParameter {
-                    msg: "Invalid address format".to_string(),
+    fn ref_rule(&mut self, rule: (u32, u32, u32)) -> Result<(), VpnctrlError> {
+        let users = self.rules.entry(rule).or_insert(0);
+        if *users == 0 {
+            let (fwmark, table, prio) = rule;
+            if netlink::add_rule(AF_INET, fwmark, table, prio).is_err() {
+                return Err(VpnctrlError::Internal {
+                    msg: "Failed to set routing rule".to_string(),
                 });
             }
+            // IPv6 may be disabled altogether
+            if let Err(e) = netlink::add_rule(AF_INET6, fwmark, table, prio) {
+                log::warn!("Failed to set IPv6 routing rule: {}", e);
+            }
+            journal::record(rule_mutation(rule));
+        }
+        *users += 1;
+        Ok(())
+    }
+
+    fn unref_rule(&mut self, rule: (u32, u32, u32)) -> Result<(), VpnctrlError> {
+        let users = match self.rules.get_mut(&rule) {
+            Some(x) => x,
+            None => return Ok(()),
         };
 
-        let ipn: IpNetwork = match cidr.parse() {
-            Ok(x) => x,
-            Err(_) => {
-                return Err(VpnctrlError::BadParameter {
-                    msg: "bad cidr".to_string(),
-                })
+        *users -= 1;
+        if *users == 0 {
+            self.rules.remove(&rule);
+            let (fwmark, table, prio) = rule;
+            netlink::remove_rule(AF_INET6, fwmark, table, prio).ok();
+            if netlink::remove_rule(AF_INET, fwmark, table, prio).is_err() {
+                return Err(VpnctrlError::Internal {
+                    msg: "Failed to remove routing rule".to_string(),
+                });
             }
+            journal::forget(&rule_mutation(rule));
+        }
+        Ok(())
+    }
+}
+
+impl PlatformRoute for Route {
+    fn new(defaults: RoutePolicy) -> Result<Self, VpnctrlError>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            defaults,
+            ifaces: HashMap::new(),
+            rules: HashMap::new(),
+            bypass: HashMap::new(),
+            lan_bypass: HashMap::new(),
+        })
+    }
+
+    fn init(&mut self) -> Result<(), VpnctrlError> {
+        // Rules are installed per interface
+        Ok(())
+    }
+
+    fn add_route(
+        &mut self,
+        ifname: &str,
+        cidr: &str,
+        opts: &RouteOptions,
+    ) -> Result<(), VpnctrlError> {
+        let (wgc_ifname, ipn) = Self::parse_route(ifname, cidr)?;
+        opts.validate(cidr)?;
+
+        self.ensure_iface(ifname)?;
+        let routing = self.ifaces.get_mut(ifname).unwrap();
+        let table = match routing.route_table(opts) {
+            Some(x) => x,
+            None => return Ok(()),
         };
-        match netlink::del_route(&wgc_ifname, self.fwmark, ipn) {
-            Ok(_) => Ok(()),
+        // Bypass rules look up the main table, so they couldn't keep endpoints out of the tunnel
+        if table == RT_TABLE_MAIN as u32 && is_default_route(cidr) {
+            return Err(VpnctrlError::BadParameter {
+                msg: "Default route can't go into the main table".to_string(),
+            });
+        }
+
+        match netlink::add_route(&wgc_ifname, table, ipn, opts) {
+            Ok(_) => {
+                routing.routes.insert(ipn, *opts);
+                journal::record(route_mutation(ifname, &ipn, table));
+                Ok(())
+            }
+            Err(e) => Err(VpnctrlError::Internal {
+                msg: format!("Failed to add route {}: {}", cidr, e),
+            }),
+        }
+    }
+
+    fn remove_route(&mut self, ifname: &str, cidr: &str) -> Result<(), VpnctrlError> {
+        let (wgc_ifname, ipn) = Self::parse_route(ifname, cidr)?;
+
+        let (table, opts) = match self.ifaces.get_mut(ifname) {
+            Some(routing) => {
+                let opts = routing.routes.remove(&ipn).unwrap_or_default();
+                match routing.route_table(&opts) {
+                    Some(x) => (x, opts),
+                    None => return Ok(()),
+                }
+            }
+            None => (self.defaults.fwmark, RouteOptions::default()),
+        };
+
+        match netlink::del_route(&wgc_ifname, table, ipn, &opts) {
+            Ok(_) => {
+                journal::forget(&route_mutation(ifname, &ipn, table));
+                Ok(())
+            }
             Err(_) => Err(VpnctrlError::Internal {
                 msg: "Internal error".to_string(),
             }),
         }
     }
 
-    fn add_route_bypass(&mut self, _address: &str) -> Result<(), VpnctrlError> {
-        // No need for this. fwmark will handle clutter for us
-        Ok(())
+    fn add_route_bypass(&mut self, address: &str) -> Result<(), VpnctrlError> {
+        let dst = Self::parse_bypass(address)?;
+        if self.bypass.contains_key(&dst) {
+            return Ok(());
+        }
+
+        let prio = self.bypass_priority();
+        match netlink::add_bypass_rule(dst, RT_TABLE_MAIN as u32, prio) {
+            Ok(_) => {
+                self.bypass.insert(dst, prio);
+                journal::record(bypass_mutation(&dst, prio));
+                Ok(())
+            }
+            Err(e) => Err(VpnctrlError::Internal {
+                msg: format!("Failed to add bypass rule: {}", e),
+            }),
+        }
     }
 
-    fn remove_route_bypass(&mut self, _address: &str) -> Result<(), VpnctrlError> {
-        Ok(())
+    fn remove_route_bypass(&mut self, address: &str) -> Result<(), VpnctrlError> {
+        let dst = Self::parse_bypass(address)?;
+        let prio = match self.bypass.remove(&dst) {
+            Some(x) => x,
+            None => {
+                return Err(VpnctrlError::EntryNotFound {
+                    msg: address.to_string(),
+                })
+            }
+        };
+
+        match netlink::remove_bypass_rule(dst, RT_TABLE_MAIN as u32, prio) {
+            Ok(_) => {
+                journal::forget(&bypass_mutation(&dst, prio));
+                Ok(())
+            }
+            Err(e) => Err(VpnctrlError::Internal {
+                msg: format!("Failed to remove bypass rule: {}", e),
+            }),
+        }
     }
 
     fn get_route_bypass(&self) -> Result<Vec<String>, VpnctrlError> {
-        Ok(vec![])
+        Ok(self.bypass.keys().map(|x| x.to_string()).collect())
     }
 
     fn backup_default_route(&mut self) -> Result<(), VpnctrlError> {
@@ -115,7 +373,7 @@ impl PlatformRoute for Route {
         Ok(())
     }
 
-    fn remove_default_route(&mut self) -> Result<(), VpnctrlError> {
+    fn remove_default_route(&mut self, _family: IpFamily) -> Result<(), VpnctrlError> {
         // No need for this. fwmark will handle clutter for us
         Ok(())
     }
@@ -124,4 +382,403 @@ impl PlatformRoute for Route {
         // No need for this. fwmark will handle clutter for us
         Ok(())
     }
+
+    fn restore_family_default_route(&mut self, _family: IpFamily) -> Result<(), VpnctrlError> {
+        // No need for this. fwmark will handle clutter for us
+        Ok(())
+    }
+
+    fn refresh_default_route(&mut self) -> Result<Vec<String>, VpnctrlError> {
+        // Bypass rules look up the main table, so they follow the new gateway by themselves
+        let mut gateways = vec![];
+        for family in [AF_INET, AF_INET6] {
+            match netlink::get_default_gateways(family) {
+                Ok(x) => gateways.extend(x),
+                Err(e) => {
+                    return Err(VpnctrlError::Internal {
+                        msg: format!("Failed to look up default gateways: {}", e),
+                    })
+                }
+            }
+        }
+        Ok(gateways)
+    }
+
+    fn add_interface(&mut self, ifname: &str, policy: RoutePolicy) -> Result<(), VpnctrlError> {
+        if self.ifaces.contains_key(ifname) {
+            return Err(VpnctrlError::DuplicatedEntry {
+                msg: ifname.to_string(),
+            });
+        }
+
+        let routing = IfaceRouting {
+            policy,
+            routes: HashMap::new(),
+        };
+        if let Some(rule) = routing.rule() {
+            self.ref_rule(rule)?;
+        }
+        self.ifaces.insert(ifname.to_string(), routing);
+        self.reprioritize_bypass();
+        Ok(())
+    }
+
+    fn remove_interface(&mut self, ifname: &str) -> Result<(), VpnctrlError> {
+        let routing = match self.ifaces.remove(ifname) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        // Routes may be gone already along with the link, so failures are fine here
+        if let Ok(wgc_ifname) = ifname.parse::<InterfaceName>() {
+            for (ipn, opts) in routing.routes.iter() {
+                if let Some(table) = routing.route_table(opts) {
+                    netlink::del_route(&wgc_ifname, table, *ipn, opts).ok();
+                    journal::forget(&route_mutation(ifname, ipn, table));
+                }
+            }
+        }
+
+        let res = match routing.rule() {
+            Some(rule) => self.unref_rule(rule),
+            None => Ok(()),
+        };
+        self.reprioritize_bypass();
+        res
+    }
+
+    /// Bypass rules have to be evaluated before any of our fwmark rules
+    fn bypass_priority(&self) -> u32 {
+        self.rules
+            .keys()
+            .map(|(_, _, prio)| *prio)
+            .chain(std::iter::once(DEFAULT_RULE_PRIORITY))
+            .min()
+            .unwrap()
+            .saturating_sub(1)
+    }
+
+    fn refresh_lan_bypass(&mut self, tunnels: &[String]) -> Result<Vec<String>, VpnctrlError> {
+        let local = match netlink::get_local_networks() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(VpnctrlError::Internal {
+                    msg: format!("Failed to list local networks: {}", e),
+                })
+            }
+        };
+        let wanted = lan_networks(&local, tunnels);
+
+        let stale: Vec<IpNetwork> = self
+            .lan_bypass
+            .keys()
+            .filter(|x| !wanted.contains(x))
+            .cloned()
+            .collect();
+        for dst in stale {
+            let prio = self.lan_bypass.remove(&dst).unwrap();
+            if netlink::remove_bypass_rule(dst, RT_TABLE_MAIN as u32, prio).is_ok() {
+                journal::forget(&bypass_mutation(&dst, prio));
+            }
+        }
+
+        let prio = self.bypass_priority();
+        for dst in wanted {
+            // Already covered by a user bypass
+            if self.lan_bypass.contains_key(&dst) || self.bypass.contains_key(&dst) {
+                continue;
+            }
+            if let Err(e) = netlink::add_bypass_rule(dst, RT_TABLE_MAIN as u32, prio) {
+                return Err(VpnctrlError::Internal {
+                    msg: format!("Failed to add LAN bypass rule: {}", e),
+                });
+            }
+            self.lan_bypass.insert(dst, prio);
+            journal::record(bypass_mutation(&dst, prio));
+        }
+
+        Ok(self.lan_bypass.keys().map(|x| x.to_string()).collect())
+    }
+
+    fn clear_lan_bypass(&mut self) -> Result<(), VpnctrlError> {
+        let mut res = Ok(());
+        for (dst, prio) in self.lan_bypass.drain() {
+            match netlink::remove_bypass_rule(dst, RT_TABLE_MAIN as u32, prio) {
+                Ok(_) => journal::forget(&bypass_mutation(&dst, prio)),
+                Err(e) => {
+                    res = Err(VpnctrlError::Internal {
+                        msg: format!("Failed to remove LAN bypass rule: {}", e),
+                    })
+                }
+            }
+        }
+        res
+    }
+
+    fn reconcile(&mut self) -> Result<RouteDrift, VpnctrlError> {
+        let mut drift = RouteDrift::default();
+        let mut dumps: HashMap<(u16, u32), Vec<RouteInfo>> = HashMap::new();
+        let mut dump = |family: u16, table: u32| -> Result<Vec<RouteInfo>, VpnctrlError> {
+            if let Some(x) = dumps.get(&(family, table)) {
+                return Ok(x.clone());
+            }
+            let routes: Vec<RouteInfo> = match netlink::get_routes(family, table) {
+                Ok(x) => x.iter().filter_map(netlink::route_info).collect(),
+                // IPv6 may be disabled altogether
+                Err(_) if family == AF_INET6 => vec![],
+                Err(e) => {
+                    return Err(VpnctrlError::Internal {
+                        msg: format!("Failed to dump routing table {}: {}", table, e),
+                    })
+                }
+            };
+            dumps.insert((family, table), routes.clone());
+            Ok(routes)
+        };
+
+        // Tables other than main are ours alone, so anything else found there is drift
+        let mut owned: HashMap<u32, HashSet<(IpNetwork, Option<u32>)>> = HashMap::new();
+        for routing in self.ifaces.values() {
+            if let Some(x) = routing.table().filter(|x| *x != RT_TABLE_MAIN as u32) {
+                owned.entry(x).or_default();
+            }
+        }
+
+        for (ifname, routing) in self.ifaces.iter() {
+            let if_index = ifname
+                .parse::<InterfaceName>()
+                .ok()
+                .and_then(|x| netlink::if_nametoindex(&x).ok());
+
+            for (ipn, opts) in routing.routes.iter() {
+                let table = match routing.route_table(opts) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if let Some(x) = owned.get_mut(&table) {
+                    x.insert((*ipn, if_index));
+                }
+
+                let entry = format!("route {} dev {} table {}", ipn, ifname, table);
+                let if_index = match if_index {
+                    Some(x) => x,
+                    // Routes go away along with the link, and only the link can bring them back
+                    None => {
+                        drift.missing.push(entry);
+                        continue;
+                    }
+                };
+
+                let present = dump(family_of(ipn), table)?
+                    .iter()
+                    .any(|(dst, oif, metric)| {
+                        dst == ipn
+                            && *oif == Some(if_index)
+                            && (opts.metric.is_none() || *metric == opts.metric)
+                    });
+                if !present {
+                    let wgc_ifname: InterfaceName = ifname.parse().unwrap();
+                    let res = netlink::add_route(&wgc_ifname, table, *ipn, opts);
+                    record_restore(&mut drift, entry, res);
+                }
+            }
+        }
+
+        for (table, installed) in owned.iter() {
+            for family in [AF_INET, AF_INET6] {
+                for (dst, oif, _) in dump(family, *table)? {
+                    if !installed.contains(&(dst, oif)) {
+                        drift.extra.push(format!("route {} table {}", dst, table));
+                    }
+                }
+            }
+        }
+
+        let rules4 = netlink::get_rules(AF_INET).map_err(|e| VpnctrlError::Internal {
+            msg: format!("Failed to dump routing rules: {}", e),
+        })?;
+        let rules6 = netlink::get_rules(AF_INET6).ok();
+
+        for (fwmark, table, prio) in self.rules.keys().cloned() {
+            let entry = format!(
+                "rule not fwmark {:#x} lookup {} pref {}",
+                fwmark, table, prio
+            );
+            if !netlink::has_fwmark_rule(&rules4, fwmark, table, prio) {
+                let res = netlink::add_rule(AF_INET, fwmark, table, prio);
+                record_restore(&mut drift, entry.clone(), res);
+            }
+            if let Some(rules6) = &rules6 {
+                if !netlink::has_fwmark_rule(rules6, fwmark, table, prio) {
+                    let res = netlink::add_rule(AF_INET6, fwmark, table, prio);
+                    record_restore(&mut drift, format!("{} (IPv6)", entry), res);
+                }
+            }
+        }
+
+        for (dst, prio) in self.bypass.iter().chain(self.lan_bypass.iter()) {
+            let rules = match dst {
+                IpNetwork::V4(_) => &rules4,
+                IpNetwork::V6(_) => match &rules6 {
+                    Some(x) => x,
+                    None => continue,
+                },
+            };
+            if !netlink::has_bypass_rule(rules, *dst, RT_TABLE_MAIN as u32, *prio) {
+                let entry = format!("rule to {} lookup main pref {}", dst, prio);
+                let res = netlink::add_bypass_rule(*dst, RT_TABLE_MAIN as u32, *prio);
+                record_restore(&mut drift, entry, res);
+            }
+        }
+
+        // Tables are walked in no particular order
+        drift.extra.sort();
+        Ok(drift)
+    }
+
+    fn cleanup(&mut self) -> Result<(), VpnctrlError> {
+        let mut res = self.clear_lan_bypass();
+        let bypass: Vec<String> = self.bypass.keys().map(|x| x.to_string()).collect();
+        for address in bypass {
+            if let Err(e) = self.remove_route_bypass(&address) {
+                res = Err(e);
+            }
+        }
+
+        let ifnames: Vec<String> = self.ifaces.keys().cloned().collect();
+        for ifname in ifnames {
+            if let Err(e) = self.remove_interface(&ifname) {
+                res = Err(e);
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lan_networks() {
+        let local = vec![
+            ("eth0".to_string(), "192.168.1.0/24".parse().unwrap()),
+            ("eth0".to_string(), "203.0.113.0/24".parse().unwrap()),
+            ("eth1".to_string(), "10.0.0.0/7".parse().unwrap()),
+            ("wg0".to_string(), "10.10.0.0/24".parse().unwrap()),
+        ];
+        let networks = lan_networks(&local, &["wg0".to_string()]);
+
+        assert!(networks.contains(&"192.168.1.0/24".parse().unwrap()));
+        assert!(networks.contains(&"fe80::/10".parse().unwrap()));
+        assert!(!networks.contains(&"203.0.113.0/24".parse().unwrap()));
+        assert!(!networks.contains(&"10.0.0.0/7".parse().unwrap()));
+        assert!(!networks.contains(&"10.10.0.0/24".parse().unwrap()));
+        assert_eq!(networks.len(), LINK_NETWORKS.len() + 1);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let lo: InterfaceName = "lo".parse().unwrap();
+        netlink::set_up(&lo, 65536).unwrap();
+
+        let policy = RoutePolicy {
+            table: RouteTable::Id(0x1234),
+            fwmark: 0x1234,
+            rule_priority: 0x7000,
+        };
+        let mut route = Route::new(policy).unwrap();
+        route.add_interface("lo", policy).unwrap();
+        route
+            .add_route("lo", "198.51.100.0/24", &RouteOptions::default())
+            .unwrap();
+        assert!(route.reconcile().unwrap().is_empty());
+
+        let ipn: IpNetwork = "198.51.100.0/24".parse().unwrap();
+        let extra: IpNetwork = "203.0.113.0/24".parse().unwrap();
+        netlink::del_route(&lo, 0x1234, ipn, &RouteOptions::default()).unwrap();
+        netlink::remove_rule(AF_INET, 0x1234, 0x1234, 0x7000).unwrap();
+        netlink::add_route(&lo, 0x1234, extra, &RouteOptions::default()).unwrap();
+
+        let drift = route.reconcile().unwrap();
+        assert_eq!(drift.restored.len(), 2);
+        assert!(drift.missing.is_empty());
+        assert_eq!(drift.extra, vec!["route 203.0.113.0/24 table 4660"]);
+
+        // Extra routes are only reported
+        let drift = route.reconcile().unwrap();
+        assert!(drift.restored.is_empty());
+        assert_eq!(drift.extra.len(), 1);
+
+        route.cleanup().unwrap();
+    }
+
+    #[test]
+    fn test_bypass_priority() {
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let policy = RoutePolicy {
+            table: RouteTable::Id(0x1234),
+            fwmark: 0x1234,
+            rule_priority: 0x7000,
+        };
+        let mut route = Route::new(policy).unwrap();
+        route.add_interface("wg0", policy).unwrap();
+        route.add_route_bypass("198.51.100.1/32").unwrap();
+
+        // An interface with a lower priority comes along later
+        let policy = RoutePolicy {
+            table: RouteTable::Id(0x1235),
+            fwmark: 0x1235,
+            rule_priority: 0x6000,
+        };
+        route.add_interface("wg1", policy).unwrap();
+
+        let dst: IpNetwork = "198.51.100.1/32".parse().unwrap();
+        let rules = netlink::get_rules(AF_INET).unwrap();
+        assert!(netlink::has_bypass_rule(
+            &rules,
+            dst,
+            RT_TABLE_MAIN as u32,
+            0x5fff
+        ));
+        assert!(!netlink::has_bypass_rule(
+            &rules,
+            dst,
+            RT_TABLE_MAIN as u32,
+            0x6fff
+        ));
+
+        route.remove_interface("wg1").unwrap();
+        let rules = netlink::get_rules(AF_INET).unwrap();
+        assert!(netlink::has_bypass_rule(
+            &rules,
+            dst,
+            RT_TABLE_MAIN as u32,
+            0x6fff
+        ));
+
+        route.cleanup().unwrap();
+    }
+
+    #[test]
+    fn test_main_table_default_route() {
+        let policy = RoutePolicy {
+            table: RouteTable::Main,
+            ..Default::default()
+        };
+        let mut route = Route::new(policy).unwrap();
+        route.add_interface("wg0", policy).unwrap();
+        assert!(route
+            .add_route("wg0", "0.0.0.0/0", &RouteOptions::default())
+            .is_err());
+    }
 }
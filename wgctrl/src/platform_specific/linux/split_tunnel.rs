@@ -0,0 +1,450 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+/*
+ * Based on Mullvad Talpid-core
+ * https://github.com/mullvad/mullvadvpn-app/tree/master/talpid-core/
+ */
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use netlink_packet_route::constants::{AF_INET, AF_INET6, RT_TABLE_MAIN};
+use talpid_types::cgroup::find_net_cls_mount;
+
+use super::super::common::PlatformSplitTunnel;
+use super::super::journal::{self, Mutation};
+use super::nft;
+use crate::error::VpnctrlError;
+use crate::netlink;
+
+const DEFAULT_NET_CLS_DIR: &str = "/sys/fs/cgroup/net_cls";
+/// Mount point of the unified hierarchy, where it is the only one
+const CGROUP2_DIR: &str = "/sys/fs/cgroup";
+const SPLIT_TUNNEL_CGROUP_NAME: &str = "mareel-exclusions";
+const SPLIT_TUNNEL_NFT_TABLE: &str = "mareel_split";
+/// Replies come back with the mark restored, which would fail the reverse path check
+const SRC_VALID_MARK: &str = "/proc/sys/net/ipv4/conf/all/src_valid_mark";
+
+/// net_cls classid given to the excluded processes
+pub const SPLIT_TUNNEL_CLASSID: u32 = 0x4d524c;
+/// Mark put on traffic of the excluded processes
+pub const SPLIT_TUNNEL_MARK: u32 = 0x6d72656c;
+
+fn io_error(e: io::Error) -> VpnctrlError {
+    VpnctrlError::Internal { msg: e.to_string() }
+}
+
+fn ruleset(matcher: &str) -> String {
+    format!(
+        r#"    chain output {{
+        type route hook output priority mangle; policy accept;
+        {matcher} ct mark set {mark:#x} meta mark set ct mark
+    }}
+    chain prerouting {{
+        type filter hook prerouting priority mangle; policy accept;
+        ct mark {mark:#x} meta mark set ct mark
+    }}
+    chain postrouting {{
+        type nat hook postrouting priority srcnat; policy accept;
+        ct mark {mark:#x} oifname != "lo" masquerade
+    }}"#,
+        matcher = matcher,
+        mark = SPLIT_TUNNEL_MARK,
+    )
+}
+
+fn mark_rule(rule_priority: u32) -> Mutation {
+    Mutation::Rule {
+        fwmark: SPLIT_TUNNEL_MARK,
+        table: RT_TABLE_MAIN as u32,
+        priority: rule_priority,
+        mark: true,
+    }
+}
+
+/// Adds the rule sending marked traffic to the main table. Only IPv4 is required.
+fn add_mark_rule(priority: u32) -> Result<(), VpnctrlError> {
+    if let Err(e) =
+        netlink::add_mark_rule(AF_INET, SPLIT_TUNNEL_MARK, RT_TABLE_MAIN as u32, priority)
+    {
+        return Err(VpnctrlError::Internal {
+            msg: format!("Failed to add split tunnel rule: {}", e),
+        });
+    }
+    // IPv6 may be disabled altogether
+    if let Err(e) =
+        netlink::add_mark_rule(AF_INET6, SPLIT_TUNNEL_MARK, RT_TABLE_MAIN as u32, priority)
+    {
+        log::warn!("Failed to add IPv6 split tunnel rule: {}", e);
+    }
+    journal::record(mark_rule(priority));
+    Ok(())
+}
+
+fn remove_mark_rule(priority: u32) -> Result<(), VpnctrlError> {
+    // IPv6 may be disabled altogether
+    netlink::remove_mark_rule(AF_INET6, SPLIT_TUNNEL_MARK, RT_TABLE_MAIN as u32, priority).ok();
+    match netlink::remove_mark_rule(AF_INET, SPLIT_TUNNEL_MARK, RT_TABLE_MAIN as u32, priority) {
+        Ok(_) => {
+            journal::forget(&mark_rule(priority));
+            Ok(())
+        }
+        Err(e) => Err(VpnctrlError::Internal {
+            msg: format!("Failed to remove split tunnel rule: {}", e),
+        }),
+    }
+}
+
+fn src_valid_mark_mutation(value: &str) -> Mutation {
+    Mutation::Sysctl {
+        path: SRC_VALID_MARK.to_string(),
+        value: value.to_string(),
+    }
+}
+
+fn read_pids(procs: &Path) -> Result<Vec<u32>, VpnctrlError> {
+    let procs = fs::read_to_string(procs).map_err(io_error)?;
+    Ok(procs
+        .lines()
+        .filter_map(|x| x.trim().parse().ok())
+        .collect())
+}
+
+fn mount_net_cls() -> Result<PathBuf, VpnctrlError> {
+    let path = PathBuf::from(DEFAULT_NET_CLS_DIR);
+    fs::create_dir_all(&path).map_err(io_error)?;
+
+    let output = duct::cmd!("mount", "-t", "cgroup", "-o", "net_cls", "net_cls", &path)
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .map_err(io_error)?;
+    if !output.status.success() {
+        return Err(VpnctrlError::Internal {
+            msg: format!(
+                "Failed to mount net_cls: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        });
+    }
+
+    Ok(path)
+}
+
+/// Hierarchy the excluded processes are grouped in, by its mount point
+#[derive(Debug, Clone, PartialEq)]
+enum Cgroup {
+    /// cgroup v1 `net_cls`, matched on the classid
+    NetCls(PathBuf),
+    /// cgroup v2, matched on the path of the group
+    Unified(PathBuf),
+}
+
+impl Cgroup {
+    fn detect() -> Result<Self, VpnctrlError> {
+        if let Some(x) = find_net_cls_mount().map_err(io_error)? {
+            return Ok(Cgroup::NetCls(x));
+        }
+        if Path::new(CGROUP2_DIR).join("cgroup.controllers").exists() {
+            return Ok(Cgroup::Unified(PathBuf::from(CGROUP2_DIR)));
+        }
+        Ok(Cgroup::NetCls(mount_net_cls()?))
+    }
+
+    fn root(&self) -> &Path {
+        match self {
+            Cgroup::NetCls(x) | Cgroup::Unified(x) => x,
+        }
+    }
+
+    fn group(&self) -> PathBuf {
+        self.root().join(SPLIT_TUNNEL_CGROUP_NAME)
+    }
+
+    /// nft expression matching the sockets of processes in the group
+    fn matcher(&self) -> String {
+        match self {
+            Cgroup::NetCls(_) => format!("meta cgroup {:#x}", SPLIT_TUNNEL_CLASSID),
+            // The group sits right below the root
+            Cgroup::Unified(_) => {
+                format!("socket cgroupv2 level 1 \"{}\"", SPLIT_TUNNEL_CGROUP_NAME)
+            }
+        }
+    }
+
+    fn create(&self) -> Result<PathBuf, VpnctrlError> {
+        let group = self.group();
+        fs::create_dir_all(&group).map_err(io_error)?;
+        if let Cgroup::NetCls(_) = self {
+            fs::write(
+                group.join("net_cls.classid"),
+                SPLIT_TUNNEL_CLASSID.to_string(),
+            )
+            .map_err(io_error)?;
+        }
+        Ok(group)
+    }
+}
+
+/// Processes in our cgroup get their traffic marked, and the mark is routed through the main
+/// table ahead of the tunnel rules.
+pub struct SplitTunnel {
+    rule_priority: u32,
+    cgroup: Option<Cgroup>,
+    // src_valid_mark from before we set it, if we had to
+    src_valid_mark: Option<String>,
+}
+
+impl SplitTunnel {
+    fn set_src_valid_mark(&mut self) -> Result<(), VpnctrlError> {
+        let sysctl_error = |e: io::Error| VpnctrlError::Internal {
+            msg: format!("Failed to set src_valid_mark: {}", e),
+        };
+        let previous = fs::read_to_string(SRC_VALID_MARK)
+            .map_err(sysctl_error)?
+            .trim()
+            .to_string();
+        if previous == "1" {
+            return Ok(());
+        }
+
+        fs::write(SRC_VALID_MARK, "1").map_err(sysctl_error)?;
+        journal::record(src_valid_mark_mutation(&previous));
+        self.src_valid_mark = Some(previous);
+        Ok(())
+    }
+
+    fn restore_src_valid_mark(&mut self) -> Result<(), VpnctrlError> {
+        let previous = match self.src_valid_mark.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Err(e) = fs::write(SRC_VALID_MARK, &previous) {
+            return Err(VpnctrlError::Internal {
+                msg: format!("Failed to restore src_valid_mark: {}", e),
+            });
+        }
+        journal::forget(&src_valid_mark_mutation(&previous));
+        Ok(())
+    }
+
+    fn install_rules(&self, cgroup: &Cgroup) -> Result<(), VpnctrlError> {
+        nft::replace_table(SPLIT_TUNNEL_NFT_TABLE, &ruleset(&cgroup.matcher()))?;
+        if let Err(e) = add_mark_rule(self.rule_priority) {
+            nft::delete_table(SPLIT_TUNNEL_NFT_TABLE).ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Sets up the cgroup, nftables and routing rules on first use
+    fn enable(&mut self) -> Result<PathBuf, VpnctrlError> {
+        if let Some(x) = &self.cgroup {
+            return Ok(x.group());
+        }
+
+        let cgroup = Cgroup::detect()?;
+        let group = cgroup.create()?;
+
+        self.set_src_valid_mark()?;
+        if let Err(e) = self.install_rules(&cgroup) {
+            self.restore_src_valid_mark().ok();
+            return Err(e);
+        }
+
+        self.cgroup = Some(cgroup);
+        Ok(group)
+    }
+}
+
+impl PlatformSplitTunnel for SplitTunnel {
+    fn new(rule_priority: u32) -> Result<Self, VpnctrlError>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            rule_priority,
+            cgroup: None,
+            src_valid_mark: None,
+        })
+    }
+
+    fn add_pid(&mut self, pid: u32) -> Result<(), VpnctrlError> {
+        let group = self.enable()?;
+        fs::write(group.join("cgroup.procs"), pid.to_string()).map_err(io_error)
+    }
+
+    fn remove_pid(&mut self, pid: u32) -> Result<(), VpnctrlError> {
+        if !self.get_pids()?.contains(&pid) {
+            return Err(VpnctrlError::EntryNotFound {
+                msg: pid.to_string(),
+            });
+        }
+
+        // Moving the process back to the root group is the only way to take it out
+        let root = self.cgroup.as_ref().unwrap().root();
+        fs::write(root.join("cgroup.procs"), pid.to_string()).map_err(io_error)
+    }
+
+    fn get_pids(&self) -> Result<Vec<u32>, VpnctrlError> {
+        match &self.cgroup {
+            Some(x) => read_pids(&x.group().join("cgroup.procs")),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn set_rule_priority(&mut self, rule_priority: u32) -> Result<(), VpnctrlError> {
+        if rule_priority == self.rule_priority {
+            return Ok(());
+        }
+        // Rules are only installed on first use
+        if self.cgroup.is_some() {
+            // Add first, so that excluded traffic never goes into a tunnel
+            add_mark_rule(rule_priority)?;
+            if let Err(e) = remove_mark_rule(self.rule_priority) {
+                log::warn!("{}", e);
+            }
+        }
+        self.rule_priority = rule_priority;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), VpnctrlError> {
+        let cgroup = match self.cgroup.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let mut res = Ok(());
+        for pid in read_pids(&cgroup.group().join("cgroup.procs")).unwrap_or_default() {
+            // Process may have exited in the meantime
+            fs::write(cgroup.root().join("cgroup.procs"), pid.to_string()).ok();
+        }
+
+        if let Err(e) = remove_mark_rule(self.rule_priority) {
+            res = Err(e);
+        }
+
+        if let Err(e) = nft::delete_table(SPLIT_TUNNEL_NFT_TABLE) {
+            res = Err(e);
+        }
+        if let Err(e) = self.restore_src_valid_mark() {
+            res = Err(e);
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wgctrl-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_ruleset() {
+        let net_cls = Cgroup::NetCls(PathBuf::from(DEFAULT_NET_CLS_DIR));
+        let rules = ruleset(&net_cls.matcher());
+        assert!(rules.contains("meta cgroup 0x4d524c ct mark set 0x6d72656c"));
+
+        let unified = Cgroup::Unified(PathBuf::from(CGROUP2_DIR));
+        let rules = ruleset(&unified.matcher());
+        assert!(rules.contains(r#"socket cgroupv2 level 1 "mareel-exclusions" ct mark set"#));
+        assert!(!rules.contains("meta cgroup"));
+    }
+
+    #[test]
+    fn test_rule_priority() {
+        use super::super::super::common::{PlatformRoute, RoutePolicy, RouteTable};
+        use super::super::route::Route;
+
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            eprintln!("Skipping: cannot create network namespace");
+            return;
+        }
+
+        let mut route = Route::new(RoutePolicy::default()).unwrap();
+        let mut split = SplitTunnel::new(route.bypass_priority() - 1).unwrap();
+        // As if enabled, without touching the cgroups and nftables of the host
+        add_mark_rule(split.rule_priority).unwrap();
+        split.cgroup = Some(Cgroup::NetCls(PathBuf::from(DEFAULT_NET_CLS_DIR)));
+        let old = split.rule_priority;
+
+        // An interface with a lower priority than the default comes along
+        let policy = RoutePolicy {
+            table: RouteTable::Id(0x1235),
+            fwmark: 0x1235,
+            rule_priority: 0x6000,
+        };
+        route.add_interface("wg1", policy).unwrap();
+        split
+            .set_rule_priority(route.bypass_priority() - 1)
+            .unwrap();
+
+        let rules = netlink::get_rules(AF_INET).unwrap();
+        let has_rule =
+            |prio| netlink::has_mark_rule(&rules, SPLIT_TUNNEL_MARK, RT_TABLE_MAIN as u32, prio);
+        assert!(has_rule(0x5ffe));
+        assert!(!has_rule(old));
+
+        remove_mark_rule(split.rule_priority).unwrap();
+        split.cgroup = None;
+        route.cleanup().unwrap();
+    }
+
+    #[test]
+    fn test_read_pids() {
+        let dir = temp_dir("split-pids");
+        let procs = dir.join("cgroup.procs");
+        fs::write(&procs, "1\n 23\nbogus\n\n").unwrap();
+        assert_eq!(read_pids(&procs).unwrap(), vec![1, 23]);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(read_pids(&procs).is_err());
+    }
+
+    #[test]
+    fn test_cgroup_create() {
+        let dir = temp_dir("split-cgroup");
+
+        let net_cls = Cgroup::NetCls(dir.join("net_cls"));
+        let group = net_cls.create().unwrap();
+        assert_eq!(group, dir.join("net_cls").join(SPLIT_TUNNEL_CGROUP_NAME));
+        assert_eq!(
+            fs::read_to_string(group.join("net_cls.classid")).unwrap(),
+            SPLIT_TUNNEL_CLASSID.to_string()
+        );
+
+        // The classid file belongs to net_cls only
+        let unified = Cgroup::Unified(dir.join("unified"));
+        let group = unified.create().unwrap();
+        assert!(group.is_dir());
+        assert!(!group.join("net_cls.classid").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
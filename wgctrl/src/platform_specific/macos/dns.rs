@@ -18,9 +18,10 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use super::super::common::{instance, DnsBackend, DnsConfig, DnsStatus};
 use parking_lot::Mutex;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt,
     net::{AddrParseError, IpAddr},
     sync::{mpsc as sync_mpsc, Arc},
@@ -36,7 +37,9 @@ use system_configuration::{
         string::CFString,
     },
     dynamic_store::{SCDynamicStore, SCDynamicStoreBuilder, SCDynamicStoreCallBackContext},
-    sys::schema_definitions::{kSCPropNetDNSServerAddresses, kSCPropNetInterfaceDeviceName},
+    sys::schema_definitions::{
+        kSCPropNetDNSSearchDomains, kSCPropNetDNSServerAddresses, kSCPropNetInterfaceDeviceName,
+    },
 };
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -80,6 +83,8 @@ struct State {
     dns_settings: DnsSettings,
     /// The backup of all DNS settings. These are being applied back on reset.
     backup: HashMap<ServicePath, Option<DnsSettings>>,
+    /// Times a service was found with other settings and ours put back
+    corrections: u64,
 }
 
 /// Holds the configuration for one service.
@@ -92,7 +97,11 @@ struct DnsSettings {
 unsafe impl Send for DnsSettings {}
 
 impl DnsSettings {
-    pub fn from_server_addresses(server_addresses: &[DnsServer], name: String) -> Self {
+    pub fn from_config(
+        server_addresses: &[DnsServer],
+        search_domains: &[String],
+        name: String,
+    ) -> Self {
         let mut mut_dict = CFMutableDictionary::new();
         if !server_addresses.is_empty() {
             let cf_string_servers: Vec<CFString> =
@@ -105,6 +114,17 @@ impl DnsSettings {
                 &server_addresses_value.to_void(),
             );
         }
+        if !search_domains.is_empty() {
+            let cf_string_domains: Vec<CFString> =
+                search_domains.iter().map(|s| CFString::new(s)).collect();
+            let search_domains_value = CFArray::from_CFTypes(&cf_string_domains).into_untyped();
+            let search_domains_key =
+                unsafe { CFString::wrap_under_get_rule(kSCPropNetDNSSearchDomains) };
+            mut_dict.add(
+                &search_domains_key.to_void(),
+                &search_domains_value.to_void(),
+            );
+        }
         let dict = mut_dict.to_immutable();
         DnsSettings { dict, name }
     }
@@ -222,6 +242,10 @@ pub struct DnsMonitor {
     /// When it's `Some(state)` we are actively making sure `state.dns_settings` is configured
     /// on all network interfaces.
     state: Arc<Mutex<Option<State>>>,
+
+    /// Settings asked for by each interface. The system configuration is global, so what gets
+    /// enforced is their merge.
+    configs: BTreeMap<String, DnsConfig>,
 }
 
 /// SAFETY: The `SCDynamicStore` can be sent to other threads since it doesn't share mutable state
@@ -235,18 +259,87 @@ impl super::super::common::DnsMonitorT for DnsMonitor {
     /// DNS settings for all network interfaces. If any changes occur it will instantly reset
     /// the DNS settings for that interface back to the last server list set to this instance
     /// with `set_dns`.
-    fn new(_handle: tokio::runtime::Handle) -> Result<Self> {
+    fn new(_handle: tokio::runtime::Handle, backends: &[DnsBackend]) -> Result<Self> {
+        if backends != DnsBackend::AUTO {
+            log::warn!("Only the system DNS manager is supported, ignoring the DNS backend");
+        }
         let state = Arc::new(Mutex::new(None));
         Self::spawn(state.clone())?;
         Ok(DnsMonitor {
-            store: SCDynamicStoreBuilder::new("mullvad-dns").build(),
+            store: SCDynamicStoreBuilder::new(format!("{}-dns", instance().name)).build(),
             state,
+            configs: BTreeMap::new(),
         })
     }
 
-    fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<()> {
-        let servers: Vec<DnsServer> = servers.iter().map(|ip| ip.to_string()).collect();
-        let settings = DnsSettings::from_server_addresses(&servers, interface.to_string());
+    fn probe(_backends: &[DnsBackend]) -> Vec<(DnsBackend, std::result::Result<(), String>)> {
+        vec![]
+    }
+
+    fn set(&mut self, interface: &str, config: &DnsConfig) -> Result<()> {
+        if !config.routing_domains.is_empty() {
+            log::warn!(
+                "Routing domains are not supported, all names are resolved through {}",
+                interface
+            );
+        }
+        self.configs.insert(interface.to_string(), config.clone());
+        self.apply()
+    }
+
+    fn reset_interface(&mut self, interface: &str) -> Result<()> {
+        if self.configs.remove(interface).is_none() {
+            return Ok(());
+        }
+        self.apply()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.configs.clear();
+        self.restore()
+    }
+
+    fn manager_name(&self) -> Option<String> {
+        self.state
+            .lock()
+            .as_ref()
+            .map(|_| "system configuration".to_string())
+    }
+
+    fn status(&self, interface: &str) -> Result<DnsStatus> {
+        let mut resolvers = vec![];
+        for (path, settings) in read_all_dns(&self.store) {
+            if let Some(settings) = settings {
+                for server in settings.interface_config(&path)? {
+                    if !resolvers.contains(&server) {
+                        resolvers.push(server);
+                    }
+                }
+            }
+        }
+
+        let state = self.state.lock();
+        Ok(DnsStatus {
+            manager: state
+                .as_ref()
+                .filter(|_| self.configs.contains_key(interface))
+                .map(|_| "system configuration".to_string()),
+            resolvers: Some(resolvers),
+            corrections: Some(state.as_ref().map(|x| x.corrections).unwrap_or(0)),
+        })
+    }
+}
+
+impl DnsMonitor {
+    /// Enforces the merge of the configs of all interfaces, or restores the backup if none is left
+    fn apply(&mut self) -> Result<()> {
+        let interface = match self.configs.keys().next() {
+            Some(x) => x.clone(),
+            None => return self.restore(),
+        };
+        let config = DnsConfig::merge(self.configs.values());
+        let servers: Vec<DnsServer> = config.servers.iter().map(|ip| ip.to_string()).collect();
+        let settings = DnsSettings::from_config(&servers, &config.search_domains, interface);
         let mut state_lock = self.state.lock();
         *state_lock = Some(match state_lock.take() {
             None => {
@@ -258,16 +351,18 @@ impl super::super::common::DnsMonitorT for DnsMonitor {
                 State {
                     dns_settings: settings,
                     backup,
+                    corrections: 0,
                 }
             }
             Some(state) => {
-                if servers != state.dns_settings.server_addresses() {
+                if settings != state.dns_settings {
                     for service_path in state.backup.keys() {
                         settings.save(&self.store, service_path.as_str())?;
                     }
                     State {
                         dns_settings: settings,
                         backup: state.backup,
+                        corrections: state.corrections,
                     }
                 } else {
                     log::debug!("No change, new DNS same as the one already set");
@@ -278,7 +373,7 @@ impl super::super::common::DnsMonitorT for DnsMonitor {
         Ok(())
     }
 
-    fn reset(&mut self) -> Result<()> {
+    fn restore(&mut self) -> Result<()> {
         let mut state_lock = self.state.lock();
         if let Some(state) = state_lock.take() {
             log::trace!("Restoring DNS settings to: {:#?}", state.backup);
@@ -295,9 +390,7 @@ impl super::super::common::DnsMonitorT for DnsMonitor {
         }
         Ok(())
     }
-}
 
-impl DnsMonitor {
     /// Spawns the background thread running the CoreFoundation main loop and monitors the system
     /// for DNS changes.
     fn spawn(state: Arc<Mutex<Option<State>>>) -> Result<()> {
@@ -418,6 +511,7 @@ fn dns_change_callback_internal(
             }
         };
         if should_set_dns {
+            state.corrections += 1;
             if let Err(e) = state.dns_settings.save(&store, path.clone()) {
                 log::error!("Failed changing DNS for {}: {}", *path, e);
             }
@@ -17,7 +17,6 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use regex::Regex;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::process::Command;
@@ -30,8 +29,10 @@ use wireguard_control::{
 
 use wireguard_control::backends::userspace::resolve_tun;
 
+use ipnet::IpNet;
+
 use super::super::common::{
-    InterfaceStatus, PeerTrafficStat, PlatformInterface, WgIfCfg, WgPeerCfg,
+    parse_addresses, InterfaceStatus, PeerTrafficStat, PlatformInterface, WgIfCfg, WgPeerCfg,
 };
 
 use crate::error::VpnctrlError;
@@ -45,6 +46,7 @@ pub struct Interface {
     port: u16,
     peers: HashMap<[u8; 32], WgPeerCfg>,
     status: InterfaceStatus,
+    addrs: Vec<IpNet>,
 }
 
 impl PlatformInterface for Interface {
@@ -88,6 +90,7 @@ impl PlatformInterface for Interface {
             port: 0,
             peers: HashMap::new(),
             status: InterfaceStatus::Stopped,
+            addrs: vec![],
         })
     }
 
@@ -272,6 +275,50 @@ impl PlatformInterface for Interface {
         Ok(())
     }
 
+    fn set_peer_endpoint(&mut self, pubkey: &str, endpoint: &str) -> Result<(), VpnctrlError> {
+        let pk = match Key::from_base64(pubkey) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(VpnctrlError::BadParameter {
+                    msg: "Invalid pubkey format".to_string(),
+                })
+            }
+        };
+        let endpt: SocketAddr = match endpoint.parse() {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(VpnctrlError::BadParameter {
+                    msg: "Invalid endpoint format".to_string(),
+                })
+            }
+        };
+
+        let mut pubkey_raw: [u8; 32] = [0; 32];
+        pubkey_raw.copy_from_slice(pk.as_bytes());
+        let peer = match self.peers.get_mut(&pubkey_raw) {
+            Some(x) => x,
+            None => {
+                return Err(VpnctrlError::EntryNotFound {
+                    msg: "Entry not found".to_string(),
+                })
+            }
+        };
+
+        // Without replace_allowed_ips, only the endpoint of the peer changes
+        match DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&pk).set_endpoint(endpt))
+            .apply(&self.ifname, self.backend)
+        {
+            Ok(_) => {
+                peer.endpoint = Some(endpoint.to_string());
+                Ok(())
+            }
+            Err(_) => Err(VpnctrlError::Internal {
+                msg: "Failed to update interface".to_string(),
+            }),
+        }
+    }
+
     fn get_status(&self) -> InterfaceStatus {
         self.status.clone()
     }
@@ -289,10 +336,17 @@ impl PlatformInterface for Interface {
         Ok(dev
             .peers
             .into_iter()
-            .map(|x| PeerTrafficStat {
-                pubkey: x.config.public_key.to_base64(),
-                rx_bytes: x.stats.rx_bytes,
-                tx_bytes: x.stats.tx_bytes,
+            .map(|x| {
+                PeerTrafficStat::new(
+                    x.config.public_key.to_base64(),
+                    x.stats.rx_bytes,
+                    x.stats.tx_bytes,
+                    x.stats.last_handshake_time,
+                    x.config.endpoint,
+                    x.config.persistent_keepalive_interval,
+                    // wireguard-go reports it, but wireguard-control drops it
+                    None,
+                )
             })
             .collect())
     }
@@ -327,30 +381,66 @@ impl PlatformInterface for Interface {
     }
 
     fn set_ip(&mut self, cidrs: &[String]) -> Result<(), VpnctrlError> {
-        let re = Regex::new(r"/.*").unwrap();
+        let wanted =
+            parse_addresses(cidrs).map_err(|e| VpnctrlError::BadParameter { msg: e.join(", ") })?;
 
-        for cidr in cidrs {
-            let ip = re.replace_all(cidr, "");
-            // TODO: Support IPv6!
-            match Command::new("ifconfig")
-                .arg(&self.real_ifname)
-                .arg("inet")
-                .arg(cidr)
-                .arg(&*ip)
-                .arg("alias")
-                .output()
-            {
-                Ok(_) => {}
-                Err(_e) => {
-                    return Err(VpnctrlError::Internal {
-                        msg: "Failed to set address".to_string(),
-                    })
-                }
-            };
+        let stale: Vec<IpNet> = self
+            .addrs
+            .iter()
+            .filter(|x| !wanted.contains(x))
+            .cloned()
+            .collect();
+        for addr in stale {
+            self.ifconfig(&[family_arg(&addr), &addr.addr().to_string(), "-alias"])?;
+            self.addrs.retain(|x| *x != addr);
+        }
+
+        for addr in wanted {
+            if self.addrs.contains(&addr) {
+                continue;
+            }
+            let cidr = addr.to_string();
+            let ip = addr.addr().to_string();
+            match addr {
+                // Point-to-point, so the destination is the address itself
+                IpNet::V4(_) => self.ifconfig(&["inet", &cidr, &ip, "alias"])?,
+                IpNet::V6(_) => self.ifconfig(&["inet6", &cidr, "alias"])?,
+            }
+            self.addrs.push(addr);
         }
 
         Ok(())
     }
+
+    fn get_ip(&self) -> Result<Vec<String>, VpnctrlError> {
+        Ok(self.addrs.iter().map(|x| x.to_string()).collect())
+    }
+}
+
+fn family_arg(addr: &IpNet) -> &'static str {
+    match addr {
+        IpNet::V4(_) => "inet",
+        IpNet::V6(_) => "inet6",
+    }
+}
+
+impl Interface {
+    fn ifconfig(&self, args: &[&str]) -> Result<(), VpnctrlError> {
+        let output = Command::new("ifconfig")
+            .arg(&self.real_ifname)
+            .args(args)
+            .output()
+            .map_err(|e| VpnctrlError::Internal { msg: e.to_string() })?;
+        if !output.status.success() {
+            return Err(VpnctrlError::Internal {
+                msg: format!(
+                    "Failed to set address: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+            });
+        }
+        Ok(())
+    }
 }
 
 impl Drop for Interface {
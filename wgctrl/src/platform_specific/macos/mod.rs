@@ -17,10 +17,18 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+pub mod firewall;
 pub mod interface;
+pub mod netmon;
+pub mod recovery;
 pub mod route;
+pub mod split_tunnel;
 
+pub use firewall::*;
 pub use interface::*;
+pub use netmon::*;
+pub use recovery::*;
 pub use route::*;
+pub use split_tunnel::*;
 
 pub mod dns;
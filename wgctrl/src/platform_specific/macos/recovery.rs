@@ -0,0 +1,35 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::super::journal::{self, Mutation};
+use super::route;
+use crate::error::VpnctrlError;
+
+/// Undoes `mutation`, made by a previous run. Already undone changes are fine.
+pub fn undo(mutation: &Mutation) -> Result<(), VpnctrlError> {
+    match mutation {
+        Mutation::DefaultRoute {
+            family,
+            nexthop_type,
+            nexthop,
+        } => route::restore_default(*family, nexthop_type, nexthop),
+        Mutation::BypassRoute { destination } => route::delete_bypass(destination),
+        _ => journal::unsupported(mutation),
+    }
+}
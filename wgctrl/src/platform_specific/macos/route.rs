@@ -23,29 +23,119 @@
  * https://github.com/mullvad/mullvadvpn-app/tree/master/talpid-core/
  */
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::process::Command;
 
-use super::super::common::PlatformRoute;
+use super::super::common::{
+    IpFamily, PlatformRoute, RouteDrift, RouteOptions, RoutePolicy, DEFAULT_RULE_PRIORITY,
+};
+use super::super::journal::{self, Mutation};
 use crate::error::VpnctrlError;
 use wireguard_control::backends::userspace::resolve_tun;
 
 use wireguard_control::InterfaceName;
 
+fn family_flag(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "-inet",
+        IpFamily::V6 => "-inet6",
+    }
+}
+
+fn default_route_mutation(
+    family: IpFamily,
+    (nexthop_type, nexthop): &(String, String),
+) -> Mutation {
+    Mutation::DefaultRoute {
+        family,
+        nexthop_type: nexthop_type.clone(),
+        nexthop: nexthop.clone(),
+    }
+}
+
+fn bypass_mutation(address: &str) -> Mutation {
+    Mutation::BypassRoute {
+        destination: address.to_string(),
+    }
+}
+
+/// Puts back the default route of `family` through `nexthop_type` `nexthop`, unless the system
+/// already set up another gateway
+pub(crate) fn restore_default(
+    family: IpFamily,
+    nexthop_type: &str,
+    nexthop: &str,
+) -> Result<(), VpnctrlError> {
+    // Check our default route is not damaged...
+    match Route::get_default_node_cmd(family_flag(family)) {
+        Ok((current_type, _)) => {
+            if current_type == "-gateway" {
+                // Something... happened while we are asleep.
+                return Ok(());
+            }
+
+            // TODO: This cannot detect route change through PPP daemon or sort of.
+            // TODO: Handle them
+        }
+        Err(e) => return Err(VpnctrlError::Internal { msg: e.to_string() }),
+    }
+
+    match Command::new("route")
+        .arg("-q")
+        .arg("-n")
+        .arg("delete")
+        .arg(family_flag(family))
+        .arg("default")
+        .output()
+    {
+        Ok(_) => {}
+        Err(e) => return Err(VpnctrlError::Internal { msg: e.to_string() }),
+    };
+
+    match Command::new("route")
+        .arg("-q")
+        .arg("-n")
+        .arg("add")
+        .arg(family_flag(family))
+        .arg("default")
+        .arg(nexthop_type)
+        .arg(nexthop)
+        .output()
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
+    }
+}
+
+pub(crate) fn delete_bypass(address: &str) -> Result<(), VpnctrlError> {
+    match Command::new("route")
+        .arg("-q")
+        .arg("-n")
+        .arg("delete")
+        .arg(family_flag(IpFamily::of(address)))
+        .arg(address)
+        .output()
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
+    }
+}
+
 pub struct Route {
-    default_gw: (String, String),
-    default_route_removed: bool,
+    // Node used to reach the default route of each family, if there is one
+    default_gw: HashMap<IpFamily, (String, String)>,
+    default_route_removed: HashSet<IpFamily>,
     route_bypass_set: HashSet<String>,
 }
 
 impl PlatformRoute for Route {
-    fn new(_fwmark: u32) -> Result<Self, VpnctrlError>
+    fn new(_defaults: RoutePolicy) -> Result<Self, VpnctrlError>
     where
         Self: Sized,
     {
         Ok(Self {
-            default_gw: ("".to_string(), "".to_string()),
-            default_route_removed: false,
+            default_gw: HashMap::new(),
+            default_route_removed: HashSet::new(),
             route_bypass_set: HashSet::new(),
         })
     }
@@ -55,23 +145,39 @@ impl PlatformRoute for Route {
         Ok(())
     }
 
-    fn add_route(&mut self, ifname: &str, cidr: &str) -> Result<(), VpnctrlError> {
+    fn add_route(
+        &mut self,
+        ifname: &str,
+        cidr: &str,
+        opts: &RouteOptions,
+    ) -> Result<(), VpnctrlError> {
+        opts.validate(cidr)?;
+        if opts.metric.is_some() || opts.table.is_some() {
+            return Err(VpnctrlError::BadParameter {
+                msg: "Route metric and table are not supported on this platform".to_string(),
+            });
+        }
+
         let real_ifname = match Self::get_real_ifname(ifname) {
             Ok(x) => x,
             Err(e) => return Err(VpnctrlError::Internal { msg: e.to_string() }),
         };
 
-        // TODO: Support IPv6!
-        match Command::new("route")
-            .arg("-q")
+        let mut cmd = Command::new("route");
+        cmd.arg("-q")
             .arg("-n")
             .arg("add")
-            .arg("-inet")
-            .arg(cidr)
-            .arg("-interface")
-            .arg(real_ifname)
-            .output()
-        {
+            .arg(family_flag(IpFamily::of(cidr)))
+            .arg(cidr);
+        match opts.via {
+            Some(x) => cmd.arg(x.to_string()),
+            None => cmd.arg("-interface").arg(real_ifname),
+        };
+        if let Some(x) = opts.src {
+            cmd.arg("-ifa").arg(x.to_string());
+        }
+
+        match cmd.output() {
             Ok(_) => Ok(()),
             Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
         }
@@ -83,12 +189,11 @@ impl PlatformRoute for Route {
             Err(e) => return Err(VpnctrlError::Internal { msg: e.to_string() }),
         };
 
-        // TODO: Support IPv6!
         match Command::new("route")
             .arg("-q")
             .arg("-n")
             .arg("delete")
-            .arg("-inet")
+            .arg(family_flag(IpFamily::of(cidr)))
             .arg(cidr)
             .arg("-interface")
             .arg(real_ifname)
@@ -100,19 +205,29 @@ impl PlatformRoute for Route {
     }
 
     fn add_route_bypass(&mut self, address: &str) -> Result<(), VpnctrlError> {
-        // TODO: Support IPv6!
+        let family = IpFamily::of(address);
+        let (nexthop_type, nexthop) = match self.default_gw.get(&family) {
+            Some(x) => x,
+            None => {
+                return Err(VpnctrlError::Internal {
+                    msg: format!("No default route to bypass {} through", address),
+                })
+            }
+        };
+
         match Command::new("route")
             .arg("-q")
             .arg("-n")
             .arg("add")
-            .arg("-inet")
+            .arg(family_flag(family))
             .arg(address)
-            .arg(&self.default_gw.0)
-            .arg(&self.default_gw.1)
+            .arg(nexthop_type)
+            .arg(nexthop)
             .output()
         {
             Ok(_) => {
                 self.route_bypass_set.insert(address.to_string());
+                journal::record(bypass_mutation(address));
                 Ok(())
             }
             Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
@@ -124,20 +239,10 @@ impl PlatformRoute for Route {
             return Ok(());
         }
 
-        match Command::new("route")
-            .arg("-q")
-            .arg("-n")
-            .arg("delete")
-            .arg("-inet")
-            .arg(address)
-            .output()
-        {
-            Ok(_) => {
-                self.route_bypass_set.insert(address.to_string());
-                Ok(())
-            }
-            Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
-        }
+        delete_bypass(address)?;
+        self.route_bypass_set.remove(address);
+        journal::forget(&bypass_mutation(address));
+        Ok(())
     }
 
     fn get_route_bypass(&self) -> Result<Vec<String>, VpnctrlError> {
@@ -146,101 +251,194 @@ impl PlatformRoute for Route {
 
     fn backup_default_route(&mut self) -> Result<(), VpnctrlError> {
         // Back up default route
-        self.default_gw = match Self::get_default_node_cmd("-inet") {
-            Ok(x) => x,
-            Err(e) => return Err(VpnctrlError::Internal { msg: e.to_string() }),
-        };
+        self.default_gw.clear();
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let node = Self::get_default_node_cmd(family_flag(family))?;
+            // Hosts without IPv6 connectivity have no default route to back up
+            if !node.0.is_empty() {
+                self.default_gw.insert(family, node);
+            }
+        }
 
-        self.default_route_removed = false;
+        self.default_route_removed.clear();
 
         Ok(())
     }
 
-    fn remove_default_route(&mut self) -> Result<(), VpnctrlError> {
-        if self.default_route_removed {
+    fn remove_default_route(&mut self, family: IpFamily) -> Result<(), VpnctrlError> {
+        if !self.default_route_removed.insert(family) {
             return Ok(());
         }
 
-        self.default_route_removed = true;
-
-        // TODO: Support IPv6!
         match Command::new("route")
             .arg("-q")
             .arg("-n")
             .arg("delete")
-            .arg("-inet")
+            .arg(family_flag(family))
             .arg("default")
             .output()
         {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                if let Some(node) = self.default_gw.get(&family) {
+                    journal::record(default_route_mutation(family, node));
+                }
+                Ok(())
+            }
             Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
         }
     }
 
     fn restore_default_route(&mut self) -> Result<(), VpnctrlError> {
-        if !self.default_route_removed {
+        if self.default_route_removed.is_empty() {
             return Ok(());
         }
 
         // Remove route bypasses
         self.cleanup_route_bypass();
 
-        // Check our default route is not damaged...
-        match Self::get_default_node_cmd("-inet") {
-            Ok((nexthop_type, _nexthop)) => {
-                if nexthop_type == "-gateway" {
-                    // Something... happened while we are asleep.
-                    return Ok(());
+        // Every family gets its chance, and the failed ones are kept for another try
+        let families: Vec<IpFamily> = self.default_route_removed.drain().collect();
+        let mut errors = vec![];
+        for family in families {
+            let node = match self.default_gw.get(&family) {
+                Some(x) => x,
+                None => continue,
+            };
+            match restore_default(family, &node.0, &node.1) {
+                Ok(_) => journal::forget(&default_route_mutation(family, node)),
+                Err(e) => {
+                    errors.push(format!("{:?}: {}", family, e));
+                    self.default_route_removed.insert(family);
                 }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(VpnctrlError::Internal {
+                msg: format!("Failed to restore default route of {}", errors.join(", ")),
+            });
+        }
+        Ok(())
+    }
+
+    fn restore_family_default_route(&mut self, family: IpFamily) -> Result<(), VpnctrlError> {
+        if !self.default_route_removed.remove(&family) {
+            return Ok(());
+        }
 
-                // TODO: This cannot detect route change through PPP daemon or sort of.
-                // TODO: Handle them
+        if let Some(node) = self.default_gw.get(&family) {
+            if let Err(e) = restore_default(family, &node.0, &node.1) {
+                self.default_route_removed.insert(family);
+                return Err(VpnctrlError::Internal {
+                    msg: format!("Failed to restore default route of {:?}: {}", family, e),
+                });
             }
-            Err(e) => return Err(VpnctrlError::Internal { msg: e.to_string() }),
+            journal::forget(&default_route_mutation(family, node));
         }
 
-        self.default_route_removed = false;
-        // TODO: Support IPv6!
-        match Command::new("route")
-            .arg("-q")
-            .arg("-n")
-            .arg("delete")
-            .arg("-inet")
-            .arg("default")
-            .output()
-        {
-            Ok(_) => {}
-            Err(e) => return Err(VpnctrlError::Internal { msg: e.to_string() }),
-        };
+        // Bypasses are only needed while a default route is taken over
+        if self.default_route_removed.is_empty() {
+            self.cleanup_route_bypass();
+        }
+        Ok(())
+    }
 
-        // TODO: Support IPv6!
-        match Command::new("route")
-            .arg("-q")
-            .arg("-n")
-            .arg("add")
-            .arg("-inet")
-            .arg("default")
-            .arg(&self.default_gw.0)
-            .arg(&self.default_gw.1)
-            .output()
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
+    fn refresh_default_route(&mut self) -> Result<Vec<String>, VpnctrlError> {
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let node = Self::get_default_node_cmd(family_flag(family))?;
+            // Our own default route leads to a tunnel, which tells nothing of the network
+            if node.1.starts_with("utun") {
+                continue;
+            }
+            // Off the network, there is no gateway left to restore or to bypass through
+            if node.0.is_empty() {
+                if let Some(old) = self.default_gw.remove(&family) {
+                    log::info!("Default route of {:?} through {} is gone", family, old.1);
+                    if self.default_route_removed.contains(&family) {
+                        journal::forget(&default_route_mutation(family, &old));
+                    }
+                }
+                continue;
+            }
+            if self.default_gw.get(&family) == Some(&node) {
+                continue;
+            }
+
+            log::info!("Default route of {:?} moved to {}", family, node.1);
+            // A crash now should bring back the new default route, not the one we removed
+            if self.default_route_removed.contains(&family) {
+                if let Some(old) = self.default_gw.get(&family) {
+                    journal::forget(&default_route_mutation(family, old));
+                }
+                journal::record(default_route_mutation(family, &node));
+            }
+            self.default_gw.insert(family, node);
+            self.move_route_bypass(family);
         }
+
+        let mut gateways: Vec<String> = self.default_gw.values().map(|x| x.1.clone()).collect();
+        gateways.sort();
+        Ok(gateways)
+    }
+
+    fn add_interface(&mut self, _ifname: &str, _policy: RoutePolicy) -> Result<(), VpnctrlError> {
+        // No policy routing here. Everything goes to the main table.
+        Ok(())
+    }
+
+    fn remove_interface(&mut self, _ifname: &str) -> Result<(), VpnctrlError> {
+        // Routes are gone along with the utun device
+        Ok(())
+    }
+
+    fn bypass_priority(&self) -> u32 {
+        // No rules here, so nothing to stay ahead of
+        DEFAULT_RULE_PRIORITY.saturating_sub(1)
+    }
+
+    fn refresh_lan_bypass(&mut self, _tunnels: &[String]) -> Result<Vec<String>, VpnctrlError> {
+        // Connected routes of the local networks are more specific than our split default route
+        Ok(vec![])
+    }
+
+    fn clear_lan_bypass(&mut self) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+
+    fn reconcile(&mut self) -> Result<RouteDrift, VpnctrlError> {
+        // Drift detection is not supported on this platform
+        Ok(RouteDrift::default())
+    }
+
+    fn cleanup(&mut self) -> Result<(), VpnctrlError> {
+        self.restore_default_route()
     }
 }
 
 impl Route {
+    /// Adds the bypass routes of `family` again through the current default route
+    fn move_route_bypass(&mut self, family: IpFamily) {
+        let addrs: Vec<String> = self
+            .route_bypass_set
+            .iter()
+            .filter(|x| IpFamily::of(x) == family)
+            .cloned()
+            .collect();
+        for addr in addrs {
+            if let Err(e) = self
+                .remove_route_bypass(&addr)
+                .and_then(|_| self.add_route_bypass(&addr))
+            {
+                log::error!("Failed to move bypass route of {}: {}", addr, e);
+            }
+        }
+    }
+
     fn cleanup_route_bypass(&mut self) {
         for addr in self.route_bypass_set.iter() {
-            Command::new("route")
-                .arg("-q")
-                .arg("-n")
-                .arg("delete")
-                .arg("-inet")
-                .arg(addr)
-                .output()
-                .ok();
+            if delete_bypass(addr).is_ok() {
+                journal::forget(&bypass_mutation(addr));
+            }
         }
 
         self.route_bypass_set = HashSet::new();
@@ -264,7 +462,7 @@ impl Route {
         }
     }
 
-    // Retrieves the node that's currently used to reach 0.0.0.0/0
+    // Retrieves the node that's currently used to reach the default route
     // Arguments can be either -inet or -inet6
     fn get_default_node_cmd(if_family: &'static str) -> Result<(String, String), VpnctrlError> {
         let cmd_out = Command::new("route")
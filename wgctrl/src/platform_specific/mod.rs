@@ -17,10 +17,14 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use self::common::{DnsMonitor, PlatformInterface, PlatformRoute};
+use self::common::{
+    DnsBackend, DnsMonitor, PlatformFirewall, PlatformInterface, PlatformNetworkMonitor,
+    PlatformRoute, PlatformSplitTunnel, RoutePolicy,
+};
 
 // Platform common
 pub mod common;
+pub mod journal;
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -46,12 +50,27 @@ impl PlatformSpecificFactory {
         Interface::new(name)
     }
 
-    pub fn get_route(fwmark: u32) -> Result<Route, VpnctrlError> {
-        Route::new(fwmark)
+    pub fn get_route(defaults: RoutePolicy) -> Result<Route, VpnctrlError> {
+        Route::new(defaults)
     }
 
-    pub fn get_dnsmon(handle: tokio::runtime::Handle) -> Result<DnsMonitor, VpnctrlError> {
-        match DnsMonitor::new(handle) {
+    pub fn get_firewall() -> Result<Firewall, VpnctrlError> {
+        Firewall::new()
+    }
+
+    pub fn get_network_monitor() -> Result<NetworkMonitor, VpnctrlError> {
+        NetworkMonitor::new()
+    }
+
+    pub fn get_split_tunnel(rule_priority: u32) -> Result<SplitTunnel, VpnctrlError> {
+        SplitTunnel::new(rule_priority)
+    }
+
+    pub fn get_dnsmon(
+        handle: tokio::runtime::Handle,
+        backends: &[DnsBackend],
+    ) -> Result<DnsMonitor, VpnctrlError> {
+        match DnsMonitor::new(handle, backends) {
             Ok(x) => Ok(x),
             Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
         }
@@ -20,10 +20,11 @@
 
 use crate::ffi_error;
 
+use super::super::common::{DnsBackend, DnsConfig, DnsStatus};
 use super::winlog::{log_sink, LogSink};
 
 use lazy_static::lazy_static;
-use std::{env, io, net::IpAddr, path::Path};
+use std::{collections::BTreeMap, env, io, net::IpAddr, path::Path};
 use talpid_types::ErrorExt;
 use widestring::WideCString;
 use winapi::shared::ifdef::NET_LUID;
@@ -67,21 +68,38 @@ pub enum Error {
     UpdateDnsCachePolicy(#[error(source)] io::Error),
 }
 
-pub struct DnsMonitor {}
+pub struct DnsMonitor {
+    /// Settings of each interface. The cache policy gets their merged servers.
+    configs: BTreeMap<String, DnsConfig>,
+}
 
 impl super::super::common::DnsMonitorT for DnsMonitor {
     type Error = Error;
 
-    fn new(_handle: tokio::runtime::Handle) -> Result<Self, Error> {
+    fn new(_handle: tokio::runtime::Handle, backends: &[DnsBackend]) -> Result<Self, Error> {
+        if backends != DnsBackend::AUTO {
+            log::warn!("Only the system DNS manager is supported, ignoring the DNS backend");
+        }
         unsafe { WinDns_Initialize(Some(log_sink), b"WinDns\0".as_ptr()).into_result()? };
 
-        let mut monitor = DnsMonitor {};
+        let mut monitor = DnsMonitor {
+            configs: BTreeMap::new(),
+        };
         monitor.reset()?;
 
         Ok(monitor)
     }
 
-    fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<(), Error> {
+    fn probe(_backends: &[DnsBackend]) -> Vec<(DnsBackend, std::result::Result<(), String>)> {
+        vec![]
+    }
+
+    fn set(&mut self, interface: &str, config: &DnsConfig) -> Result<(), Error> {
+        if !config.search_domains.is_empty() || !config.routing_domains.is_empty() {
+            log::warn!("DNS domains are not supported, ignoring them");
+        }
+
+        let servers = &config.servers;
         let ipv4 = servers
             .iter()
             .filter(|ip| ip.is_ipv4())
@@ -134,23 +152,61 @@ impl super::super::common::DnsMonitorT for DnsMonitor {
             .into_result()
         }?;
 
-        if *GLOBAL_DNS_CACHE_POLICY {
-            if let Err(error) = set_dns_cache_policy(servers) {
-                log::error!("{}", error.display_chain());
-                log::warn!("DNS resolution may be slowed down");
-            }
-        }
+        self.configs.insert(interface.to_string(), config.clone());
+        self.update_dns_cache_policy();
+        Ok(())
+    }
 
+    fn reset_interface(&mut self, interface: &str) -> Result<(), Error> {
+        if self.configs.remove(interface).is_none() {
+            return Ok(());
+        }
+        if self.configs.is_empty() {
+            return self.reset();
+        }
+        self.update_dns_cache_policy();
         Ok(())
     }
 
     fn reset(&mut self) -> Result<(), Error> {
+        self.configs.clear();
         if *GLOBAL_DNS_CACHE_POLICY {
             reset_dns_cache_policy()
         } else {
             Ok(())
         }
     }
+
+    fn manager_name(&self) -> Option<String> {
+        match self.configs.is_empty() {
+            false => Some("windns".to_string()),
+            true => None,
+        }
+    }
+
+    /// The resolvers in use can't be observed, WinDns does not report them back
+    fn status(&self, interface: &str) -> Result<DnsStatus, Error> {
+        Ok(DnsStatus {
+            manager: match self.configs.contains_key(interface) {
+                true => self.manager_name(),
+                false => None,
+            },
+            resolvers: None,
+            corrections: None,
+        })
+    }
+}
+
+impl DnsMonitor {
+    fn update_dns_cache_policy(&self) {
+        if *GLOBAL_DNS_CACHE_POLICY {
+            let merged = DnsConfig::merge(self.configs.values());
+            if let Err(error) = set_dns_cache_policy(&merged.servers) {
+                log::error!("{}", error.display_chain());
+                log::warn!("DNS resolution may be slowed down");
+            }
+        }
+    }
 }
 
 fn ip_to_widestring(ip: &IpAddr) -> WideCString {
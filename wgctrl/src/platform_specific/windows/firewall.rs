@@ -0,0 +1,54 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::super::common::{DnsLeakPolicy, FirewallPolicy, PlatformFirewall};
+use crate::error::VpnctrlError;
+
+pub struct Firewall {}
+
+impl PlatformFirewall for Firewall {
+    fn new() -> Result<Self, VpnctrlError>
+    where
+        Self: Sized,
+    {
+        Ok(Self {})
+    }
+
+    fn apply_policy(&mut self, policy: &FirewallPolicy) -> Result<(), VpnctrlError> {
+        match policy {
+            FirewallPolicy::Off => Ok(()),
+            _ => Err(VpnctrlError::Internal {
+                msg: "Firewall is not supported on this platform".to_string(),
+            }),
+        }
+    }
+
+    fn apply_dns_policy(&mut self, policy: &DnsLeakPolicy) -> Result<(), VpnctrlError> {
+        match policy {
+            DnsLeakPolicy::Off => Ok(()),
+            _ => Err(VpnctrlError::Internal {
+                msg: "DNS leak protection is not supported on this platform".to_string(),
+            }),
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+}
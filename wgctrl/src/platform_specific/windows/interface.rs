@@ -26,7 +26,9 @@ use wireguard_nt::{Adapter, SetInterface, SetPeer};
 
 use ipnet::IpNet;
 
-use super::super::common::{InterfaceStatus, PeerTrafficStat, PlatformInterface, WgPeerCfg};
+use super::super::common::{
+    parse_addresses, InterfaceStatus, PeerTrafficStat, PlatformInterface, WgPeerCfg,
+};
 
 use crate::error::VpnctrlError;
 
@@ -50,6 +52,7 @@ pub struct Interface {
     iface_cfg: SetInterface,
     peers: HashMap<[u8; 32], SetPeer>,
     status: InterfaceStatus,
+    addrs: Vec<IpNet>,
 }
 
 impl PlatformInterface for Interface {
@@ -74,6 +77,7 @@ impl PlatformInterface for Interface {
             },
             peers: HashMap::new(),
             status: InterfaceStatus::Stopped,
+            addrs: vec![],
         })
     }
 
@@ -233,14 +237,59 @@ impl PlatformInterface for Interface {
         }
     }
 
+    fn set_peer_endpoint(&mut self, pubkey: &str, endpoint: &str) -> Result<(), VpnctrlError> {
+        let pubkey = match base64::decode(pubkey) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(VpnctrlError::BadParameter {
+                    msg: "Invalid pubkey format".to_string(),
+                })
+            }
+        };
+        let endpoint = match SocketAddr::from_str(endpoint) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(VpnctrlError::BadParameter {
+                    msg: "Invalid endpoint address".to_string(),
+                })
+            }
+        };
+
+        match self.peers.get_mut(pubkey.as_slice()) {
+            Some(x) => x.endpoint = endpoint,
+            None => {
+                return Err(VpnctrlError::EntryNotFound {
+                    msg: "Entry not found!".to_string(),
+                })
+            }
+        }
+
+        self.apply_peer_update()
+    }
+
     fn get_status(&self) -> InterfaceStatus {
         self.status.clone()
     }
 
     fn get_trafficstats(&self) -> Result<Vec<PeerTrafficStat>, VpnctrlError> {
-        Err(VpnctrlError::Internal {
-            msg: "Not implemented yet".to_string(),
-        })
+        Ok(self
+            .iface
+            .get_config()
+            .peers
+            .into_iter()
+            .map(|x| {
+                PeerTrafficStat::new(
+                    base64::encode(x.public_key),
+                    x.rx_bytes,
+                    x.tx_bytes,
+                    x.last_handshake,
+                    Some(x.endpoint),
+                    Some(x.persistent_keepalive),
+                    // wireguard-nt does not report it
+                    None,
+                )
+            })
+            .collect())
     }
 
     fn up(&mut self) -> bool {
@@ -254,18 +303,21 @@ impl PlatformInterface for Interface {
     }
 
     fn set_ip(&mut self, ips: &[String]) -> Result<(), VpnctrlError> {
-        let iplist: Vec<IpNet> = ips
-            .iter()
-            .map(|x| IpNet::from_str(x))
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
-            .collect();
+        let iplist =
+            parse_addresses(ips).map_err(|e| VpnctrlError::BadParameter { msg: e.join(", ") })?;
 
         match self.iface.set_default_route(&iplist, &self.iface_cfg) {
-            Ok(()) => Ok(()),
+            Ok(()) => {
+                self.addrs = iplist;
+                Ok(())
+            }
             Err(e) => Err(VpnctrlError::Internal { msg: e.to_string() }),
         }
     }
+
+    fn get_ip(&self) -> Result<Vec<String>, VpnctrlError> {
+        Ok(self.addrs.iter().map(|x| x.to_string()).collect())
+    }
 }
 
 impl Interface {
@@ -0,0 +1,40 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::time::Duration;
+
+use super::super::common::PlatformNetworkMonitor;
+use crate::error::VpnctrlError;
+
+/// Change notifications are not supported on this platform, so callers fall back to polling
+pub struct NetworkMonitor {}
+
+impl PlatformNetworkMonitor for NetworkMonitor {
+    fn new() -> Result<Self, VpnctrlError>
+    where
+        Self: Sized,
+    {
+        Ok(Self {})
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<bool, VpnctrlError> {
+        std::thread::sleep(timeout);
+        Ok(false)
+    }
+}
@@ -17,13 +17,15 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use super::super::common::PlatformRoute;
+use super::super::common::{
+    IpFamily, PlatformRoute, RouteDrift, RouteOptions, RoutePolicy, DEFAULT_RULE_PRIORITY,
+};
 use crate::error::VpnctrlError;
 
 pub struct Route {}
 
 impl PlatformRoute for Route {
-    fn new(_fwmark: u32) -> Result<Self, VpnctrlError>
+    fn new(_defaults: RoutePolicy) -> Result<Self, VpnctrlError>
     where
         Self: Sized,
     {
@@ -34,7 +36,12 @@ impl PlatformRoute for Route {
         Ok(())
     }
 
-    fn add_route(&mut self, _ifname: &str, _ip: &str) -> Result<(), VpnctrlError> {
+    fn add_route(
+        &mut self,
+        _ifname: &str,
+        _ip: &str,
+        _opts: &RouteOptions,
+    ) -> Result<(), VpnctrlError> {
         Ok(()) // wireguard-nt library does some routing stuff, so just ignore it for now...
     }
 
@@ -60,11 +67,48 @@ impl PlatformRoute for Route {
         Ok(())
     }
 
-    fn remove_default_route(&mut self) -> Result<(), VpnctrlError> {
+    fn remove_default_route(&mut self, _family: IpFamily) -> Result<(), VpnctrlError> {
         Ok(())
     }
 
     fn restore_default_route(&mut self) -> Result<(), VpnctrlError> {
         Ok(())
     }
+
+    fn restore_family_default_route(&mut self, _family: IpFamily) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+
+    fn refresh_default_route(&mut self) -> Result<Vec<String>, VpnctrlError> {
+        Ok(vec![])
+    }
+
+    fn add_interface(&mut self, _ifname: &str, _policy: RoutePolicy) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+
+    fn remove_interface(&mut self, _ifname: &str) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+
+    fn bypass_priority(&self) -> u32 {
+        DEFAULT_RULE_PRIORITY.saturating_sub(1)
+    }
+
+    fn refresh_lan_bypass(&mut self, _tunnels: &[String]) -> Result<Vec<String>, VpnctrlError> {
+        Ok(vec![])
+    }
+
+    fn clear_lan_bypass(&mut self) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+
+    fn reconcile(&mut self) -> Result<RouteDrift, VpnctrlError> {
+        // Drift detection is not supported on this platform
+        Ok(RouteDrift::default())
+    }
+
+    fn cleanup(&mut self) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
 }
@@ -0,0 +1,56 @@
+/*
+ * SPDX-FileCopyrightText: 2022 Empo Inc.
+ *
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::super::common::PlatformSplitTunnel;
+use crate::error::VpnctrlError;
+
+pub struct SplitTunnel {}
+
+impl PlatformSplitTunnel for SplitTunnel {
+    fn new(_rule_priority: u32) -> Result<Self, VpnctrlError>
+    where
+        Self: Sized,
+    {
+        Ok(Self {})
+    }
+
+    fn add_pid(&mut self, _pid: u32) -> Result<(), VpnctrlError> {
+        Err(VpnctrlError::Internal {
+            msg: "Split tunneling is not supported on this platform".to_string(),
+        })
+    }
+
+    fn remove_pid(&mut self, pid: u32) -> Result<(), VpnctrlError> {
+        Err(VpnctrlError::EntryNotFound {
+            msg: pid.to_string(),
+        })
+    }
+
+    fn get_pids(&self) -> Result<Vec<u32>, VpnctrlError> {
+        Ok(vec![])
+    }
+
+    fn set_rule_priority(&mut self, _rule_priority: u32) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), VpnctrlError> {
+        Ok(())
+    }
+}